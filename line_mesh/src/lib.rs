@@ -5,7 +5,9 @@ extern crate cgmath;
 mod line_mesh;
 mod line_buffer;
 mod line_vertex;
+mod svg;
 
 pub use line_mesh::LineMesh;
 pub use line_buffer::LineBuffer;
 pub use line_vertex::LineVertex;
+pub use svg::{View, SvgOptions, write_svg};
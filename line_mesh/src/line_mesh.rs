@@ -52,6 +52,18 @@ impl LineMesh {
     self.add_point(pt);
   }
 
+  /// The opposite corners of the smallest axis-aligned box around the points, or None if there are none
+  pub fn bounds(& self) -> Option<(Point3<f32>, Point3<f32>)> {
+    let first = match self.points.first() {
+      Some(& first) => first,
+      None => return None,
+    };
+    Some(self.points.iter().fold((first, first), |(min, max), pt| (
+      Point3::new(min.x.min(pt.x), min.y.min(pt.y), min.z.min(pt.z)),
+      Point3::new(max.x.max(pt.x), max.y.max(pt.y), max.z.max(pt.z)),
+    )))
+  }
+
   pub fn to_buffer<T: Facade>(& self, gl: & T) -> LineBuffer {
     let vert_storage: Vec<LineVertex> = self.points.iter()
       .enumerate()
@@ -0,0 +1,179 @@
+use std::io::{self, Write};
+
+use cgmath::{Matrix4, Point2, Point3, Vector4};
+
+use line_mesh::LineMesh;
+
+/// The direction a line mesh is seen from when it's flattened into an SVG
+#[derive(Copy, Clone, Debug)]
+pub enum View {
+  /// Looking down the negative z axis, with y up
+  Front,
+  /// Looking down the negative y axis, with negative z up
+  Top,
+  /// Looking down the negative x axis, with y up
+  Side,
+  /// Any view transformation. The x and y of the transformed points are drawn, after the
+  /// perspective divide if the matrix has one.
+  Matrix(Matrix4<f32>),
+}
+
+impl View {
+  /// Project a point onto the drawing plane, with y up
+  pub fn project(& self, pt: Point3<f32>) -> Point2<f32> {
+    match * self {
+      View::Front => Point2::new(pt.x, pt.y),
+      View::Top => Point2::new(pt.x, -pt.z),
+      View::Side => Point2::new(-pt.z, pt.y),
+      View::Matrix(transform) => {
+        let projected = transform * pt.to_homogeneous();
+        if projected.w != 0.0 && projected.w != 1.0 {
+          Point2::new(projected.x / projected.w, projected.y / projected.w)
+        } else {
+          Point2::new(projected.x, projected.y)
+        }
+      },
+    }
+  }
+}
+
+/// How a line mesh is drawn as SVG
+#[derive(Copy, Clone, Debug)]
+pub struct SvgOptions {
+  pub view: View,
+  /// The size of the longer side of the drawing
+  pub size: f32,
+  /// Space left around the drawing, in the same units as the size
+  pub margin: f32,
+  /// Line width, in the same units as the size
+  pub stroke_width: f32,
+}
+
+impl SvgOptions {
+  pub fn new(view: View) -> SvgOptions {
+    SvgOptions {
+      view: view,
+      size: 800.0,
+      margin: 10.0,
+      stroke_width: 1.0,
+    }
+  }
+}
+
+fn svg_color(color: Vector4<f32>) -> String {
+  let byte = |value: f32| (value.max(0.0).min(1.0) * 255.0).round() as u8;
+  format!("#{:02x}{:02x}{:02x}", byte(color.x), byte(color.y), byte(color.z))
+}
+
+/// Write the line segments of a mesh as an SVG drawing, projected through the options' view and
+/// scaled to fit. Each segment keeps the colour of its first point, and segments which continue
+/// one another in the same colour are joined into a single path, so plotters don't lift the pen.
+pub fn write_svg<W: Write>(mesh: & LineMesh, options: & SvgOptions, out: &mut W) -> io::Result<()> {
+  // Projected segments, leaving out the zero length ones which mark jumps
+  let segments: Vec<(Point2<f32>, Point2<f32>, Vector4<f32>)> = mesh.points.chunks(2)
+    .zip(mesh.colors.chunks(2))
+    .filter(|& (pts, _)| pts.len() == 2)
+    .map(|(pts, colors)| (options.view.project(pts[0]), options.view.project(pts[1]), colors[0]))
+    .filter(|& (start, end, _)| start != end)
+    .collect();
+
+  let (mut min_x, mut min_y) = (::std::f32::INFINITY, ::std::f32::INFINITY);
+  let (mut max_x, mut max_y) = (::std::f32::NEG_INFINITY, ::std::f32::NEG_INFINITY);
+  for & (start, end, _) in & segments {
+    for pt in & [start, end] {
+      min_x = min_x.min(pt.x);
+      min_y = min_y.min(pt.y);
+      max_x = max_x.max(pt.x);
+      max_y = max_y.max(pt.y);
+    }
+  }
+  if segments.is_empty() {
+    min_x = 0.0; min_y = 0.0; max_x = 0.0; max_y = 0.0;
+  }
+
+  let extent = (max_x - min_x).max(max_y - min_y);
+  let scale = if extent > 0.0 { options.size / extent } else { 1.0 };
+  let width = (max_x - min_x) * scale + 2.0 * options.margin;
+  let height = (max_y - min_y) * scale + 2.0 * options.margin;
+  // SVG has y pointing down
+  let to_svg = |pt: Point2<f32>| ((pt.x - min_x) * scale + options.margin, (max_y - pt.y) * scale + options.margin);
+
+  writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+  writeln!(out, "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\">", width, height, width, height)?;
+  writeln!(out, "  <g fill=\"none\" stroke-width=\"{}\" stroke-linecap=\"round\" stroke-linejoin=\"round\">", options.stroke_width)?;
+
+  let mut idx = 0;
+  while idx < segments.len() {
+    let (start, _, color) = segments[idx];
+    let (x, y) = to_svg(start);
+    let mut path = format!("M{} {}", x, y);
+    while idx < segments.len() {
+      let (_, end, _) = segments[idx];
+      let (x, y) = to_svg(end);
+      path.push_str(& format!(" L{} {}", x, y));
+      idx += 1;
+      // Keep going while the next segment picks up where this one ended
+      if idx >= segments.len() || segments[idx].0 != end || segments[idx].2 != color { break; }
+    }
+    if color.w < 1.0 {
+      writeln!(out, "    <path d=\"{}\" stroke=\"{}\" stroke-opacity=\"{}\"/>", path, svg_color(color), color.w.max(0.0))?;
+    } else {
+      writeln!(out, "    <path d=\"{}\" stroke=\"{}\"/>", path, svg_color(color))?;
+    }
+  }
+
+  writeln!(out, "  </g>")?;
+  writeln!(out, "</svg>")?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn svg(mesh: & LineMesh, view: View) -> String {
+    let mut out = Vec::new();
+    write_svg(mesh, & SvgOptions::new(view), &mut out).unwrap();
+    String::from_utf8(out).unwrap()
+  }
+
+  fn paths(svg: & str) -> Vec<& str> {
+    svg.lines().filter(|line| line.trim().starts_with("<path")).collect()
+  }
+
+  #[test]
+  fn paths_follow_colours_and_jumps() {
+    let (red, green) = (Vector4::new(1.0, 0.0, 0.0, 1.0), Vector4::new(0.0, 1.0, 0.0, 1.0));
+    let mut mesh = LineMesh::new();
+    mesh.set_color(red);
+    mesh.append_segment(Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0));
+    mesh.append_segment(Point3::new(1.0, 0.0, 0.0), Point3::new(1.0, 2.0, 0.0));
+    mesh.move_to(Point3::new(3.0, 2.0, 0.0));
+    mesh.append_segment(Point3::new(3.0, 2.0, 0.0), Point3::new(4.0, 2.0, 0.0));
+    mesh.set_color(green);
+    mesh.append_segment(Point3::new(4.0, 2.0, 0.0), Point3::new(4.0, 3.0, 0.0));
+
+    let svg = svg(& mesh, View::Front);
+    // 4 by 3 scaled to 800 across, with a margin of 10
+    assert!(svg.contains("viewBox=\"0 0 820 620\""));
+    let paths = paths(& svg);
+    assert_eq!(paths.len(), 3);
+    // The touching red segments are one path, and y points down
+    assert!(paths[0].contains("d=\"M10 610 L210 610 L210 210\" stroke=\"#ff0000\""));
+    assert!(paths[1].contains("d=\"M610 210 L810 210\" stroke=\"#ff0000\""));
+    assert!(paths[2].contains("d=\"M810 210 L810 10\" stroke=\"#00ff00\""));
+  }
+
+  #[test]
+  fn empty_drawings_are_just_the_margin() {
+    let mut mesh = LineMesh::new();
+    assert!(svg(& mesh, View::Front).contains("viewBox=\"0 0 20 20\""));
+
+    // A vertical line seen from above is a point
+    mesh.append_segment(Point3::new(1.0, 0.0, 1.0), Point3::new(1.0, 5.0, 1.0));
+    let svg = svg(& mesh, View::Top);
+    assert!(svg.contains("viewBox=\"0 0 20 20\""));
+    assert!(paths(& svg).is_empty());
+    assert!(!svg.contains("NaN") && !svg.contains("inf"));
+  }
+}
@@ -0,0 +1,434 @@
+//! Generates plants without opening a window, for batch jobs on headless machines.
+//!
+//! Usage: generate <system name | grammar file> [-n iterations] [-s seed] [-o output] [-f format] [--view front|top|side]
+//!                 [--branches prisms|tubes] [--pipe exponent] [--taper] [-t time] [--frames count]
+//!                 [--threads count] [--timings] [--max-modules count] [--max-time seconds] [--max-memory bytes]
+//!                 [--max-iterations count] [--weld tolerance]
+//!
+//! The format is one of obj, ply, glb, svg, word, lsw, json or csv, and defaults to the output's extension.
+//! Without an output the word is written to stdout. Words are written in the notation of the
+//! `notation` module, with each branch indented on its own lines, and lsw files are words in the
+//! binary format of the `word_cache` module. An lsw file can be given in place of a system, to draw
+//! the word it holds without deriving it again, with the same -s to place its foliage the same way.
+//! json and csv are the measurements of the `metrics` module rather than the plant itself.
+//!
+//! Timed systems are grown until the time given by -t, which defaults to the number of
+//! iterations. With --frames, that many frames of their growth are written, numbered after the output.
+//!
+//! --threads sets how many threads the derivation uses, and --timings reports how long each
+//! iteration took on stderr. With --max-modules, --max-time, --max-memory or --max-iterations, the
+//! derivation stops at the last word which fits within them.
+//!
+//! --weld merges the vertices of obj, ply and glb meshes which are within the tolerance of each
+//! other and have the same normal, colour and texture coordinates.
+
+extern crate lsystem;
+extern crate line_mesh;
+extern crate vertex_index_mesh;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::process;
+use std::time::Duration;
+
+use lsystem::lsystem::{LSystem, Module, run_system_budgeted};
+use lsystem::executor::Executor;
+use lsystem::budget::{Budget, StopReason};
+use lsystem::grammar::parse_grammar;
+use lsystem::trees::*;
+use lsystem::turtle::Tropism;
+use lsystem::draw_helpers::{BranchStyle, OrganMeshes, ls_to_lines, ls_to_organ_meshes};
+use lsystem::pipe_model::PipeModel;
+use lsystem::notation::format_word_indented;
+use lsystem::word_cache;
+use lsystem::word_tree::check_brackets;
+use lsystem::metrics::{self, plant_metrics};
+use lsystem::timed::{self, TimedLSystem};
+use lsystem::rand_util;
+use line_mesh::{SvgOptions, View};
+use vertex_index_mesh::{WeldTolerance, weld_vertices};
+
+const USAGE: &'static str = "Usage: generate <system name | grammar file> [-n iterations] [-s seed] [-o output] [-f format] [--view front|top|side] [--branches prisms|tubes] [--pipe exponent] [--taper] [-t time] [--frames count] [--threads count] [--timings] [--max-modules count] [--max-time seconds] [--max-memory bytes] [--max-iterations count] [--weld tolerance]
+Systems: koch, dragon, basic, branching, round, acropetal, bush, growing (timed), or a cached .lsw word
+Formats: obj, ply, glb, svg, word (the default when there is no output), lsw, json or csv (measurements)";
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Format {
+  Obj,
+  Ply,
+  Glb,
+  Svg,
+  Word,
+  Cache,
+  Json,
+  Csv,
+}
+
+impl Format {
+  fn from_name(name: & str) -> Option<Format> {
+    match name {
+      "obj" => Some(Format::Obj),
+      "ply" => Some(Format::Ply),
+      "glb" => Some(Format::Glb),
+      "svg" => Some(Format::Svg),
+      "word" | "txt" => Some(Format::Word),
+      "lsw" => Some(Format::Cache),
+      "json" => Some(Format::Json),
+      "csv" => Some(Format::Csv),
+      _ => None,
+    }
+  }
+}
+
+struct Options {
+  system: String,
+  iterations: u32,
+  seed: u64,
+  output: Option<String>,
+  format: Format,
+  view: View,
+  style: BranchStyle,
+  /// Radii from the pipe model, instead of the segments' widths
+  pipe: Option<PipeModel>,
+  weld: Option<WeldTolerance>,
+  /// How long timed systems grow for
+  time: Option<f32>,
+  frames: Option<u32>,
+  executor: Executor,
+  timings: bool,
+  budget: Budget,
+}
+
+fn parse_args(args: & [String]) -> Result<Options, String> {
+  let mut system = None;
+  let mut iterations = 5;
+  let mut seed = None;
+  let mut output = None;
+  let mut format = None;
+  let mut view = View::Front;
+  let mut style = BranchStyle::Prisms;
+  let mut exponent = None;
+  let mut taper = false;
+  let mut weld = None;
+  let mut time = None;
+  let mut frames = None;
+  let mut threads = None;
+  let mut timings = false;
+  let mut budget = Budget::unlimited();
+
+  let mut args = args.iter();
+  while let Some(arg) = args.next() {
+    if !arg.starts_with('-') {
+      if system.is_some() {
+        return Err(format!("unexpected argument '{}'", arg));
+      }
+      system = Some(arg.clone());
+      continue;
+    }
+    if arg == "--taper" {
+      taper = true;
+      continue;
+    }
+    if arg == "--timings" {
+      timings = true;
+      continue;
+    }
+
+    let value = match args.next() {
+      Some(value) => value,
+      None => return Err(format!("'{}' needs a value", arg)),
+    };
+    match arg.as_str() {
+      "-n" | "--iterations" => {
+        iterations = value.parse().map_err(|_| format!("'{}' is not a number of iterations", value))?;
+      },
+      "-s" | "--seed" => {
+        seed = Some(value.parse().map_err(|_| format!("'{}' is not a seed", value))?);
+      },
+      "-o" | "--output" => {
+        output = Some(value.clone());
+      },
+      "-f" | "--format" => {
+        format = Some(Format::from_name(value).ok_or(format!("unknown format '{}'", value))?);
+      },
+      "--view" => {
+        view = match value.as_str() {
+          "front" => View::Front,
+          "top" => View::Top,
+          "side" => View::Side,
+          other => return Err(format!("unknown view '{}'", other)),
+        };
+      },
+      "-t" | "--time" => {
+        time = Some(value.parse().map_err(|_| format!("'{}' is not a time", value))?);
+      },
+      "--threads" => {
+        threads = Some(value.parse().map_err(|_| format!("'{}' is not a number of threads", value))?);
+      },
+      "--max-modules" => {
+        budget.max_modules = Some(value.parse().map_err(|_| format!("'{}' is not a number of modules", value))?);
+      },
+      "--max-time" => {
+        let seconds: f64 = value.parse().map_err(|_| format!("'{}' is not a number of seconds", value))?;
+        if !(seconds > 0.0 && seconds.is_finite()) {
+          return Err(format!("the time limit must be a positive number of seconds, not {}", seconds));
+        }
+        budget.max_time = Some(Duration::new(seconds.trunc() as u64, (seconds.fract() * 1.0e9) as u32));
+      },
+      "--max-memory" => {
+        budget.max_memory = Some(value.parse().map_err(|_| format!("'{}' is not a number of bytes", value))?);
+      },
+      "--max-iterations" => {
+        budget.max_iterations = Some(value.parse().map_err(|_| format!("'{}' is not a number of iterations", value))?);
+      },
+      "--frames" => {
+        frames = Some(value.parse().map_err(|_| format!("'{}' is not a number of frames", value))?);
+      },
+      "--pipe" => {
+        let value: f32 = value.parse().map_err(|_| format!("'{}' is not an exponent", value))?;
+        if !(value > 0.0) {
+          return Err(format!("the pipe exponent must be positive, not {}", value));
+        }
+        exponent = Some(value);
+      },
+      "--weld" => {
+        let value: f32 = value.parse().map_err(|_| format!("'{}' is not a tolerance", value))?;
+        if !(value >= 0.0 && value.is_finite()) {
+          return Err(format!("the weld tolerance must be a distance of 0 or more, not {}", value));
+        }
+        weld = Some(WeldTolerance::new(value));
+      },
+      "--branches" => {
+        style = match value.as_str() {
+          "prisms" => BranchStyle::Prisms,
+          "tubes" => BranchStyle::Tubes,
+          other => return Err(format!("unknown branch style '{}'", other)),
+        };
+      },
+      _ => return Err(format!("unknown option '{}'", arg)),
+    }
+  }
+
+  let system = match system {
+    Some(system) => system,
+    None => return Err("no system given".to_string()),
+  };
+  let format = match (format, output.as_ref()) {
+    (Some(format), _) => format,
+    (None, Some(output)) => {
+      let extension = Path::new(output).extension().and_then(|ext| ext.to_str()).unwrap_or("");
+      match Format::from_name(& extension.to_lowercase()) {
+        Some(format) => format,
+        None => return Err(format!("can't tell the format of '{}', use -f", output)),
+      }
+    },
+    (None, None) => Format::Word,
+  };
+
+  if frames.is_some() && output.is_none() {
+    return Err("frames need an output to be numbered after".to_string());
+  }
+
+  let pipe = match (exponent, taper) {
+    (Some(exponent), _) => Some(PipeModel { taper: taper, .. PipeModel::new(exponent) }),
+    (None, true) => Some(PipeModel { taper: true, .. PipeModel::da_vinci() }),
+    (None, false) => None,
+  };
+
+  Ok(Options {
+    system: system,
+    iterations: iterations,
+    seed: seed.unwrap_or_else(rand_util::random::<u64>),
+    output: output,
+    format: format,
+    view: view,
+    style: style,
+    pipe: pipe,
+    weld: weld,
+    time: time,
+    frames: frames,
+    executor: match threads {
+      Some(threads) => Executor { threads: threads, .. Executor::new() },
+      None => Executor::new(),
+    },
+    timings: timings,
+    budget: budget,
+  })
+}
+
+/// The meshes of the plant, welded if --weld was given
+fn organ_meshes(word: & [Module], tropism: & Tropism, options: & Options) -> OrganMeshes {
+  let meshes = ls_to_organ_meshes(word, tropism, options.seed, options.style, options.pipe.as_ref());
+  match options.weld {
+    Some(ref tolerance) => OrganMeshes {
+      trunk: weld_vertices(meshes.trunk, tolerance),
+      branches: weld_vertices(meshes.branches, tolerance),
+      foliage: weld_vertices(meshes.foliage, tolerance),
+    },
+    None => meshes,
+  }
+}
+
+fn write_output<W: Write>(word: & [Module], tropism: & Tropism, options: & Options, output: Option<& str>, out: &mut W) -> io::Result<()> {
+  match options.format {
+    Format::Obj => {
+      let mesh = organ_meshes(word, tropism, options).combined();
+      // Materials go next to the OBJ file, when there is one
+      match output {
+        Some(output) => {
+          let mtl_path = Path::new(output).with_extension("mtl");
+          let mtl_name = mtl_path.file_name().and_then(|name| name.to_str()).unwrap_or("materials.mtl").to_string();
+          vertex_index_mesh::write_mtl(& mesh, &mut BufWriter::new(File::create(& mtl_path)?))?;
+          vertex_index_mesh::write_obj(& mesh, out, Some(& mtl_name))
+        },
+        None => vertex_index_mesh::write_obj(& mesh, out, None),
+      }
+    },
+    Format::Ply => vertex_index_mesh::write_ply(& organ_meshes(word, tropism, options).combined(), out),
+    Format::Glb => organ_meshes(word, tropism, options).write_glb(out),
+    Format::Svg => line_mesh::write_svg(& ls_to_lines(word, tropism), & SvgOptions::new(options.view), out),
+    Format::Word => out.write_all(format_word_indented(word, "  ").as_bytes()),
+    Format::Cache => word_cache::write_word(word, out),
+    Format::Json | Format::Csv => {
+      let metrics = plant_metrics(word, tropism).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+      match options.format {
+        Format::Json => writeln!(out, "{}", metrics.to_json()),
+        _ => writeln!(out, "{}\n{}", metrics::CSV_HEADER, metrics.to_csv()),
+      }
+    },
+  }
+}
+
+/// Write to the output file, or to stdout if there is none
+fn save(word: & [Module], tropism: & Tropism, options: & Options, output: Option<& str>) -> io::Result<()> {
+  if let Err(err) = check_brackets(word) {
+    writeln!(io::stderr(), "warning: unbalanced brackets, {}", err)?;
+  }
+  match output {
+    Some(output) => {
+      let mut out = BufWriter::new(File::create(output)?);
+      write_output(word, tropism, options, Some(output), &mut out)?;
+      out.flush()
+    },
+    None => {
+      let stdout = io::stdout();
+      let mut out = BufWriter::new(stdout.lock());
+      write_output(word, tropism, options, None, &mut out)?;
+      out.flush()
+    },
+  }
+}
+
+/// The output with the frame number added to its name, so tree.obj becomes tree_0007.obj
+fn frame_path(output: & str, frame: usize) -> String {
+  let path = Path::new(output);
+  let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("frame");
+  let name = match path.extension().and_then(|ext| ext.to_str()) {
+    Some(ext) => format!("{}_{:04}.{}", stem, frame, ext),
+    None => format!("{}_{:04}", stem, frame),
+  };
+  path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+fn generate<T: LSystem<Module = Module>>(system: T, options: & Options) -> io::Result<()> {
+  if options.time.is_some() || options.frames.is_some() {
+    return Err(io::Error::new(io::ErrorKind::InvalidInput, "only timed systems take a time or frames"));
+  }
+  let tropism = system.tropism();
+  let (result, timings) = run_system_budgeted(system, options.iterations, options.seed, & options.executor, & options.budget)
+    .map_err(|err| io::Error::new(io::ErrorKind::Other, format!("derivation failed in {}", err)))?;
+  match result.reason {
+    StopReason::Completed | StopReason::Exhausted => (),
+    reason => writeln!(io::stderr(), "stopped after {} iterations: {:?}", result.iterations, reason)?,
+  }
+  let word = result.word;
+  if options.timings {
+    for timing in timings {
+      writeln!(io::stderr(), "iteration {}: {} -> {} modules in {} chunks, {:.3} ms", timing.iteration, timing.input_len, timing.output_len,
+        timing.chunks, timing.duration.as_secs() as f64 * 1000.0 + timing.duration.subsec_nanos() as f64 / 1.0e6)?;
+    }
+  }
+  save(& word, & tropism, options, options.output.as_ref().map(|output| output.as_str()))
+}
+
+fn generate_timed<T: TimedLSystem<Module = Module>>(system: T, options: & Options) -> io::Result<()> {
+  let tropism = system.tropism();
+  let time = options.time.unwrap_or(options.iterations as f32);
+  match (options.frames, options.output.as_ref()) {
+    (Some(count), Some(output)) => {
+      for (frame, word) in timed::frames(& system, time, count).iter().enumerate() {
+        save(word, & tropism, options, Some(& frame_path(output, frame)))?;
+      }
+      Ok(())
+    },
+    _ => save(& timed::word_at(& system, time), & tropism, options, options.output.as_ref().map(|output| output.as_str())),
+  }
+}
+
+fn run(options: & Options) -> Result<(), String> {
+  let result = match options.system.as_str() {
+    "koch" => generate(KochCurve, options),
+    "dragon" => generate(DragonCurve, options),
+    "basic" => generate(BasicTree, options),
+    "branching" => generate(BranchingTree { base_width: 0.1, base_length: 0.5, tropism: Tropism::none() }, options),
+    "round" => generate(RoundTree {
+      base_width: 0.15,
+      trunk_base_length: 0.1,
+      branch_base_length: 1.0,
+      base_foliage_radius: 0.5,
+      base_foliage_length: 1.0,
+      tropism: Tropism::none(),
+    }, options),
+    "acropetal" => generate(AcropetalSignal, options),
+    "bush" => generate(StochasticBush, options),
+    "growing" => generate_timed(GrowingTree {
+      base_width: 0.15,
+      segment_length: 1.0,
+      branch_angle: 45.0_f32.to_radians(),
+      branch_depth: 3,
+      foliage_radius: 0.5,
+      tropism: Tropism::none(),
+    }, options),
+    filename if filename.ends_with(".lsw") => {
+      // Every format needs the whole word at once, so it's read in rather than mapped
+      let word = word_cache::load_word(filename).map_err(|err| format!("{}: {}", filename, err))?;
+      // The word was derived already, and the tropism it was drawn with isn't kept
+      save(& word, & Tropism::none(), options, options.output.as_ref().map(|output| output.as_str()))
+    },
+    filename => {
+      let mut source = String::new();
+      File::open(filename)
+        .and_then(|mut file| file.read_to_string(&mut source))
+        .map_err(|err| format!("{}: {}", filename, err))?;
+      let grammar = parse_grammar(& source).map_err(|err| format!("{}: {}", filename, err))?;
+      generate(grammar, options)
+    },
+  };
+  result.map_err(|err| match options.output {
+    Some(ref output) => format!("{}: {}", output, err),
+    None => err.to_string(),
+  })
+}
+
+fn main() {
+  let args: Vec<String> = env::args().skip(1).collect();
+  if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+    println!("{}", USAGE);
+    return;
+  }
+
+  let options = match parse_args(& args) {
+    Ok(options) => options,
+    Err(message) => {
+      writeln!(io::stderr(), "{}\n{}", message, USAGE).unwrap();
+      process::exit(2);
+    },
+  };
+
+  if let Err(message) = run(& options) {
+    writeln!(io::stderr(), "{}", message).unwrap();
+    process::exit(1);
+  }
+}
@@ -0,0 +1,58 @@
+//! Limits which stop a derivation before it grows out of hand, see `lsystem::run_system_budgeted`
+
+use std::time::Duration;
+
+/// How far a derivation may go. Every limit is optional, and the default has none.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Budget {
+  /// The longest word to produce
+  pub max_modules: Option<usize>,
+  /// The most iterations to run, whatever the derivation asks for
+  pub max_iterations: Option<u32>,
+  /// How long the whole derivation may take
+  pub max_time: Option<Duration>,
+  /// Bytes of memory for the words, estimated as the size of the modules in the word being
+  /// rewritten and the word being produced
+  pub max_memory: Option<usize>,
+}
+
+impl Budget {
+  pub fn unlimited() -> Budget { Budget::default() }
+
+  /// Words of up to `max_modules`, produced within `max_time`
+  pub fn interactive(max_modules: usize, max_time: Duration) -> Budget {
+    Budget {
+      max_modules: Some(max_modules),
+      max_time: Some(max_time),
+      .. Budget::default()
+    }
+  }
+}
+
+/// Why a derivation stopped
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StopReason {
+  /// All of the iterations asked for were run
+  Completed,
+  /// The word became empty, so there was nothing left to rewrite
+  Exhausted,
+  /// The next word would have been longer than `max_modules`
+  MaxModules,
+  /// `max_iterations` iterations were run, fewer than were asked for
+  MaxIterations,
+  /// The derivation ran out of time during an iteration
+  MaxTime,
+  /// The next word would have used more than `max_memory`
+  MaxMemory,
+}
+
+/// The word which a budgeted derivation ended with, and how it got there
+#[derive(Clone, Debug)]
+pub struct BudgetedWord<M> {
+  /// The word of the last iteration which finished within the budget, or an empty word when
+  /// even the axiom was over it
+  pub word: Vec<M>,
+  /// How many iterations produced the word
+  pub iterations: u32,
+  pub reason: StopReason,
+}
@@ -1,65 +1,64 @@
+use std::io::{self, Write};
+
 use glium::index::PrimitiveType;
 use cgmath::*;
-use rand;
+use rand::Rng;
 
 use defs::*;
-use matrixstack;
-use lsystem::{Module, DrawCommand};
+use lsystem::{ToDrawCommand, Organ, DerivationRng, derivation_rng};
+use turtle::{self, Tropism, TurtleSink, TurtleState};
 use line_mesh::LineMesh;
 use vertex_index_mesh::{self, VertexIndexMesh, Vertex};
 use rand_util;
-use convex_hull;
-use half_edge_mesh::{HalfEdgeMesh, ToPtrVec};
-
-pub fn ls_to_lines(word: &[Module]) -> LineMesh {
-  let mut line = LineMesh::new();
-
-  // lsystem moves by default in the positive-y direction
-  let base_heading = Vec3::new(0.0, 1.0, 0.0);
-  let mut mat_stack: matrixstack::MatrixStack<f32> = matrixstack::MatrixStack::new();
-
-  // Starting point
-  line.append_point(Pt::origin());
-
-  for item in word {
-    match item.to_draw_command() {
-      DrawCommand::Foliage { .. } => (), // Don't draw foliage in the line version
-      DrawCommand::Segment { w: _, l: length } => {
-        mat_stack.transform(Matrix4::from_translation(base_heading * length));
-        line.append_point(mat_stack.origin());
-      },
-      DrawCommand::Forward { d: distance } => {
-        mat_stack.transform(Matrix4::from_translation(base_heading * distance));
-        line.move_to(mat_stack.origin());
-      },
-      DrawCommand::Roll { r } => {
-        mat_stack.rotate(Matrix3::from_angle_z(Rad(r)));
-      },
-      DrawCommand::Pitch { r } => {
-        mat_stack.rotate(Matrix3::from_angle_x(Rad(r)));
-      },
-      DrawCommand::Yaw { r } => {
-        mat_stack.rotate(Matrix3::from_angle_y(Rad(r)));
-      },
-      DrawCommand::Euler { x, y, z } => {
-        mat_stack.rotate(Matrix3::from(Euler::new(Rad(x), Rad(y), Rad(z))));
-      },
-      DrawCommand::Push => {
-        mat_stack.push();
-        line.move_to(mat_stack.origin());
-      },
-      DrawCommand::Pop => {
-        mat_stack.pop();
-        line.move_to(mat_stack.origin());
-      },
-      DrawCommand::None => (),
-    }
-  }
-
-  return line;
-}
-
-fn cylinder(start: Pt, end: Pt, facets: u32, radius: f32) -> VertexIndexMesh {
+use hull;
+use pipe_model::{self, PipeModel};
+
+/// Draws the turtle's path as lines, jumping to the start of each branch and back
+pub struct LineSink {
+  pub line: LineMesh,
+}
+
+impl LineSink {
+  pub fn new() -> LineSink {
+    let mut line = LineMesh::new();
+    // Opaque black, so that exported lines show up on paper
+    line.set_color(Vec4::new(0.0, 0.0, 0.0, 1.0));
+    // Starting point
+    line.append_point(Pt::origin());
+    LineSink { line: line }
+  }
+}
+
+impl TurtleSink for LineSink {
+  fn segment(&mut self, _start: Pt, end: Pt, _width: f32, _organ: Organ, _state: & TurtleState) {
+    self.line.append_point(end);
+  }
+
+  // Don't draw foliage in the line version
+
+  fn move_to(&mut self, state: & TurtleState) {
+    self.line.move_to(state.position());
+  }
+
+  fn branch_start(&mut self, state: & TurtleState) {
+    self.line.move_to(state.position());
+  }
+
+  fn branch_end(&mut self, state: & TurtleState) {
+    self.line.move_to(state.position());
+  }
+}
+
+pub fn ls_to_lines<M: ToDrawCommand>(word: &[M], tropism: & Tropism) -> LineMesh {
+  let mut sink = LineSink::new();
+  turtle::interpret(word, tropism, &mut sink);
+  sink.line
+}
+
+/// A prism from `start` to `end`, whose radius goes from `start_radius` to `end_radius`. The sides
+/// share the vertices of their rings, while each cap has its own copy of its ring, so that the caps
+/// don't bend the normals of the sides or take their colours.
+fn cylinder(start: Pt, end: Pt, facets: u32, start_radius: f32, end_radius: f32) -> VertexIndexMesh {
   if facets < 2 { return VertexIndexMesh::new(PrimitiveType::TrianglesList); }
 
   let rot_angle = Rad::full_turn() / (facets as f32);
@@ -68,31 +67,35 @@ fn cylinder(start: Pt, end: Pt, facets: u32, radius: f32) -> VertexIndexMesh {
   let stem_axis = (end - start).normalize();
   // If the vector happens to be the x axis, the cross product won't work
   let cross_vec = if stem_axis == Vec3::unit_x() { Vec3::unit_y() } else { Vec3::unit_x() };
-  // let perp_vec = stem_axis.cross(cross_vec).normalize_to(radius);
-  let perp_vec = stem_axis.cross(cross_vec).normalize_to(radius);
+  let perp_vec = stem_axis.cross(cross_vec).normalize();
 
   let mut mesh = VertexIndexMesh::new(PrimitiveType::TrianglesList);
 
-  let half_step = Mat3::from_axis_angle(stem_axis, offset_angle);
-  let full_step = Mat3::from_axis_angle(stem_axis, rot_angle);
-  let one_and_one_half_step = Mat3::from_axis_angle(stem_axis, rot_angle + offset_angle);
-
-  let base_point = start + perp_vec;
-  let top_point = end + (half_step * perp_vec);
-  let next_point = start + (full_step * perp_vec);
-  let top_next_point = end + (one_and_one_half_step * perp_vec);
-
-  let base_struct = vec![start, base_point, next_point, top_point, top_next_point, end];
-  // bottom, left tri, right tri, top
-  let base_indices: Vec<usize> = vec![0, 2, 1, 1, 2, 3, 2, 4, 3, 3, 4, 5];
-
+  // The top ring is rotated by half a step from the bottom one
+  let start_idx = mesh.add_vertex_shared(Vertex::pos_only(start.as_ref()));
+  let end_idx = mesh.add_vertex_shared(Vertex::pos_only(end.as_ref()));
+  let mut bottom_ring = Vec::with_capacity(facets as usize);
+  let mut top_ring = Vec::with_capacity(facets as usize);
+  let mut bottom_cap = Vec::with_capacity(facets as usize);
+  let mut top_cap = Vec::with_capacity(facets as usize);
   for base_num in 0..facets {
-    let base_mult = base_num as f32;
-    let rot_matrix = Mat4::from_translation(start.to_vec()) * Mat4::from_axis_angle(stem_axis, rot_angle * base_mult) * Mat4::from_translation(-start.to_vec());
-    let rotated = transform_points(& base_struct, rot_matrix);
-    for & idx in & base_indices {
-      mesh.add_vertex(Vertex::pos_only(rotated[idx].as_ref()));
-    }
+    let angle = rot_angle * (base_num as f32);
+    let bottom_point = start + Mat3::from_axis_angle(stem_axis, angle) * perp_vec * start_radius;
+    let top_point = end + Mat3::from_axis_angle(stem_axis, angle + offset_angle) * perp_vec * end_radius;
+    bottom_ring.push(mesh.add_vertex_shared(Vertex::pos_only(bottom_point.as_ref())));
+    top_ring.push(mesh.add_vertex_shared(Vertex::pos_only(top_point.as_ref())));
+    bottom_cap.push(mesh.add_vertex_shared(Vertex::pos_only(bottom_point.as_ref())));
+    top_cap.push(mesh.add_vertex_shared(Vertex::pos_only(top_point.as_ref())));
+  }
+
+  for base_num in 0..(facets as usize) {
+    let next_num = (base_num + 1) % (facets as usize);
+    let (base, next, top, top_next) = (bottom_ring[base_num], bottom_ring[next_num], top_ring[base_num], top_ring[next_num]);
+    // bottom, left tri, right tri, top
+    mesh.add_triangle(start_idx, bottom_cap[next_num], bottom_cap[base_num]);
+    mesh.add_triangle(base, next, top);
+    mesh.add_triangle(next, top_next, top);
+    mesh.add_triangle(top_cap[base_num], top_cap[next_num], end_idx);
   }
 
   return mesh;
@@ -102,41 +105,150 @@ fn transform_points(points: & [Pt], transform: Mat4) -> Vec<Pt> {
   points.iter().map(|pt| Pt::from_vec((transform * pt.to_homogeneous()).truncate())).collect()
 }
 
-pub fn generate_branch(start: Pt, end: Pt, facets: u32, radius: f32) -> VertexIndexMesh {
-  let mut branch_body = cylinder(start, end, facets, radius);
+/// A cylinder with random bark colours, drawn from `num_gen` for each triangle. The sides share
+/// their vertices, so a later side triangle's colour wins at the corners they have in common.
+pub fn generate_branch<R: Rng>(start: Pt, end: Pt, facets: u32, radius: f32, num_gen: &mut R) -> VertexIndexMesh {
+  generate_tapered_branch(start, end, facets, radius, radius, num_gen)
+}
+
+/// Like `generate_branch`, narrowing from `start_radius` to `end_radius`
+pub fn generate_tapered_branch<R: Rng>(start: Pt, end: Pt, facets: u32, start_radius: f32, end_radius: f32, num_gen: &mut R) -> VertexIndexMesh {
+  let mut branch_body = cylinder(start, end, facets, start_radius, end_radius);
 
   branch_body = vertex_index_mesh::assign_colors(branch_body, |_, _| {
-    let redval = rand_util::random_lohi(25.0_f32, 70.0_f32);
-    let ratio_grn = rand_util::random_lohi(1.45_f32, 1.65_f32);
-    let ratio_blu = rand_util::random_lohi(3.0_f32, 3.4_f32);
-    let (r, g, b) = (redval, redval / ratio_grn, redval / ratio_blu);
-    let v = Vec4::new(r / 255.0, g / 255.0, b / 255.0, 1.0);
+    let v = bark_color(num_gen);
     [v, v, v]
   });
 
   branch_body
 }
 
-fn to_vertex_index_mesh(half_edge: HalfEdgeMesh) -> VertexIndexMesh {
+fn bark_color<R: Rng>(num_gen: &mut R) -> Vec4 {
+  let redval = rand_util::random_lohi(num_gen, 25.0_f32, 70.0_f32);
+  let ratio_grn = rand_util::random_lohi(num_gen, 1.45_f32, 1.65_f32);
+  let ratio_blu = rand_util::random_lohi(num_gen, 3.0_f32, 3.4_f32);
+  let (r, g, b) = (redval, redval / ratio_grn, redval / ratio_blu);
+  Vec4::new(r / 255.0, g / 255.0, b / 255.0, 1.0)
+}
+
+/// Any unit vector perpendicular to `axis`
+fn perpendicular(axis: Vec3) -> Vec3 {
+  let cross_vec = if axis.x.abs() < 0.9 { Vec3::unit_x() } else { Vec3::unit_y() };
+  axis.cross(cross_vec).normalize()
+}
+
+/// The most a joint ring of `sweep_tube` is stretched, so that very sharp bends don't make spikes
+const MAX_MITER: f32 = 4.0;
+
+/// A generalized cylinder swept along `points`, with `radii[i]` the start and end radius of the
+/// segment from `points[i]` to `points[i + 1]`. There is one ring of vertices at each point, perpendicular to
+/// the path, and consecutive segments share the ring between them, so the joints are closed. The
+/// rings at the joints are mitred: stretched across the bend by 1 / cos(θ/2) for a bend of θ, so
+/// that they meet the walls of both segments and the tube keeps its thickness around the bend.
+/// The rings' orientation starts from `normal` and follows the path by parallel transport, which
+/// rotates it as little as possible at each bend, so the tube doesn't twist. Both ends are capped.
+pub fn sweep_tube(points: & [Pt], radii: & [(f32, f32)], normal: Vec3, facets: u32) -> VertexIndexMesh {
   let mut mesh = VertexIndexMesh::new(PrimitiveType::TrianglesList);
+  if facets < 2 || points.len() < 2 || radii.len() + 1 != points.len() { return mesh; }
 
-  for face in half_edge.faces.values() {
-    let face_borrow = face.borrow();
-    for vert in face_borrow.adjacent_verts().to_ptr_vec() {
-      mesh.add_vertex(Vertex::pos_only(vert.borrow().pos.as_ref()));
+  let segments = radii.len();
+  let directions: Vec<Vec3> = points.windows(2).map(|pair| (pair[1] - pair[0]).normalize()).collect();
+
+  let rot_angle = Rad::full_turn() / (facets as f32);
+  let mut rings: Vec<Vec<Idx>> = Vec::with_capacity(points.len());
+  let mut frame_normal = normal;
+  let mut prev_tangent = directions[0];
+  for (idx, & point) in points.iter().enumerate() {
+    // Rings at the joints lie halfway between the directions of the segments on either side, and
+    // the length of that halfway vector is 2 cos(θ/2)
+    let (tangent, radius, miter) = if idx == 0 {
+      (directions[0], radii[0].0, None)
+    } else if idx == segments {
+      (directions[segments - 1], radii[segments - 1].1, None)
+    } else {
+      let halfway = directions[idx - 1] + directions[idx];
+      let bend = directions[idx] - directions[idx - 1];
+      let radius = (radii[idx - 1].1 + radii[idx].0) / 2.0;
+      if halfway.magnitude() < 1.0e-6 {
+        (directions[idx], radius, None)
+      } else if bend.magnitude() < 1.0e-6 {
+        (halfway.normalize(), radius, None)
+      } else {
+        (halfway.normalize(), radius, Some((bend.normalize(), (2.0 / halfway.magnitude()).min(MAX_MITER))))
+      }
+    };
+
+    // Parallel transport: the smallest rotation which takes the last tangent to this one
+    let axis = prev_tangent.cross(tangent);
+    if axis.magnitude() > 1.0e-6 {
+      let angle = axis.magnitude().atan2(prev_tangent.dot(tangent));
+      frame_normal = Mat3::from_axis_angle(axis.normalize(), Rad(angle)) * frame_normal;
+    }
+    frame_normal = frame_normal - tangent * frame_normal.dot(tangent);
+    frame_normal = if frame_normal.magnitude() < 1.0e-6 { perpendicular(tangent) } else { frame_normal.normalize() };
+    prev_tangent = tangent;
+
+    let perp_vec = frame_normal * radius;
+    rings.push((0..facets).map(|base_num| {
+      let offset = Mat3::from_axis_angle(tangent, rot_angle * (base_num as f32)) * perp_vec;
+      let offset = match miter {
+        Some((bend, factor)) => offset + bend * (offset.dot(bend) * (factor - 1.0)),
+        None => offset,
+      };
+      let ring_point = point + offset;
+      mesh.add_vertex_shared(Vertex::pos_only(ring_point.as_ref()))
+    }).collect());
+  }
+
+  let start_idx = mesh.add_vertex_shared(Vertex::pos_only(points[0].as_ref()));
+  let end_idx = mesh.add_vertex_shared(Vertex::pos_only(points[segments].as_ref()));
+  for base_num in 0..(facets as usize) {
+    let next_num = (base_num + 1) % (facets as usize);
+    mesh.add_triangle(start_idx, rings[0][next_num], rings[0][base_num]);
+    for pair in rings.windows(2) {
+      let (base, next, top, top_next) = (pair[0][base_num], pair[0][next_num], pair[1][base_num], pair[1][next_num]);
+      mesh.add_triangle(base, next, top);
+      mesh.add_triangle(next, top_next, top);
     }
+    mesh.add_triangle(rings[segments][base_num], rings[segments][next_num], end_idx);
   }
 
   mesh
 }
 
-pub fn generate_foliage(start: Pt, end: Pt, radius: f32) -> VertexIndexMesh {
+/// A tube along a branch's path with a random bark colour for each triangle, drawn from `num_gen`
+pub fn generate_tube<R: Rng>(points: & [Pt], radii: & [(f32, f32)], normal: Vec3, facets: u32, num_gen: &mut R) -> VertexIndexMesh {
+  vertex_index_mesh::assign_colors(sweep_tube(points, radii, normal, facets), |_, _| {
+    let v = bark_color(num_gen);
+    [v, v, v]
+  })
+}
+
+/// A convex hull around random points in a sphere, drawn from `num_gen`
+pub fn generate_foliage<R: Rng>(start: Pt, end: Pt, radius: f32, num_gen: &mut R) -> VertexIndexMesh {
+  // Foliage which hasn't started growing yet has nothing to show
+  if radius <= 0.0 { return VertexIndexMesh::new(PrimitiveType::TrianglesList); }
   let midpoint = (end.to_vec() + start.to_vec()) / 2.0_f32;
-  let points = rand_util::rand_points_in_sphere(&mut rand::thread_rng(), 200, radius);
+  let points = rand_util::rand_points_in_sphere(num_gen, 200, radius);
   let translation = Matrix4::from_translation(start.to_vec());
   let transformed_points = transform_points(& points, translation);
-  let hull = convex_hull::get_convex_hull(transformed_points);
-  let mut hull_mesh = to_vertex_index_mesh(hull);
+  let mut hull_mesh = VertexIndexMesh::new(PrimitiveType::TrianglesList);
+  // Only the points on the hull become vertices
+  let mut mesh_indices: Vec<Option<Idx>> = vec![None; transformed_points.len()];
+  for tri in hull::convex_hull(& transformed_points) {
+    let mut corners = [0; 3];
+    for (corner, & idx) in tri.iter().enumerate() {
+      corners[corner] = match mesh_indices[idx as usize] {
+        Some(mesh_idx) => mesh_idx,
+        None => {
+          let mesh_idx = hull_mesh.add_vertex_shared(Vertex::pos_only(transformed_points[idx as usize].as_ref()));
+          mesh_indices[idx as usize] = Some(mesh_idx);
+          mesh_idx
+        },
+      };
+    }
+    hull_mesh.add_triangle(corners[0], corners[1], corners[2]);
+  }
   hull_mesh = vertex_index_mesh::assign_colors(hull_mesh, |_, _| {
     let green = Vec4::new(62.0 / 255.0, 117.0 / 255.0, 31.0 / 255.0, 1.0);
     [green, green, green]
@@ -145,53 +257,341 @@ pub fn generate_foliage(start: Pt, end: Pt, radius: f32) -> VertexIndexMesh {
   hull_mesh
 }
 
-pub fn ls_to_cylinders(word: & [Module]) -> VertexIndexMesh {
-  let mut mesh = VertexIndexMesh::new(PrimitiveType::TrianglesList);
+/// A plant's geometry, split up by organ
+pub struct OrganMeshes {
+  pub trunk: VertexIndexMesh,
+  pub branches: VertexIndexMesh,
+  pub foliage: VertexIndexMesh,
+}
 
-  // lsystem moves by default in the positive-y direction
-  let base_heading = Vec3::new(0.0, 1.0, 0.0);
-  let mut mat_stack: matrixstack::MatrixStack<f32> = matrixstack::MatrixStack::new();
-
-  for item in word {
-    match item.to_draw_command() {
-      DrawCommand::Foliage { l: length, r: radius } => {
-        let start = mat_stack.origin();
-        mat_stack.transform(Matrix4::from_translation(base_heading * length));
-        let end = mat_stack.origin();
-        mesh.extend_with(& generate_foliage(start, end, radius));
-      },
-      DrawCommand::Segment { w: width, l: length } => {
-        let start = mat_stack.origin();
-        mat_stack.transform(Matrix4::from_translation(base_heading * length));
-        let end = mat_stack.origin();
-        mesh.extend_with(& generate_branch(start, end, 8, width / 2.0));
-      },
-      DrawCommand::Forward { d: distance } => {
-        mat_stack.transform(Matrix4::from_translation(base_heading * distance));
-      },
-      DrawCommand::Pitch { r } => {
-        mat_stack.rotate(Matrix3::from_angle_x(Rad(r)));
-      },
-      DrawCommand::Yaw { r } => {
-        mat_stack.rotate(Matrix3::from_angle_y(Rad(r)));
-      },
-      DrawCommand::Roll { r } => {
-        mat_stack.rotate(Matrix3::from_angle_z(Rad(r)));
-      },
-      DrawCommand::Euler { x, y, z } => {
-        mat_stack.rotate(Matrix3::from(Euler::new(Rad(x), Rad(y), Rad(z))));
-      },
-      DrawCommand::Push => {
-        mat_stack.push();
-      },
-      DrawCommand::Pop => {
-        mat_stack.pop();
-      },
-      DrawCommand::None => (),
-    }
-  }
-
-  mesh = vertex_index_mesh::recompute_normals(mesh);
+impl OrganMeshes {
+  pub fn new() -> OrganMeshes {
+    OrganMeshes {
+      trunk: VertexIndexMesh::new(PrimitiveType::TrianglesList),
+      branches: VertexIndexMesh::new(PrimitiveType::TrianglesList),
+      foliage: VertexIndexMesh::new(PrimitiveType::TrianglesList),
+    }
+  }
 
-  return mesh;
+  pub fn organ_mut(&mut self, organ: Organ) -> &mut VertexIndexMesh {
+    match organ {
+      Organ::Trunk => &mut self.trunk,
+      Organ::Branch => &mut self.branches,
+      Organ::Foliage => &mut self.foliage,
+    }
+  }
+
+  /// All of the organs in one mesh: the trunk, then the branches, then the foliage
+  pub fn combined(& self) -> VertexIndexMesh {
+    let mut mesh = VertexIndexMesh::new(PrimitiveType::TrianglesList);
+    mesh.extend_with(& self.trunk);
+    mesh.extend_with(& self.branches);
+    mesh.extend_with(& self.foliage);
+    mesh
+  }
+
+  /// Write the plant as binary glTF, with one node for each organ
+  pub fn write_glb<W: Write>(& self, out: &mut W) -> io::Result<()> {
+    vertex_index_mesh::write_glb(& [("trunk", & self.trunk), ("branches", & self.branches), ("foliage", & self.foliage)], out)
+  }
+}
+
+/// The radii of the segments, in the order they are drawn
+struct SegmentRadii {
+  // Given radii, such as those from `pipe_model::branch_radii`, otherwise half the segments' widths
+  radii: Option<Vec<(f32, f32)>>,
+  next: usize,
+}
+
+impl SegmentRadii {
+  fn new(radii: Option<Vec<(f32, f32)>>) -> SegmentRadii {
+    SegmentRadii { radii: radii, next: 0 }
+  }
+
+  /// The start and end radius of the next segment
+  fn next(&mut self, width: f32) -> (f32, f32) {
+    let given = self.radii.as_ref().and_then(|radii| radii.get(self.next).cloned());
+    self.next += 1;
+    given.unwrap_or((width / 2.0, width / 2.0))
+  }
+}
+
+/// The generator for the random parts of a mesh. The derivation draws from `derivation_rng` with
+/// the same seed, for each iteration and module, and the axiom from its last iteration's first
+/// module, so the mesh takes the next stream along to stay independent of both.
+fn mesh_rng(seed: u64) -> DerivationRng {
+  derivation_rng(seed, ::std::u32::MAX, 1)
+}
+
+/// Draws segments as cylinders with bark colours, and foliage as convex hulls.
+/// All of the randomness comes from the seed, so the same word and seed always give the same mesh.
+pub struct CylinderSink {
+  pub meshes: OrganMeshes,
+  num_gen: DerivationRng,
+  radii: SegmentRadii,
+}
+
+impl CylinderSink {
+  pub fn new(seed: u64) -> CylinderSink {
+    CylinderSink::with_radii(seed, None)
+  }
+
+  /// Draw the segments with the given start and end radii, in the order they are drawn,
+  /// instead of half their widths
+  pub fn with_radii(seed: u64, radii: Option<Vec<(f32, f32)>>) -> CylinderSink {
+    CylinderSink {
+      meshes: OrganMeshes::new(),
+      num_gen: mesh_rng(seed),
+      radii: SegmentRadii::new(radii),
+    }
+  }
+}
+
+impl TurtleSink for CylinderSink {
+  fn segment(&mut self, start: Pt, end: Pt, width: f32, organ: Organ, _state: & TurtleState) {
+    let (start_radius, end_radius) = self.radii.next(width);
+    let branch = generate_tapered_branch(start, end, 8, start_radius, end_radius, &mut self.num_gen);
+    self.meshes.organ_mut(organ).extend_with(& branch);
+  }
+
+  fn foliage(&mut self, start: Pt, end: Pt, radius: f32, _state: & TurtleState) {
+    let foliage = generate_foliage(start, end, radius, &mut self.num_gen);
+    self.meshes.foliage.extend_with(& foliage);
+  }
+}
+
+/// The path of a branch which is still being drawn by a TubeSink
+struct OpenTube {
+  organ: Organ,
+  points: Vec<Pt>,
+  radii: Vec<(f32, f32)>,
+  normal: Vec3,
+}
+
+/// Draws each branch as one tube following the turtle's path, and foliage as convex hulls.
+/// A branch's tube carries on through consecutive segments, past any child branches, and ends at
+/// a Pop, at foliage, at a jump, or where the organ changes. Call `finish` after interpreting
+/// the word to draw the branches which are still open.
+pub struct TubeSink {
+  pub meshes: OrganMeshes,
+  num_gen: DerivationRng,
+  radii: SegmentRadii,
+  current: Option<OpenTube>,
+  // The tubes of the parent branches, which carry on when their children end
+  parents: Vec<Option<OpenTube>>,
+}
+
+impl TubeSink {
+  pub fn new(seed: u64) -> TubeSink {
+    TubeSink::with_radii(seed, None)
+  }
+
+  /// Draw the segments with the given start and end radii, see `CylinderSink::with_radii`
+  pub fn with_radii(seed: u64, radii: Option<Vec<(f32, f32)>>) -> TubeSink {
+    TubeSink {
+      meshes: OrganMeshes::new(),
+      num_gen: mesh_rng(seed),
+      radii: SegmentRadii::new(radii),
+      current: None,
+      parents: Vec::new(),
+    }
+  }
+
+  fn end_tube(&mut self) {
+    if let Some(tube) = self.current.take() {
+      let mesh = generate_tube(& tube.points, & tube.radii, tube.normal, 8, &mut self.num_gen);
+      self.meshes.organ_mut(tube.organ).extend_with(& mesh);
+    }
+  }
+
+  /// Draw any branches which haven't ended, and return the meshes
+  pub fn finish(mut self) -> OrganMeshes {
+    self.end_tube();
+    while let Some(parent) = self.parents.pop() {
+      self.current = parent;
+      self.end_tube();
+    }
+    self.meshes
+  }
+}
+
+impl TurtleSink for TubeSink {
+  fn segment(&mut self, start: Pt, end: Pt, width: f32, organ: Organ, state: & TurtleState) {
+    let radii = self.radii.next(width);
+    if (end - start).magnitude() < 1.0e-6 { return; }
+    let continues = match self.current {
+      Some(ref tube) => tube.organ == organ && (tube.points[tube.points.len() - 1] - start).magnitude() < 1.0e-5,
+      None => false,
+    };
+    if !continues {
+      self.end_tube();
+      self.current = Some(OpenTube {
+        organ: organ,
+        points: vec![start],
+        radii: Vec::new(),
+        normal: (state.frame * Vec4::unit_x()).truncate(),
+      });
+    }
+    if let Some(ref mut tube) = self.current {
+      tube.points.push(end);
+      tube.radii.push(radii);
+    }
+  }
+
+  fn foliage(&mut self, start: Pt, end: Pt, radius: f32, _state: & TurtleState) {
+    self.end_tube();
+    let foliage = generate_foliage(start, end, radius, &mut self.num_gen);
+    self.meshes.foliage.extend_with(& foliage);
+  }
+
+  fn move_to(&mut self, _state: & TurtleState) {
+    self.end_tube();
+  }
+
+  fn branch_start(&mut self, _state: & TurtleState) {
+    let parent = self.current.take();
+    self.parents.push(parent);
+  }
+
+  fn branch_end(&mut self, _state: & TurtleState) {
+    self.end_tube();
+    self.current = self.parents.pop().unwrap_or(None);
+  }
+}
+
+/// How segments are turned into geometry
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BranchStyle {
+  /// A separate prism for every segment
+  Prisms,
+  /// A generalized cylinder for every branch, see `TubeSink`
+  Tubes,
+}
+
+/// Branch and foliage geometry for a word, kept separate by organ. With a pipe model, the
+/// branches' radii come from the word's branching structure instead of the segments' widths.
+pub fn ls_to_organ_meshes<M: ToDrawCommand>(word: & [M], tropism: & Tropism, seed: u64, style: BranchStyle, pipe: Option<& PipeModel>) -> OrganMeshes {
+  let radii = pipe.map(|model| pipe_model::branch_radii(word, model));
+  let meshes = match style {
+    BranchStyle::Prisms => {
+      let mut sink = CylinderSink::with_radii(seed, radii);
+      turtle::interpret(word, tropism, &mut sink);
+      sink.meshes
+    },
+    BranchStyle::Tubes => {
+      let mut sink = TubeSink::with_radii(seed, radii);
+      turtle::interpret(word, tropism, &mut sink);
+      sink.finish()
+    },
+  };
+  // The generators share vertices as they go, so there's nothing for `weld_vertices` to merge.
+  // The only vertices which meet are the caps' copies of the prisms' rings, which are kept apart
+  // on purpose, and welding them would smooth the caps into the sides again. The generate binary
+  // welds with --weld, which leaves them apart as their normals differ.
+  OrganMeshes {
+    trunk: vertex_index_mesh::recompute_normals(meshes.trunk),
+    branches: vertex_index_mesh::recompute_normals(meshes.branches),
+    foliage: vertex_index_mesh::recompute_normals(meshes.foliage),
+  }
+}
+
+pub fn ls_to_cylinders<M: ToDrawCommand>(word: & [M], tropism: & Tropism, seed: u64) -> VertexIndexMesh {
+  ls_to_organ_meshes(word, tropism, seed, BranchStyle::Prisms, None).combined()
+}
+
+pub fn ls_to_tubes<M: ToDrawCommand>(word: & [M], tropism: & Tropism, seed: u64) -> VertexIndexMesh {
+  ls_to_organ_meshes(word, tropism, seed, BranchStyle::Tubes, None).combined()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use lsystem::run_system_seeded;
+  use trees::RoundTree;
+
+  fn round_tree_word(seed: u64) -> Vec<::lsystem::Module> {
+    let tree = RoundTree {
+      base_width: 0.15,
+      trunk_base_length: 0.1,
+      branch_base_length: 1.0,
+      base_foliage_radius: 0.5,
+      base_foliage_length: 1.0,
+      tropism: Tropism::none(),
+    };
+    run_system_seeded(tree, 5, seed)
+  }
+
+  /// The exact bits of every vertex attribute, and the indices
+  fn mesh_bits(mesh: & VertexIndexMesh) -> (Vec<u32>, Vec<u32>) {
+    let mut bits = Vec::new();
+    for vertex in & mesh.vertices {
+      let (pos, normal, color, tex) = (vertex.pos(), vertex.normal(), vertex.color(), vertex.tex());
+      for & value in & [pos.x, pos.y, pos.z, normal.x, normal.y, normal.z, color.x, color.y, color.z, color.w, tex.x, tex.y] {
+        bits.push(value.to_bits());
+      }
+    }
+    (bits, mesh.indices.clone())
+  }
+
+  #[test]
+  fn meshes_are_reproducible() {
+    let word = round_tree_word(11);
+    for & style in & [BranchStyle::Prisms, BranchStyle::Tubes] {
+      let first = ls_to_organ_meshes(& word, & Tropism::none(), 11, style, None).combined();
+      let second = ls_to_organ_meshes(& word, & Tropism::none(), 11, style, None).combined();
+      assert!(!first.vertices.is_empty());
+      assert_eq!(mesh_bits(& first), mesh_bits(& second));
+    }
+  }
+
+  #[test]
+  fn cylinder_caps_have_their_own_vertices() {
+    let facets = 6;
+    let mesh = vertex_index_mesh::recompute_normals(cylinder(Pt::new(0.0, 0.0, 0.0), Pt::new(0.0, 2.0, 0.0), facets, 0.5, 0.4));
+    // Two centres, and two copies of each ring
+    assert_eq!(mesh.vertices.len(), 2 + 4 * facets as usize);
+    for tri in mesh.indices.chunks(3) {
+      let ys: Vec<f32> = tri.iter().map(|& idx| mesh.vertices[idx as usize].pos().y).collect();
+      let is_cap = ys.iter().all(|& y| y == ys[0]);
+      for & idx in tri {
+        let normal = mesh.vertices[idx as usize].normal();
+        if is_cap {
+          assert!(normal.y.abs() > 0.999);
+        } else {
+          assert!(normal.y.abs() < 0.1);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn tube_joints_are_mitred() {
+    let points = [Pt::new(0.0, 0.0, 0.0), Pt::new(0.0, 1.0, 0.0), Pt::new(1.0, 1.0, 0.0)];
+    let facets = 8;
+    let mesh = sweep_tube(& points, & [(0.2, 0.2), (0.2, 0.2)], Vec3::unit_x(), facets);
+    // The distance from a point to the line through a segment
+    let distance_to = |point: Pt, from: Pt, to: Pt| {
+      let along = (to - from).normalize();
+      let offset = point - from;
+      (offset - along * offset.dot(along)).magnitude()
+    };
+    // The ring at the joint is the second one
+    for vertex in & mesh.vertices[(facets as usize)..(2 * facets as usize)] {
+      assert!((distance_to(vertex.pos(), points[0], points[1]) - 0.2).abs() < 1.0e-5);
+      assert!((distance_to(vertex.pos(), points[1], points[2]) - 0.2).abs() < 1.0e-5);
+    }
+  }
+
+  #[test]
+  fn mesh_randomness_is_apart_from_the_derivation() {
+    for seed in 0..4 {
+      let mut mesh = mesh_rng(seed);
+      let mesh_values: Vec<u32> = (0..4).map(|_| mesh.next_u32()).collect();
+      for & (iteration, index) in & [(0, 0), (1, 0), (0, 1), (::std::u32::MAX, 0)] {
+        let mut derivation = derivation_rng(seed, iteration, index);
+        let derivation_values: Vec<u32> = (0..4).map(|_| derivation.next_u32()).collect();
+        assert!(mesh_values != derivation_values);
+      }
+    }
+  }
 }
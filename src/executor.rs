@@ -0,0 +1,135 @@
+//! Control over how derivations are spread across threads, see `lsystem::run_system_with`
+
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Sender, Receiver};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed set of worker threads which is kept between iterations and derivations. Idle workers
+/// take the next job from a shared queue, so a worker which finishes a small chunk early goes on
+/// to another one instead of waiting for the slowest.
+pub struct ThreadPool {
+  sender: Mutex<Option<Sender<Job>>>,
+  workers: Vec<JoinHandle<()>>,
+}
+
+impl ThreadPool {
+  pub fn new(threads: usize) -> ThreadPool {
+    let (sender, receiver) = mpsc::channel::<Job>();
+    let receiver: Arc<Mutex<Receiver<Job>>> = Arc::new(Mutex::new(receiver));
+    let workers = (0..threads.max(1)).map(|_| {
+      let receiver = receiver.clone();
+      thread::spawn(move || {
+        loop {
+          // The lock is only held while waiting for a job, not while running it
+          let job = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return,
+          };
+          match job {
+            Ok(job) => job(),
+            // The pool has been dropped
+            Err(_) => return,
+          }
+        }
+      })
+    }).collect();
+
+    ThreadPool {
+      sender: Mutex::new(Some(sender)),
+      workers: workers,
+    }
+  }
+
+  /// The number of worker threads
+  pub fn size(& self) -> usize { self.workers.len() }
+
+  /// Queue a job for the next idle worker
+  pub fn execute<F: FnOnce() + Send + 'static>(& self, job: F) {
+    if let Ok(sender) = self.sender.lock() {
+      if let Some(ref sender) = * sender {
+        sender.send(Box::new(job)).expect("thread pool workers have stopped");
+      }
+    }
+  }
+}
+
+impl Drop for ThreadPool {
+  fn drop(&mut self) {
+    // Closing the queue lets the workers finish their last jobs and stop
+    if let Ok(mut sender) = self.sender.lock() {
+      sender.take();
+    }
+    for worker in self.workers.drain(..) {
+      let _ = worker.join();
+    }
+  }
+}
+
+/// How each iteration of a derivation is split up between threads. The word is cut into chunks,
+/// and each chunk is rewritten on its own thread, either spawned for the iteration or taken from
+/// a persistent pool.
+#[derive(Clone)]
+pub struct Executor {
+  /// The most threads to use at once
+  pub threads: usize,
+  /// Chunks are never made smaller than this many modules, unless the whole word is smaller
+  pub min_chunk_size: usize,
+  /// Words shorter than this are rewritten on the calling thread
+  pub sequential_threshold: usize,
+  /// How many chunks to make for each thread. More chunks balance the load better when some
+  /// parts of the word take longer to rewrite than others.
+  pub chunks_per_thread: usize,
+  /// Workers to run the chunks on, instead of spawning new threads every iteration
+  pub pool: Option<Arc<ThreadPool>>,
+}
+
+impl Executor {
+  /// Up to 8 threads spawned for each iteration, for words of a few thousand modules or more
+  pub fn new() -> Executor {
+    Executor {
+      threads: 8,
+      min_chunk_size: 256,
+      sequential_threshold: 2048,
+      chunks_per_thread: 1,
+      pool: None,
+    }
+  }
+
+  /// Everything on the calling thread
+  pub fn sequential() -> Executor {
+    Executor { threads: 1, .. Executor::new() }
+  }
+
+  /// A persistent pool of `threads` workers, with a few chunks for each so they share the load
+  pub fn with_pool(threads: usize) -> Executor {
+    Executor {
+      threads: threads,
+      chunks_per_thread: 4,
+      pool: Some(Arc::new(ThreadPool::new(threads))),
+      .. Executor::new()
+    }
+  }
+
+  /// The number of chunks to split a word of `len` modules into, 1 meaning the calling thread
+  /// rewrites it all
+  pub fn chunk_count(& self, len: usize) -> usize {
+    if self.threads <= 1 || len < self.sequential_threshold { return 1; }
+    let most_chunks = (len + self.min_chunk_size.max(1) - 1) / self.min_chunk_size.max(1);
+    (self.threads * self.chunks_per_thread.max(1)).min(most_chunks).max(1)
+  }
+}
+
+/// What happened in one iteration of a derivation
+#[derive(Copy, Clone, Debug)]
+pub struct IterationTiming {
+  pub iteration: u32,
+  /// The length of the word before and after the iteration
+  pub input_len: usize,
+  pub output_len: usize,
+  /// How many chunks the word was split into, 1 if it was rewritten on the calling thread
+  pub chunks: usize,
+  pub duration: Duration,
+}
@@ -0,0 +1,591 @@
+//! A small expression language for parametric l-systems: rule conditions like `l > 0.5 && life < 3`
+//! and successor parameters like `w*0.707, l*R`.
+//!
+//! Expressions have the usual arithmetic operators (`+ - * / ^`), comparisons (`< <= > >= == !=`),
+//! logical operators (`&& || !`) and the functions `sin cos tan asin acos atan sqrt abs floor ceil
+//! exp ln min max pow`. Like the rest of the notation of ABOP, the trigonometric functions work in degrees.
+//! Comparisons and logical operators evaluate to 1.0 for true and 0.0 for false, so conditions are
+//! ordinary expressions, and any non-zero value counts as true.
+//!
+//! Names are either resolved while parsing (`parse_expr`, as the grammar parser does for formal parameters
+//! and constants), or left as variables and looked up in a `Scope` when evaluating (`Expr::parse`).
+//! Modules are scopes for their own named parameters, so a hand-written `LSystem` can use the same
+//! expressions, or whole `ParametricRule`s, against its modules and a table of constants:
+//!
+//! ```
+//! # extern crate lsystem;
+//! # use std::collections::HashMap;
+//! # use lsystem::expr::ParametricRule;
+//! # use lsystem::lsystem::branch;
+//! # fn main() {
+//! let grow = ParametricRule::new("l > 0.5 && life < 3", "w*0.707, l*R", |p| vec![branch(p[0], p[1], 0)]).unwrap();
+//! let mut constants = HashMap::new();
+//! constants.insert("R".to_string(), 0.8);
+//!
+//! let module = branch(0.1, 1.0, 2);
+//! assert_eq!(grow.apply(& (& module, & constants)).unwrap(), Some(vec![branch(0.1 * 0.707, 0.8, 0)]));
+//! // Too short to grow
+//! assert_eq!(grow.apply(& (& branch(0.1, 0.5, 2), & constants)).unwrap(), None);
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::f32;
+use std::fmt;
+
+use lsystem::Module;
+
+/// An arithmetic expression over the parameters of a parametric l-system
+#[derive(Clone, Debug)]
+pub enum Expr {
+  /// A constant value
+  Num(f32),
+  /// The value of a parameter, by its position in the parameters the expression is evaluated with
+  Param(usize),
+  /// A name which is looked up in a `Scope` when the expression is evaluated
+  Var(String),
+  /// Negation
+  Neg(Box<Expr>),
+  /// Logical not
+  Not(Box<Expr>),
+  /// A binary operation on two sub-expressions
+  Binary(BinOp, Box<Expr>, Box<Expr>),
+  /// A call to one of the built in functions
+  Call(Func, Vec<Expr>),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BinOp {
+  Add,
+  Sub,
+  Mul,
+  Div,
+  Pow,
+  Lt,
+  Le,
+  Gt,
+  Ge,
+  Eq,
+  Ne,
+  And,
+  Or,
+}
+
+impl BinOp {
+  fn apply(& self, a: f32, b: f32) -> f32 {
+    let truth = |cond: bool| if cond { 1.0 } else { 0.0 };
+    match * self {
+      BinOp::Add => a + b,
+      BinOp::Sub => a - b,
+      BinOp::Mul => a * b,
+      BinOp::Div => a / b,
+      BinOp::Pow => a.powf(b),
+      BinOp::Lt => truth(a < b),
+      BinOp::Le => truth(a <= b),
+      BinOp::Gt => truth(a > b),
+      BinOp::Ge => truth(a >= b),
+      BinOp::Eq => truth(a == b),
+      BinOp::Ne => truth(a != b),
+      BinOp::And => truth(a != 0.0 && b != 0.0),
+      BinOp::Or => truth(a != 0.0 || b != 0.0),
+    }
+  }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Func {
+  Sin,
+  Cos,
+  Tan,
+  Asin,
+  Acos,
+  Atan,
+  Sqrt,
+  Abs,
+  Floor,
+  Ceil,
+  Exp,
+  Ln,
+  Min,
+  Max,
+  Pow,
+}
+
+impl Func {
+  fn from_name(name: & str) -> Option<Func> {
+    match name {
+      "sin" => Some(Func::Sin),
+      "cos" => Some(Func::Cos),
+      "tan" => Some(Func::Tan),
+      "asin" => Some(Func::Asin),
+      "acos" => Some(Func::Acos),
+      "atan" => Some(Func::Atan),
+      "sqrt" => Some(Func::Sqrt),
+      "abs" => Some(Func::Abs),
+      "floor" => Some(Func::Floor),
+      "ceil" => Some(Func::Ceil),
+      "exp" => Some(Func::Exp),
+      "ln" => Some(Func::Ln),
+      "min" => Some(Func::Min),
+      "max" => Some(Func::Max),
+      "pow" => Some(Func::Pow),
+      _ => None,
+    }
+  }
+
+  fn arity(& self) -> usize {
+    match * self {
+      Func::Min | Func::Max | Func::Pow => 2,
+      _ => 1,
+    }
+  }
+
+  fn apply(& self, args: & [f32]) -> f32 {
+    let a = args[0];
+    match * self {
+      Func::Sin => a.to_radians().sin(),
+      Func::Cos => a.to_radians().cos(),
+      Func::Tan => a.to_radians().tan(),
+      Func::Asin => a.asin().to_degrees(),
+      Func::Acos => a.acos().to_degrees(),
+      Func::Atan => a.atan().to_degrees(),
+      Func::Sqrt => a.sqrt(),
+      Func::Abs => a.abs(),
+      Func::Floor => a.floor(),
+      Func::Ceil => a.ceil(),
+      Func::Exp => a.exp(),
+      Func::Ln => a.ln(),
+      Func::Min => a.min(args[1]),
+      Func::Max => a.max(args[1]),
+      Func::Pow => a.powf(args[1]),
+    }
+  }
+}
+
+/// Provides the values of the variables in an expression
+pub trait Scope {
+  fn lookup(& self, name: & str) -> Option<f32>;
+}
+
+/// A table of named constants
+impl Scope for HashMap<String, f32> {
+  fn lookup(& self, name: & str) -> Option<f32> { self.get(name).cloned() }
+}
+
+/// The named parameters of a module: `w`, `l`, `life`, `r`, `d`, `c`, `e`, `x`, `y` and `z` as
+/// appropriate, and `p0` to `p3` for symbols
+impl Scope for Module {
+  fn lookup(& self, name: & str) -> Option<f32> { self.param(name) }
+}
+
+/// Two scopes, where names in the first one hide the same names in the second one
+impl<'a, 'b, A: Scope, B: Scope> Scope for (&'a A, &'b B) {
+  fn lookup(& self, name: & str) -> Option<f32> {
+    self.0.lookup(name).or_else(|| self.1.lookup(name))
+  }
+}
+
+/// An error from evaluating an expression in a scope which doesn't define one of its names
+#[derive(Clone, Debug)]
+pub struct UnknownName(pub String);
+
+impl fmt::Display for UnknownName {
+  fn fmt(& self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "unknown name '{}'", self.0)
+  }
+}
+
+impl Expr {
+  /// Parse an expression, leaving all of its names as variables to be looked up when it's evaluated
+  pub fn parse(text: & str) -> Result<Expr, ExprError> {
+    parse_expr(text, |name| Some(Expr::Var(name.to_string())))
+  }
+
+  fn value<F>(& self, params: & [f32], lookup: & F) -> Result<f32, UnknownName> where F: Fn(& str) -> Option<f32> {
+    Ok(match * self {
+      Expr::Num(value) => value,
+      Expr::Param(idx) => params.get(idx).cloned().unwrap_or(0.0),
+      Expr::Var(ref name) => match lookup(name) {
+        Some(value) => value,
+        None => return Err(UnknownName(name.clone())),
+      },
+      Expr::Neg(ref inner) => -inner.value(params, lookup)?,
+      Expr::Not(ref inner) => if inner.value(params, lookup)? == 0.0 { 1.0 } else { 0.0 },
+      Expr::Binary(op, ref a, ref b) => op.apply(a.value(params, lookup)?, b.value(params, lookup)?),
+      Expr::Call(func, ref args) => {
+        let mut values = Vec::with_capacity(args.len());
+        for arg in args {
+          values.push(arg.value(params, lookup)?);
+        }
+        func.apply(& values)
+      },
+    })
+  }
+
+  /// Evaluate the expression, with `params` providing the values of `Expr::Param`s.
+  /// Any variables evaluate to NaN, so this is meant for expressions whose names were all resolved by `parse_expr`.
+  pub fn eval(& self, params: & [f32]) -> f32 {
+    self.value(params, & |_| None).unwrap_or(f32::NAN)
+  }
+
+  /// Evaluate the expression as a condition, anything other than zero is true
+  pub fn test(& self, params: & [f32]) -> bool {
+    self.eval(params) != 0.0
+  }
+
+  /// Evaluate the expression, looking up its variables in `scope`
+  pub fn eval_in<S: Scope>(& self, scope: & S) -> Result<f32, UnknownName> {
+    self.value(& [], & |name| scope.lookup(name))
+  }
+
+  /// Evaluate the expression as a condition, looking up its variables in `scope`
+  pub fn test_in<S: Scope>(& self, scope: & S) -> Result<bool, UnknownName> {
+    self.eval_in(scope).map(|value| value != 0.0)
+  }
+}
+
+/// A rule of a parametric l-system, as data: a condition on a module, and the parameters of the successor.
+/// The successor itself is made by `build` from the evaluated parameters, so the rule works with any module type.
+pub struct ParametricRule<M> {
+  condition: Option<Expr>,
+  params: Vec<Expr>,
+  build: fn(& [f32]) -> Vec<M>,
+}
+
+impl<M> ParametricRule<M> {
+  /// Both the condition and the comma separated parameters may be empty, an empty condition always holds
+  pub fn new(condition: & str, params: & str, build: fn(& [f32]) -> Vec<M>) -> Result<ParametricRule<M>, ExprError> {
+    let condition = if condition.trim().is_empty() { None } else { Some(Expr::parse(condition)?) };
+    Ok(ParametricRule {
+      condition: condition,
+      params: parse_expr_list(params, |name| Some(Expr::Var(name.to_string())))?,
+      build: build,
+    })
+  }
+
+  /// The successor, if the condition holds in `scope`, which is usually the module being rewritten,
+  /// possibly together with some constants
+  pub fn apply<S: Scope>(& self, scope: & S) -> Result<Option<Vec<M>>, UnknownName> {
+    if let Some(ref condition) = self.condition {
+      if !condition.test_in(scope)? { return Ok(None); }
+    }
+    let mut values = Vec::with_capacity(self.params.len());
+    for param in & self.params {
+      values.push(param.eval_in(scope)?);
+    }
+    Ok(Some((self.build)(& values)))
+  }
+}
+
+/// An error in the text of an expression, `column` counts characters from the start of the text, starting at 0
+#[derive(Clone, Debug)]
+pub struct ExprError {
+  pub column: usize,
+  pub message: String,
+}
+
+impl fmt::Display for ExprError {
+  fn fmt(& self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "column {}: {}", self.column + 1, self.message)
+  }
+}
+
+/// Parse an expression. Names are looked up with `resolve`, which turns them into the
+/// expression they stand for (usually a `Param` or a `Num` for a constant).
+pub fn parse_expr<F>(text: & str, resolve: F) -> Result<Expr, ExprError> where F: Fn(& str) -> Option<Expr> {
+  let mut parser = ExprParser {
+    chars: text.chars().collect(),
+    pos: 0,
+    resolve: resolve,
+  };
+  let expr = parser.logical_or()?;
+  parser.skip_whitespace();
+  if parser.pos < parser.chars.len() {
+    return Err(parser.error(format!("unexpected '{}'", parser.chars[parser.pos])));
+  }
+  Ok(expr)
+}
+
+/// Parse a comma separated list of expressions, which may be empty
+pub fn parse_expr_list<F>(text: & str, resolve: F) -> Result<Vec<Expr>, ExprError> where F: Fn(& str) -> Option<Expr> {
+  let mut parser = ExprParser {
+    chars: text.chars().collect(),
+    pos: 0,
+    resolve: resolve,
+  };
+  let mut exprs = Vec::new();
+  if parser.peek().is_none() { return Ok(exprs); }
+  loop {
+    exprs.push(parser.logical_or()?);
+    if !parser.accept(",") { break; }
+  }
+  parser.skip_whitespace();
+  if parser.pos < parser.chars.len() {
+    return Err(parser.error(format!("unexpected '{}'", parser.chars[parser.pos])));
+  }
+  Ok(exprs)
+}
+
+/// A recursive descent parser, one function per precedence level
+struct ExprParser<F> {
+  chars: Vec<char>,
+  pos: usize,
+  resolve: F,
+}
+
+impl<F> ExprParser<F> where F: Fn(& str) -> Option<Expr> {
+  fn error(& self, message: String) -> ExprError {
+    ExprError { column: self.pos, message: message }
+  }
+
+  fn skip_whitespace(&mut self) {
+    while self.pos < self.chars.len() && self.chars[self.pos].is_whitespace() {
+      self.pos += 1;
+    }
+  }
+
+  fn peek(&mut self) -> Option<char> {
+    self.skip_whitespace();
+    self.chars.get(self.pos).cloned()
+  }
+
+  /// Consume `token` if it comes next
+  fn accept(&mut self, token: & str) -> bool {
+    self.skip_whitespace();
+    let token_chars: Vec<char> = token.chars().collect();
+    if self.chars[self.pos..].starts_with(& token_chars) {
+      self.pos += token_chars.len();
+      true
+    } else {
+      false
+    }
+  }
+
+  fn logical_or(&mut self) -> Result<Expr, ExprError> {
+    let mut lhs = self.logical_and()?;
+    while self.accept("||") {
+      let rhs = self.logical_and()?;
+      lhs = Expr::Binary(BinOp::Or, Box::new(lhs), Box::new(rhs));
+    }
+    Ok(lhs)
+  }
+
+  fn logical_and(&mut self) -> Result<Expr, ExprError> {
+    let mut lhs = self.comparison()?;
+    while self.accept("&&") {
+      let rhs = self.comparison()?;
+      lhs = Expr::Binary(BinOp::And, Box::new(lhs), Box::new(rhs));
+    }
+    Ok(lhs)
+  }
+
+  fn comparison(&mut self) -> Result<Expr, ExprError> {
+    let lhs = self.additive()?;
+    // Longer operators have to be tried first
+    let ops = [("<=", BinOp::Le), (">=", BinOp::Ge), ("==", BinOp::Eq), ("!=", BinOp::Ne), ("<", BinOp::Lt), (">", BinOp::Gt), ("=", BinOp::Eq)];
+    for & (token, op) in ops.iter() {
+      if self.accept(token) {
+        let rhs = self.additive()?;
+        return Ok(Expr::Binary(op, Box::new(lhs), Box::new(rhs)));
+      }
+    }
+    Ok(lhs)
+  }
+
+  fn additive(&mut self) -> Result<Expr, ExprError> {
+    let mut lhs = self.multiplicative()?;
+    loop {
+      let op = if self.accept("+") { BinOp::Add } else if self.accept("-") { BinOp::Sub } else { return Ok(lhs) };
+      let rhs = self.multiplicative()?;
+      lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+    }
+  }
+
+  fn multiplicative(&mut self) -> Result<Expr, ExprError> {
+    let mut lhs = self.unary()?;
+    loop {
+      let op = if self.accept("*") { BinOp::Mul } else if self.accept("/") { BinOp::Div } else { return Ok(lhs) };
+      let rhs = self.unary()?;
+      lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+    }
+  }
+
+  fn unary(&mut self) -> Result<Expr, ExprError> {
+    if self.accept("-") {
+      Ok(Expr::Neg(Box::new(self.unary()?)))
+    } else if self.accept("!") {
+      Ok(Expr::Not(Box::new(self.unary()?)))
+    } else if self.accept("+") {
+      self.unary()
+    } else {
+      self.power()
+    }
+  }
+
+  fn power(&mut self) -> Result<Expr, ExprError> {
+    let base = self.atom()?;
+    if self.accept("^") {
+      // Right associative, and binds tighter than a leading minus on the base
+      let exponent = self.unary()?;
+      Ok(Expr::Binary(BinOp::Pow, Box::new(base), Box::new(exponent)))
+    } else {
+      Ok(base)
+    }
+  }
+
+  fn atom(&mut self) -> Result<Expr, ExprError> {
+    match self.peek() {
+      Some('(') => {
+        self.pos += 1;
+        let inner = self.logical_or()?;
+        if !self.accept(")") {
+          return Err(self.error("expected ')'".to_string()));
+        }
+        Ok(inner)
+      },
+      Some(c) if c.is_digit(10) || c == '.' => self.number(),
+      Some(c) if c.is_alphabetic() || c == '_' => {
+        let start = self.pos;
+        while self.pos < self.chars.len() && (self.chars[self.pos].is_alphanumeric() || self.chars[self.pos] == '_') {
+          self.pos += 1;
+        }
+        let name: String = self.chars[start..self.pos].iter().cloned().collect();
+        if self.peek() == Some('(') {
+          return self.call(start, & name);
+        }
+        match (self.resolve)(& name) {
+          Some(expr) => Ok(expr),
+          None => Err(ExprError { column: start, message: format!("unknown name '{}'", name) }),
+        }
+      },
+      Some(c) => Err(self.error(format!("unexpected '{}'", c))),
+      None => Err(self.error("unexpected end of expression".to_string())),
+    }
+  }
+
+  /// A function call, with the cursor on the opening parenthesis
+  fn call(&mut self, start: usize, name: & str) -> Result<Expr, ExprError> {
+    let func = match Func::from_name(name) {
+      Some(func) => func,
+      None => return Err(ExprError { column: start, message: format!("unknown function '{}'", name) }),
+    };
+    self.pos += 1;
+    let mut args = Vec::new();
+    if !self.accept(")") {
+      loop {
+        args.push(self.logical_or()?);
+        if self.accept(")") { break; }
+        if !self.accept(",") {
+          return Err(self.error("expected ',' or ')'".to_string()));
+        }
+      }
+    }
+    if args.len() != func.arity() {
+      return Err(ExprError { column: start, message: format!("'{}' takes {} arguments, found {}", name, func.arity(), args.len()) });
+    }
+    Ok(Expr::Call(func, args))
+  }
+
+  fn number(&mut self) -> Result<Expr, ExprError> {
+    let start = self.pos;
+    while self.pos < self.chars.len() && (self.chars[self.pos].is_digit(10) || self.chars[self.pos] == '.') {
+      self.pos += 1;
+    }
+    // Exponent, as in 1.5e-3
+    if self.pos < self.chars.len() && (self.chars[self.pos] == 'e' || self.chars[self.pos] == 'E') {
+      let mark = self.pos;
+      self.pos += 1;
+      if self.pos < self.chars.len() && (self.chars[self.pos] == '-' || self.chars[self.pos] == '+') {
+        self.pos += 1;
+      }
+      if self.pos < self.chars.len() && self.chars[self.pos].is_digit(10) {
+        while self.pos < self.chars.len() && self.chars[self.pos].is_digit(10) {
+          self.pos += 1;
+        }
+      } else {
+        // Not an exponent after all
+        self.pos = mark;
+      }
+    }
+    let text: String = self.chars[start..self.pos].iter().cloned().collect();
+    text.parse::<f32>()
+      .map(Expr::Num)
+      .map_err(|_| ExprError { column: start, message: format!("invalid number '{}'", text) })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+
+  use super::*;
+  use lsystem::branch;
+
+  fn eval(text: & str) -> f32 {
+    Expr::parse(text).unwrap().eval_in(& HashMap::new()).unwrap()
+  }
+
+  #[test]
+  fn arithmetic_precedence() {
+    assert_eq!(eval("1 + 2 * 3"), 7.0);
+    assert_eq!(eval("(1 + 2) * 3"), 9.0);
+    assert_eq!(eval("8 / 4 / 2"), 1.0);
+    assert_eq!(eval("10 - 4 - 3"), 3.0);
+    assert_eq!(eval("-2 ^ 2"), -4.0);
+    assert_eq!(eval("2 ^ 3 ^ 2"), 512.0);
+    assert_eq!(eval("2 * -3"), -6.0);
+    assert_eq!(eval("1.5e1 + .5"), 15.5);
+  }
+
+  #[test]
+  fn comparisons_and_logic() {
+    assert_eq!(eval("1 + 2 < 4"), 1.0);
+    assert_eq!(eval("2 * 2 >= 5"), 0.0);
+    assert_eq!(eval("3 == 3 && 2 != 3"), 1.0);
+    assert_eq!(eval("1 && 0"), 0.0);
+    assert_eq!(eval("0 || 2"), 1.0);
+    assert_eq!(eval("!0"), 1.0);
+    assert_eq!(eval("!3"), 0.0);
+    // && binds tighter than ||, and ! tighter than both
+    assert_eq!(eval("1 || 1 && 0"), 1.0);
+    assert_eq!(eval("(1 || 1) && 0"), 0.0);
+    assert_eq!(eval("!1 || 1 && 0"), 0.0);
+    assert_eq!(eval("!(1 < 2) || 2 < 3"), 1.0);
+  }
+
+  #[test]
+  fn trigonometry_in_degrees() {
+    let close = |text: & str, expected: f32| assert!((eval(text) - expected).abs() < 1e-5, "{} = {}", text, eval(text));
+    close("sin(90)", 1.0);
+    close("cos(180)", -1.0);
+    close("tan(45)", 1.0);
+    close("asin(1)", 90.0);
+    close("acos(0)", 90.0);
+    close("atan(1)", 45.0);
+    close("sin(30) * 2", 1.0);
+    close("max(1, min(4, 3)) + pow(2, 3)", 11.0);
+  }
+
+  #[test]
+  fn scopes() {
+    let mut constants = HashMap::new();
+    constants.insert("R".to_string(), 2.0);
+    constants.insert("l".to_string(), 100.0);
+    let module = branch(0.5, 3.0, 1);
+    // The module's own parameters hide the constants
+    assert_eq!(Expr::parse("l * R + life").unwrap().eval_in(& (& module, & constants)).unwrap(), 7.0);
+    assert_eq!(Expr::parse("w > 0.25 && life < 3").unwrap().test_in(& module).unwrap(), true);
+    assert_eq!(Expr::parse("d + 1").unwrap().eval_in(& module).unwrap_err().0, "d");
+  }
+
+  #[test]
+  fn errors() {
+    let column = |text: & str| Expr::parse(text).unwrap_err().column;
+    assert_eq!(column("1 +"), 3);
+    assert_eq!(column("(1 + 2"), 6);
+    assert_eq!(column("1 2"), 2);
+    assert_eq!(column("2 * sine(1)"), 4);
+    assert_eq!(column("min(1)"), 0);
+    assert!(parse_expr("x", |_| None).is_err());
+  }
+}
@@ -0,0 +1,725 @@
+//! A parser for l-systems written in the notation of The Algorithmic Beauty of Plants, e.g.
+//!
+//! ```text
+//! // Comments take up a whole line
+//! #define R 1.456
+//! #ignore + - /
+//! angle: 30
+//! tropism: 0, -1, 0, 0.2
+//! axiom: F(1)A(1)
+//! p1: A(l) : l > 2 -> [+(30)F(l)A(l/R)]
+//! A(l) -> F(l)/A(l*R)
+//! F(l, w) < A(x) > B -> B
+//! B -> (0.5) F[+B]B
+//! B -> (0.5) F[-B]B
+//! ```
+//!
+//! `axiom:` gives the starting word, `angle:` the angle in degrees used by turns without a parameter,
+//! `tropism:` the direction and susceptibility of the tropism applied when the word is drawn,
+//! `#define` names a constant, and `#ignore` lists modules which are skipped when matching contexts.
+//! Rules are `left < predecessor > right : condition -> successor`, where everything but the
+//! predecessor and successor is optional, and can be labelled with `pN:` as in the book. A
+//! probability in parentheses after the arrow makes a rule stochastic. A module with parameters in a
+//! predecessor or context only matches modules with that many parameters, while one without any
+//! matches whatever its parameters are.
+//!
+//! The turtle symbols become the corresponding `Module`s: `F(l, w)` a branch segment, `f(d)` a move
+//! without drawing, `+ -` roll, `& ^` pitch, `\ /` yaw (the rotation around the heading), `|` a half
+//! turn, `[ ]` push and pop, `!(w)` the segment width, `'(c)` the colour index and `_(e)` the tropism
+//! susceptibility. Angles are in degrees. Any other letter becomes a `Module::Symbol`, with up to
+//! `MAX_PARAMS` parameters. `$`, which rolls the turtle until its left is horizontal in ABOP, isn't
+//! supported.
+//! Conditions and parameters are written in the expression language of the `expr` module.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::f32;
+use std::fmt;
+
+use rand;
+
+use defs::*;
+use expr::{Expr, parse_expr, parse_expr_list};
+use lsystem::*;
+use turtle::Tropism;
+
+/// An error in the text of a grammar. Lines and columns start at 1.
+#[derive(Clone, Debug)]
+pub struct GrammarError {
+  pub line: usize,
+  pub column: usize,
+  pub message: String,
+}
+
+impl fmt::Display for GrammarError {
+  fn fmt(& self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "line {}, column {}: {}", self.line, self.column, self.message)
+  }
+}
+
+impl Error for GrammarError {
+  fn description(& self) -> & str { & self.message }
+}
+
+/// A module in the predecessor or context of a rule, with the number of formal parameters it binds,
+/// or none if it has no parameter list
+#[derive(Clone, Debug)]
+struct Pattern {
+  key: char,
+  arity: Option<usize>,
+}
+
+/// A module in a successor, whose parameters are computed from the bound formal parameters
+#[derive(Clone, Debug)]
+struct Template {
+  key: char,
+  params: Vec<Expr>,
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+  left: Option<Pattern>,
+  predecessor: Pattern,
+  right: Option<Pattern>,
+  condition: Option<Expr>,
+  weight: Option<f32>,
+  successor: Vec<Template>,
+}
+
+/// An l-system defined by a parsed grammar
+#[derive(Clone, Debug)]
+pub struct Grammar {
+  axiom: Vec<Module>,
+  rules: Vec<Rule>,
+  ignored: Vec<char>,
+  tropism: Tropism,
+  uses_left_context: bool,
+  uses_right_context: bool,
+}
+
+/// A module's name, parameter values and number of parameters
+type Signature = (char, [f32; MAX_PARAMS], usize);
+
+/// The name a module is matched by in rules, with its parameters. Only modules which can be rewritten have one.
+fn module_signature(module: & Module) -> Option<Signature> {
+  let mut values = [0.0; MAX_PARAMS];
+  match * module {
+    Module::Branch { w, l, .. } => { values[0] = l; values[1] = w; Some(('F', values, 2)) },
+    Module::Forward { d } => { values[0] = d; Some(('f', values, 1)) },
+    Module::Width { w } => { values[0] = w; Some(('!', values, 1)) },
+    Module::Color { c } => { values[0] = c; Some(('\'', values, 1)) },
+    Module::Susceptibility { e } => { values[0] = e; Some(('_', values, 1)) },
+    Module::Symbol { id, n, p } => Some((id, p, n as usize)),
+    _ => None,
+  }
+}
+
+/// The kind of a module as used by `#ignore`, where both directions of a turn count as the same kind
+fn module_kind(module: & Module) -> Option<char> {
+  match * module {
+    Module::Roll { .. } => Some('+'),
+    Module::Pitch { .. } => Some('&'),
+    Module::Yaw { .. } => Some('/'),
+    Module::Push => Some('['),
+    Module::Pop => Some(']'),
+    _ => module_signature(module).map(|(key, _, _)| key),
+  }
+}
+
+fn normalize_kind(key: char) -> char {
+  match key {
+    '-' | '|' => '+',
+    '^' => '&',
+    '\\' => '/',
+    _ => key,
+  }
+}
+
+fn is_turn(key: char) -> bool {
+  "+-&^\\/".contains(key)
+}
+
+fn is_module_char(key: char) -> bool {
+  key.is_alphabetic() || "+-&^\\/|[]!'_".contains(key)
+}
+
+/// The smallest and largest number of parameters each module accepts
+fn param_range(key: char) -> (usize, usize) {
+  match key {
+    'F' => (0, 2),
+    'f' => (0, 1),
+    '|' | '[' | ']' => (0, 0),
+    '!' | '\'' | '_' => (1, 1),
+    _ if is_turn(key) => (0, 1),
+    _ => (0, MAX_PARAMS),
+  }
+}
+
+/// The number of parameters a module always has, for modules other than symbols
+fn fixed_arity(key: char) -> Option<usize> {
+  match key {
+    'F' => Some(2),
+    'f' | '!' | '\'' | '_' => Some(1),
+    _ => None,
+  }
+}
+
+/// Build the module for a successor symbol from its evaluated parameters
+fn instantiate(key: char, values: & [f32]) -> Module {
+  let arg = |idx: usize, default: f32| values.get(idx).cloned().unwrap_or(default);
+  match key {
+    'F' => branch(arg(1, 1.0), arg(0, 1.0), 0),
+    'f' => forward(arg(0, 1.0)),
+    '+' => roll(arg(0, 0.0).to_radians()),
+    '-' => roll(-arg(0, 0.0).to_radians()),
+    '&' => pitch(arg(0, 0.0).to_radians()),
+    '^' => pitch(-arg(0, 0.0).to_radians()),
+    '\\' => yaw(arg(0, 0.0).to_radians()),
+    '/' => yaw(-arg(0, 0.0).to_radians()),
+    '|' => roll(f32::consts::PI),
+    '[' => push(),
+    ']' => pop(),
+    '!' => width(arg(0, 1.0)),
+    '\'' => color(arg(0, 0.0)),
+    '_' => susceptibility(arg(0, 0.0)),
+    id => symbol(id, values),
+  }
+}
+
+fn expand(successor: & [Template], params: & [f32]) -> Vec<Module> {
+  successor.iter().map(|template| {
+    let values: Vec<f32> = template.params.iter().map(|expr| expr.eval(params)).collect();
+    instantiate(template.key, & values)
+  }).collect()
+}
+
+/// Bind the formal parameters of a pattern to a module's parameters, if the module matches the pattern
+fn bind_pattern(pattern: & Pattern, found: & Option<Signature>, params: &mut Vec<f32>) -> bool {
+  match * found {
+    Some((key, ref values, count)) if key == pattern.key && pattern.arity.map_or(true, |arity| arity == count) => {
+      params.extend_from_slice(& values[..pattern.arity.unwrap_or(0)]);
+      true
+    },
+    _ => false,
+  }
+}
+
+/// Bind the parameters of a rule's predecessor and contexts to a module and its neighbours, if they all match
+fn bind_rule(rule: & Rule, module: Signature, left: & Option<Signature>, right: & Option<Signature>) -> Option<Vec<f32>> {
+  let mut params = Vec::new();
+  if let Some(ref pattern) = rule.left {
+    if !bind_pattern(pattern, left, &mut params) { return None; }
+  }
+  if !bind_pattern(& rule.predecessor, & Some(module), &mut params) { return None; }
+  if let Some(ref pattern) = rule.right {
+    if !bind_pattern(pattern, right, &mut params) { return None; }
+  }
+  Some(params)
+}
+
+impl LSystem for Grammar {
+  type Module = Module;
+
+  fn axiom(& self) -> Vec<Module> {
+    self.axiom.clone()
+  }
+
+  fn produce(& self, module: Module) -> Vec<Module> {
+    self.produce_in_context(module, & Context::new(self, & [module], 0, rand::random(), 0))
+  }
+
+  /// The first matching rule is applied. If it has a probability, it competes with all of the
+  /// other matching rules which have probabilities.
+  fn produce_in_context(& self, module: Module, context: & Context<Self>) -> Vec<Module> {
+    let signature = match module_signature(& module) {
+      Some(signature) => signature,
+      None => return vec![module],
+    };
+    let left = if self.uses_left_context { context.left().and_then(|m| module_signature(& m)) } else { None };
+    let right = if self.uses_right_context { context.right().and_then(|m| module_signature(& m)) } else { None };
+
+    let mut candidates: Vec<(f32, Vec<Module>)> = Vec::new();
+    for rule in & self.rules {
+      if rule.predecessor.key != signature.0 { continue; }
+      let params = match bind_rule(rule, signature, & left, & right) {
+        Some(params) => params,
+        None => continue,
+      };
+      if let Some(ref condition) = rule.condition {
+        if !condition.test(& params) { continue; }
+      }
+      match rule.weight {
+        None if candidates.is_empty() => return expand(& rule.successor, & params),
+        None => (),
+        Some(weight) => candidates.push((weight, expand(& rule.successor, & params))),
+      }
+    }
+
+    if candidates.is_empty() {
+      vec![module]
+    } else {
+      StochasticRule::new(candidates).choose(&mut context.rng())
+    }
+  }
+
+  fn tropism(& self) -> Tropism {
+    self.tropism
+  }
+
+  fn is_context_sensitive(& self) -> bool {
+    self.uses_left_context || self.uses_right_context
+  }
+
+  fn context_role(& self, module: & Module) -> ContextRole {
+    match module.context_role() {
+      ContextRole::Symbol if module_kind(module).map_or(false, |kind| self.ignored.contains(& kind)) => ContextRole::Ignored,
+      role => role,
+    }
+  }
+}
+
+/// Reads through one line of the grammar, keeping track of the column for errors
+struct LineCursor {
+  chars: Vec<char>,
+  pos: usize,
+  end: usize,
+  line: usize,
+}
+
+impl LineCursor {
+  fn new(text: & str, line: usize) -> LineCursor {
+    let chars: Vec<char> = text.chars().collect();
+    let end = chars.len();
+    LineCursor {
+      chars: chars,
+      pos: 0,
+      end: end,
+      line: line,
+    }
+  }
+
+  fn error_at(& self, pos: usize, message: String) -> GrammarError {
+    GrammarError { line: self.line, column: pos + 1, message: message }
+  }
+
+  fn error(& self, message: String) -> GrammarError {
+    self.error_at(self.pos, message)
+  }
+
+  fn skip_whitespace(&mut self) {
+    while self.pos < self.end && self.chars[self.pos].is_whitespace() {
+      self.pos += 1;
+    }
+  }
+
+  fn peek(&mut self) -> Option<char> {
+    self.skip_whitespace();
+    if self.pos < self.end { Some(self.chars[self.pos]) } else { None }
+  }
+
+  fn at_end(&mut self) -> bool {
+    self.peek().is_none()
+  }
+
+  fn identifier(&mut self) -> Option<String> {
+    self.skip_whitespace();
+    let start = self.pos;
+    while self.pos < self.end && (self.chars[self.pos].is_alphanumeric() || self.chars[self.pos] == '_') {
+      self.pos += 1;
+    }
+    if self.pos > start && !self.chars[start].is_digit(10) {
+      Some(self.chars[start..self.pos].iter().cloned().collect())
+    } else {
+      self.pos = start;
+      None
+    }
+  }
+
+  /// Find the first occurrence of `token` between the cursor and the end
+  fn find(& self, token: & str) -> Option<usize> {
+    let token_chars: Vec<char> = token.chars().collect();
+    (self.pos..self.end).find(|& idx| self.chars[idx..self.end].starts_with(& token_chars))
+  }
+
+  /// Having just passed an opening parenthesis, find its closing one
+  fn matching_paren(& self) -> Result<usize, GrammarError> {
+    let mut depth = 1;
+    for idx in self.pos..self.end {
+      match self.chars[idx] {
+        '(' => depth += 1,
+        ')' => {
+          depth -= 1;
+          if depth == 0 { return Ok(idx); }
+        },
+        _ => (),
+      }
+    }
+    Err(self.error_at(self.pos - 1, "unclosed '('".to_string()))
+  }
+
+  /// Parse the expression between the cursor and `end`
+  fn expr<F>(& self, end: usize, resolve: F) -> Result<Expr, GrammarError> where F: Fn(& str) -> Option<Expr> {
+    let text: String = self.chars[self.pos..end].iter().cloned().collect();
+    if text.trim().is_empty() {
+      return Err(self.error("expected an expression".to_string()));
+    }
+    parse_expr(& text, resolve).map_err(|err| self.error_at(self.pos + err.column, err.message))
+  }
+
+  /// Parse a parenthesized, comma separated list of expressions, the cursor being just past the '('
+  fn expr_list<F>(&mut self, resolve: & F) -> Result<Vec<Expr>, GrammarError> where F: Fn(& str) -> Option<Expr> {
+    let close = self.matching_paren()?;
+    let mut exprs = Vec::new();
+    let mut depth = 0;
+    let mut start = self.pos;
+    for idx in self.pos..(close + 1) {
+      match self.chars[idx] {
+        '(' => depth += 1,
+        ')' if depth > 0 => depth -= 1,
+        ',' if depth > 0 => (),
+        ',' | ')' => {
+          self.pos = start;
+          exprs.push(self.expr(idx, resolve)?);
+          start = idx + 1;
+        },
+        _ => (),
+      }
+    }
+    self.pos = close + 1;
+    Ok(exprs)
+  }
+
+  /// Parse a module of a predecessor or context, adding its formal parameters to `formals`
+  fn pattern(&mut self, formals: &mut Vec<String>) -> Result<Pattern, GrammarError> {
+    let key = match self.peek() {
+      Some(key) => key,
+      None => return Err(self.error("expected a module".to_string())),
+    };
+    if !(key.is_alphabetic() || key == '!' || key == '\'' || key == '_') {
+      return Err(self.error(format!("'{}' cannot be rewritten", key)));
+    }
+    let key_pos = self.pos;
+    self.pos += 1;
+    let mut arity = None;
+    if self.peek() == Some('(') {
+      self.pos += 1;
+      let mut count = 0;
+      loop {
+        self.skip_whitespace();
+        let name_pos = self.pos;
+        match self.identifier() {
+          Some(name) => {
+            if formals.contains(& name) {
+              return Err(self.error_at(name_pos, format!("parameter '{}' is already bound", name)));
+            }
+            formals.push(name);
+            count += 1;
+          },
+          None => return Err(self.error("expected a parameter name".to_string())),
+        }
+        match self.peek() {
+          Some(',') => self.pos += 1,
+          Some(')') => { self.pos += 1; break; },
+          _ => return Err(self.error("expected ',' or ')'".to_string())),
+        }
+      }
+      arity = Some(count);
+    }
+    match (fixed_arity(key), arity) {
+      (Some(fixed), Some(count)) if count != fixed => {
+        let expected = if fixed == 1 { "1 parameter".to_string() } else { format!("{} parameters", fixed) };
+        return Err(self.error_at(key_pos, format!("'{}' binds {} or none", key, expected)));
+      },
+      (None, Some(count)) if count > MAX_PARAMS => {
+        return Err(self.error_at(key_pos, format!("'{}' takes at most {} parameters", key, MAX_PARAMS)));
+      },
+      _ => (),
+    }
+    Ok(Pattern { key: key, arity: arity })
+  }
+
+  /// Parse a string of modules up to `end`
+  fn successor<F>(&mut self, end: usize, angle: Option<f32>, resolve: & F) -> Result<Vec<Template>, GrammarError> where F: Fn(& str) -> Option<Expr> {
+    let old_end = self.end;
+    self.end = end;
+    let mut templates = Vec::new();
+    while let Some(key) = self.peek() {
+      if !is_module_char(key) {
+        return Err(self.error(format!("unexpected '{}'", key)));
+      }
+      let key_pos = self.pos;
+      self.pos += 1;
+      let mut params = if self.peek() == Some('(') {
+        self.pos += 1;
+        self.expr_list(resolve)?
+      } else {
+        Vec::new()
+      };
+      if is_turn(key) && params.is_empty() {
+        match angle {
+          Some(angle) => params.push(Expr::Num(angle)),
+          None => return Err(self.error_at(key_pos, format!("'{}' needs an angle, either as a parameter or from an 'angle:' line", key))),
+        }
+      }
+      let (min, max) = param_range(key);
+      if params.len() < min || params.len() > max {
+        let expected = match (min, max) {
+          (1, 1) => "1 parameter".to_string(),
+          (min, max) if min == max => format!("{} parameters", min),
+          (min, max) => format!("{} to {} parameters", min, max),
+        };
+        return Err(self.error_at(key_pos, format!("'{}' takes {}, found {}", key, expected, params.len())));
+      }
+      templates.push(Template { key: key, params: params });
+    }
+    self.end = old_end;
+    Ok(templates)
+  }
+}
+
+/// Parse an l-system grammar, see the module documentation for the notation
+pub fn parse_grammar(source: & str) -> Result<Grammar, GrammarError> {
+  let lines: Vec<(usize, & str)> = source.lines()
+    .enumerate()
+    .map(|(idx, text)| (idx + 1, text))
+    .filter(|& (_, text)| !text.trim().is_empty() && !text.trim().starts_with("//"))
+    .collect();
+
+  let mut constants: HashMap<String, f32> = HashMap::new();
+  let mut angle: Option<f32> = None;
+  let mut tropism: Option<Tropism> = None;
+
+  // Constants and the angle come first, so that they can be used anywhere
+  for & (line, text) in & lines {
+    let mut cursor = LineCursor::new(text, line);
+    cursor.skip_whitespace();
+    let start = cursor.pos;
+    if text.trim().starts_with("#define") {
+      cursor.pos += "#define".len();
+      let name = match cursor.identifier() {
+        Some(name) => name,
+        None => return Err(cursor.error("expected a constant name".to_string())),
+      };
+      cursor.skip_whitespace();
+      let end = cursor.end;
+      let value = cursor.expr(end, |name| constants.get(name).map(|& value| Expr::Num(value)))?.eval(& []);
+      constants.insert(name, value);
+    } else if cursor.identifier().map_or(false, |word| word == "angle") && cursor.peek() == Some(':') {
+      if angle.is_some() {
+        return Err(cursor.error_at(start, "the angle is already defined".to_string()));
+      }
+      cursor.pos += 1;
+      cursor.skip_whitespace();
+      let end = cursor.end;
+      angle = Some(cursor.expr(end, |name| constants.get(name).map(|& value| Expr::Num(value)))?.eval(& []));
+    } else if cursor.identifier().map_or(false, |word| word == "tropism") && cursor.peek() == Some(':') {
+      if tropism.is_some() {
+        return Err(cursor.error_at(start, "the tropism is already defined".to_string()));
+      }
+      cursor.pos += 1;
+      cursor.skip_whitespace();
+      let text: String = cursor.chars[cursor.pos..cursor.end].iter().cloned().collect();
+      let values: Vec<f32> = parse_expr_list(& text, |name| constants.get(name).map(|& value| Expr::Num(value)))
+        .map_err(|err| cursor.error_at(cursor.pos + err.column, err.message))?
+        .iter()
+        .map(|expr| expr.eval(& []))
+        .collect();
+      if values.len() != 4 {
+        return Err(cursor.error_at(start, format!("the tropism takes a direction and a susceptibility, found {} values", values.len())));
+      }
+      tropism = Some(Tropism::new(Vec3::new(values[0], values[1], values[2]), values[3]));
+    }
+  }
+
+  let resolve_constant = |name: & str| constants.get(name).map(|& value| Expr::Num(value));
+
+  let mut axiom: Option<Vec<Module>> = None;
+  let mut rules = Vec::new();
+  let mut ignored = Vec::new();
+
+  for & (line, text) in & lines {
+    let mut cursor = LineCursor::new(text, line);
+    cursor.skip_whitespace();
+    let start = cursor.pos;
+
+    if text.trim().starts_with("#define") {
+      continue;
+    } else if text.trim().starts_with("#ignore") {
+      cursor.pos += "#ignore".len();
+      if cursor.peek() == Some(':') { cursor.pos += 1; }
+      while let Some(key) = cursor.peek() {
+        if !is_module_char(key) {
+          return Err(cursor.error(format!("'{}' is not a module", key)));
+        }
+        ignored.push(normalize_kind(key));
+        cursor.pos += 1;
+      }
+      continue;
+    } else if text.trim().starts_with('#') {
+      return Err(cursor.error("unknown directive".to_string()));
+    }
+
+    match cursor.identifier() {
+      Some(ref word) if (word == "angle" || word == "tropism") && cursor.peek() == Some(':') => continue,
+      Some(ref word) if word == "axiom" && cursor.peek() == Some(':') => {
+        if axiom.is_some() {
+          return Err(cursor.error_at(start, "the axiom is already defined".to_string()));
+        }
+        cursor.pos += 1;
+        let end = cursor.end;
+        let templates = cursor.successor(end, angle, & resolve_constant)?;
+        axiom = Some(expand(& templates, & []));
+        continue;
+      },
+      _ => cursor.pos = start,
+    }
+
+    // Everything else is a rule
+    let arrow = match cursor.find("->") {
+      Some(arrow) => arrow,
+      None => return Err(cursor.error_at(cursor.end, "expected '->'".to_string())),
+    };
+
+    // An optional label, like p1:
+    if let Some(label) = cursor.identifier() {
+      let numbered = label.len() > 1 && label.starts_with('p') && label[1..].chars().all(|c| c.is_digit(10));
+      if numbered && cursor.peek() == Some(':') {
+        cursor.pos += 1;
+      } else {
+        cursor.pos = start;
+      }
+    }
+
+    let colon = cursor.find(":").and_then(|colon| if colon < arrow { Some(colon) } else { None });
+    let pattern_end = colon.unwrap_or(arrow);
+
+    // Predecessor, with optional left and right contexts
+    cursor.end = pattern_end;
+    let mut formals = Vec::new();
+    let mut left = None;
+    let mut predecessor = cursor.pattern(&mut formals)?;
+    if cursor.peek() == Some('<') {
+      cursor.pos += 1;
+      left = Some(predecessor);
+      predecessor = cursor.pattern(&mut formals)?;
+    }
+    let mut right = None;
+    if cursor.peek() == Some('>') {
+      cursor.pos += 1;
+      right = Some(cursor.pattern(&mut formals)?);
+    }
+    if let Some(unexpected) = cursor.peek() {
+      return Err(cursor.error(format!("unexpected '{}'", unexpected)));
+    }
+    cursor.end = cursor.chars.len();
+
+    // Formal parameters shadow constants
+    let resolve = |name: & str| {
+      formals.iter().position(|formal| formal == name).map(Expr::Param).or_else(|| resolve_constant(name))
+    };
+
+    let condition = match colon {
+      Some(colon) => {
+        cursor.pos = colon + 1;
+        Some(cursor.expr(arrow, & resolve)?)
+      },
+      None => None,
+    };
+
+    cursor.pos = arrow + 2;
+    let mut weight = None;
+    if cursor.peek() == Some('(') {
+      cursor.pos += 1;
+      let weight_pos = cursor.pos;
+      let mut exprs = cursor.expr_list(& resolve_constant)?;
+      if exprs.len() != 1 {
+        return Err(cursor.error_at(weight_pos, "expected a single probability".to_string()));
+      }
+      let value = exprs.remove(0).eval(& []);
+      if !(value >= 0.0) {
+        return Err(cursor.error_at(weight_pos, "probabilities can't be negative".to_string()));
+      }
+      weight = Some(value);
+    }
+
+    let end = cursor.end;
+    let successor = cursor.successor(end, angle, & resolve)?;
+
+    rules.push(Rule {
+      left: left,
+      predecessor: predecessor,
+      right: right,
+      condition: condition,
+      weight: weight,
+      successor: successor,
+    });
+  }
+
+  let axiom = match axiom {
+    Some(axiom) => axiom,
+    None => return Err(GrammarError { line: lines.last().map_or(1, |& (line, _)| line), column: 1, message: "missing 'axiom:' line".to_string() }),
+  };
+
+  let uses_left_context = rules.iter().any(|rule| rule.left.is_some());
+  let uses_right_context = rules.iter().any(|rule| rule.right.is_some());
+
+  Ok(Grammar {
+    axiom: axiom,
+    rules: rules,
+    ignored: ignored,
+    tropism: tropism.unwrap_or(Tropism::none()),
+    uses_left_context: uses_left_context,
+    uses_right_context: uses_right_context,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use lsystem::{iterate_system, susceptibility, symbol};
+
+  fn error_at(source: & str) -> (usize, usize, String) {
+    let err = parse_grammar(source).err().expect("the grammar should be rejected");
+    (err.line, err.column, err.message)
+  }
+
+  #[test]
+  fn errors_point_at_the_line_and_column() {
+    assert_eq!(error_at("axiom: F(1"), (1, 9, "unclosed '('".to_string()));
+    assert_eq!(error_at("axiom: A\n\n// comment\nA ~> B"), (4, 7, "expected '->'".to_string()));
+    assert_eq!(error_at("axiom: A\nA -> B(x)"), (2, 8, "unknown name 'x'".to_string()));
+    assert_eq!(error_at("axiom: A\nA(x) : x > -> B"), (2, 12, "unexpected end of expression".to_string()));
+    assert_eq!(error_at("axiom: A\n  A(x, x) -> B"), (2, 8, "parameter 'x' is already bound".to_string()));
+    assert_eq!(error_at("#include trees\naxiom: A"), (1, 1, "unknown directive".to_string()));
+    assert_eq!(error_at("axiom: A\naxiom: B"), (2, 1, "the axiom is already defined".to_string()));
+    assert_eq!(error_at("axiom: A\n+ -> B"), (2, 1, "'+' cannot be rewritten".to_string()));
+    assert_eq!(error_at("axiom: !(1, 2)"), (1, 8, "'!' takes 1 parameter, found 2".to_string()));
+    assert_eq!(error_at("axiom: +"), (1, 8, "'+' needs an angle, either as a parameter or from an 'angle:' line".to_string()));
+    assert_eq!(error_at("A -> B\n\n"), (1, 1, "missing 'axiom:' line".to_string()));
+  }
+
+  #[test]
+  fn patterns_bind_every_parameter_or_none() {
+    assert_eq!(error_at("axiom: F\nF(l) -> F"), (2, 1, "'F' binds 2 parameters or none".to_string()));
+    assert_eq!(error_at("axiom: f\nf(a, b) -> f"), (2, 1, "'f' binds 1 parameter or none".to_string()));
+    assert_eq!(error_at("axiom: A\nA(a, b, c, d, e) -> A"), (2, 1, "'A' takes at most 4 parameters".to_string()));
+
+    let grammar = parse_grammar("axiom: A(1) A(1, 2) B(1)\nA(x) -> C(x)\nB -> C").unwrap();
+    let word = iterate_system(& grammar, & grammar.axiom(), 0, 0);
+    assert_eq!(word, vec![symbol('C', & [1.0]), symbol('A', & [1.0, 2.0]), symbol('C', & [])]);
+  }
+
+  #[test]
+  fn contexts_make_grammars_context_sensitive() {
+    assert!(!parse_grammar("axiom: A B\nA -> B").unwrap().is_context_sensitive());
+    assert!(parse_grammar("axiom: A B\nA < B -> A").unwrap().is_context_sensitive());
+    assert!(parse_grammar("axiom: A B\nA > B -> B").unwrap().is_context_sensitive());
+  }
+
+  #[test]
+  fn susceptibility_leaves_dollar_free() {
+    let grammar = parse_grammar("axiom: _(0.25) F").unwrap();
+    assert_eq!(grammar.axiom()[0], susceptibility(0.25));
+    assert_eq!(error_at("axiom: $ F"), (1, 8, "unexpected '$'".to_string()));
+  }
+}
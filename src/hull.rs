@@ -0,0 +1,138 @@
+//! Convex hulls for foliage. The hull is built here rather than with the `convex_hull` crate, as
+//! its `half_edge_mesh` keeps faces in a HashMap, whose order changes from run to run, and the
+//! meshes of a seed have to come out the same every time.
+
+use cgmath::*;
+
+use defs::*;
+
+fn farthest_by<F: Fn(& Pt) -> f32>(points: & [Pt], distance: F) -> (usize, f32) {
+  points.iter().enumerate().fold((0, -1.0), |(best, best_dist), (idx, pt)| {
+    let dist = distance(pt);
+    if dist > best_dist { (idx, dist) } else { (best, best_dist) }
+  })
+}
+
+fn face_normal(points: & [Pt], face: & Tri) -> Vec3 {
+  let (a, b, c) = (points[face[0] as usize], points[face[1] as usize], points[face[2] as usize]);
+  (b - a).cross(c - a)
+}
+
+/// Distance of `pt` above the plane of `face`, positive on the outside
+fn height_above(points: & [Pt], face: & Tri, pt: Pt) -> f32 {
+  let normal = face_normal(points, face);
+  let len = normal.magnitude();
+  if len == 0.0 { return 0.0; }
+  normal.dot(pt - points[face[0] as usize]) / len
+}
+
+/// The convex hull of a set of points, as counterclockwise triangles indexing into `points`.
+/// Points are added one at a time in the order given, so the same points always give the same
+/// triangles in the same order. Returns no triangles if the points are all on one plane.
+pub fn convex_hull(points: & [Pt]) -> Vec<Tri> {
+  if points.len() < 4 { return Vec::new(); }
+
+  // Anything closer to a plane than this is considered to be on it
+  let (_, extent) = farthest_by(points, |pt| pt.to_vec().magnitude());
+  let epsilon = extent.max(1.0) * 1.0e-5;
+
+  // Start with a large tetrahedron
+  let i0 = 0;
+  let (i1, _) = farthest_by(points, |pt| (pt - points[i0]).magnitude());
+  let axis = (points[i1] - points[i0]).normalize();
+  let (i2, dist2) = farthest_by(points, |pt| {
+    let offset = pt - points[i0];
+    (offset - axis * offset.dot(axis)).magnitude()
+  });
+  if dist2 < epsilon { return Vec::new(); }
+  let base: Tri = [i0 as Idx, i1 as Idx, i2 as Idx];
+  let (i3, dist3) = farthest_by(points, |pt| height_above(points, & base, * pt).abs());
+  if dist3 < epsilon { return Vec::new(); }
+
+  let (i0, i1, i2, i3) = (i0 as Idx, i1 as Idx, i2 as Idx, i3 as Idx);
+  let mut faces: Vec<Tri> = if height_above(points, & base, points[i3 as usize]) > 0.0 {
+    vec![[i0, i2, i1], [i0, i1, i3], [i1, i2, i3], [i2, i0, i3]]
+  } else {
+    vec![[i0, i1, i2], [i0, i3, i1], [i1, i3, i2], [i2, i3, i0]]
+  };
+
+  for (idx, & pt) in points.iter().enumerate() {
+    let idx = idx as Idx;
+    if idx == i0 || idx == i1 || idx == i2 || idx == i3 { continue; }
+
+    let (visible, hidden): (Vec<Tri>, Vec<Tri>) = faces.iter().partition(|face| height_above(points, face, pt) > epsilon);
+    if visible.is_empty() { continue; }
+
+    // The horizon is made of the edges of visible faces whose other side is hidden
+    let visible_edges: Vec<(Idx, Idx)> = visible.iter()
+      .flat_map(|face| vec![(face[0], face[1]), (face[1], face[2]), (face[2], face[0])])
+      .collect();
+    faces = hidden;
+    for & (a, b) in & visible_edges {
+      if !visible_edges.contains(& (b, a)) {
+        faces.push([a, b, idx]);
+      }
+    }
+  }
+
+  faces
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Points spread over and inside a lumpy ball, in no particular order
+  fn cloud() -> Vec<Pt> {
+    (0..80).map(|idx| {
+      let t = idx as f32;
+      let radius = if idx % 3 == 0 { 0.4 } else { 1.0 + 0.2 * (t * 1.3).sin() };
+      let (theta, phi) = (t * 2.399, (1.0 - 2.0 * (t + 0.5) / 80.0).acos());
+      Pt::new(radius * phi.sin() * theta.cos(), radius * phi.cos(), radius * phi.sin() * theta.sin())
+    }).collect()
+  }
+
+  fn check_hull(points: & [Pt], faces: & [Tri]) {
+    assert!(!faces.is_empty());
+    for face in faces {
+      for & pt in points {
+        assert!(height_above(points, face, pt) <= 1.0e-4, "{:?} is outside of {:?}", pt, face);
+      }
+    }
+    // A closed surface has every edge once in each direction
+    let edges: Vec<(Idx, Idx)> = faces.iter().flat_map(|face| vec![(face[0], face[1]), (face[1], face[2]), (face[2], face[0])]).collect();
+    for & (a, b) in & edges {
+      assert_eq!(edges.iter().filter(|& & edge| edge == (a, b)).count(), 1);
+      assert_eq!(edges.iter().filter(|& & edge| edge == (b, a)).count(), 1);
+    }
+  }
+
+  #[test]
+  fn hulls_enclose_their_points() {
+    let points = cloud();
+    check_hull(& points, & convex_hull(& points));
+  }
+
+  #[test]
+  fn cubes_have_twelve_triangles() {
+    let points: Vec<Pt> = (0..8).map(|idx| Pt::new((idx & 1) as f32, (idx >> 1 & 1) as f32, (idx >> 2) as f32)).collect();
+    let faces = convex_hull(& points);
+    check_hull(& points, & faces);
+    assert_eq!(faces.len(), 12);
+  }
+
+  #[test]
+  fn flat_or_few_points_have_no_hull() {
+    let flat: Vec<Pt> = (0..10).map(|idx| Pt::new(idx as f32, (idx * idx) as f32 * 0.1, 2.0 * idx as f32)).collect();
+    assert!(convex_hull(& flat).is_empty());
+    let few = vec![Pt::new(0.0, 0.0, 0.0), Pt::new(1.0, 0.0, 0.0), Pt::new(0.0, 1.0, 0.0)];
+    assert!(convex_hull(& few).is_empty());
+    assert!(convex_hull(& []).is_empty());
+  }
+
+  #[test]
+  fn hulls_are_reproducible() {
+    let points = cloud();
+    assert_eq!(convex_hull(& points), convex_hull(& points));
+  }
+}
@@ -0,0 +1,29 @@
+//! Parameterized, context-sensitive and stochastic l-systems, with turtle interpreters which turn
+//! their words into line and cylinder meshes. The viewer in `main.rs` is built on top of this library.
+
+extern crate glium;
+extern crate cgmath;
+extern crate rand;
+extern crate memmap;
+
+extern crate matrixstack;
+extern crate vertex_index_mesh;
+extern crate line_mesh;
+
+pub mod lsystem;
+pub mod defs;
+pub mod trees;
+pub mod rand_util;
+pub mod draw_helpers;
+pub mod turtle;
+pub mod expr;
+pub mod grammar;
+pub mod hull;
+pub mod pipe_model;
+pub mod timed;
+pub mod executor;
+pub mod budget;
+pub mod notation;
+pub mod word_cache;
+pub mod word_tree;
+pub mod metrics;
@@ -1,7 +1,22 @@
+use std::error::Error;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
 use std::thread;
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::time::Instant;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::usize;
+
+use rand::{self, Rng, SeedableRng, XorShiftRng};
+
+use turtle::Tropism;
+use executor::{Executor, IterationTiming};
+use budget::{Budget, BudgetedWord, StopReason};
 
 /// An enum for drawing commands using a turtle graphics-style approach
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum DrawCommand {
   Foliage { r: f32, l: f32 },
   /// Make a branch with width w and length l
@@ -16,6 +31,12 @@ pub enum DrawCommand {
   Yaw { r: f32 },
   /// Rotation represented as Euler angles x, y, z
   Euler { x: f32, y: f32, z: f32 },
+  /// Set the width of subsequent segments, which multiplies each segment's own width
+  Width { w: f32 },
+  /// Set the colour index of subsequent segments
+  Color { c: f32 },
+  /// Set how strongly subsequent segments bend towards the tropism direction
+  Susceptibility { e: f32 },
   /// Push current transformation onto the local pushdown stack
   Push,
   /// Pop the current transformation from the pushdown stack and return to the most recently pushed one
@@ -24,6 +45,27 @@ pub enum DrawCommand {
   None,
 }
 
+impl DrawCommand {
+  /// The value of one of the command's named parameters, see `Module::param`
+  pub fn param(& self, name: & str) -> Option<f32> {
+    match (* self, name) {
+      (DrawCommand::Foliage { r, .. }, "r") => Some(r),
+      (DrawCommand::Foliage { l, .. }, "l") => Some(l),
+      (DrawCommand::Segment { w, .. }, "w") => Some(w),
+      (DrawCommand::Segment { l, .. }, "l") => Some(l),
+      (DrawCommand::Forward { d }, "d") => Some(d),
+      (DrawCommand::Roll { r }, "r") | (DrawCommand::Pitch { r }, "r") | (DrawCommand::Yaw { r }, "r") => Some(r),
+      (DrawCommand::Euler { x, .. }, "x") => Some(x),
+      (DrawCommand::Euler { y, .. }, "y") => Some(y),
+      (DrawCommand::Euler { z, .. }, "z") => Some(z),
+      (DrawCommand::Width { w }, "w") => Some(w),
+      (DrawCommand::Color { c }, "c") => Some(c),
+      (DrawCommand::Susceptibility { e }, "e") => Some(e),
+      _ => None,
+    }
+  }
+}
+
 pub fn foliage_cmd(r: f32, l: f32) -> DrawCommand { DrawCommand::Foliage { r: r, l: l } }
 pub fn segment_cmd(w: f32, l: f32) -> DrawCommand { DrawCommand::Segment { w: w, l: l } }
 pub fn forward_cmd(d: f32) -> DrawCommand { DrawCommand::Forward { d: d } }
@@ -31,14 +73,20 @@ pub fn roll_cmd(r: f32) -> DrawCommand { DrawCommand::Roll { r: r } }
 pub fn pitch_cmd(r: f32) -> DrawCommand { DrawCommand::Pitch { r: r } }
 pub fn yaw_cmd(r: f32) -> DrawCommand { DrawCommand::Yaw { r: r } }
 pub fn euler_cmd(x: f32, y: f32, z: f32) -> DrawCommand { DrawCommand::Euler { x: x, y: y, z: z } }
+pub fn width_cmd(w: f32) -> DrawCommand { DrawCommand::Width { w: w } }
+pub fn color_cmd(c: f32) -> DrawCommand { DrawCommand::Color { c: c } }
+pub fn susceptibility_cmd(e: f32) -> DrawCommand { DrawCommand::Susceptibility { e: e } }
 pub fn push_cmd() -> DrawCommand { DrawCommand::Push }
 pub fn pop_cmd() -> DrawCommand { DrawCommand::Pop }
 pub fn none_cmd() -> DrawCommand { DrawCommand::None }
 
+/// The largest number of parameters a `Module::Symbol` can carry
+pub const MAX_PARAMS: usize = 4;
+
 /// This is a "module", one part of an l-system "word",
 /// where a word is a full description of the l-system at a certain
 /// level of iteration.
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Module {
   /// Rotate around the z axis by r radians
   Roll { r: f32 },
@@ -60,13 +108,57 @@ pub enum Module {
   Trunk { w: f32, l: f32, life: u8 },
   /// Creates a straight branch with width w and length l
   Branch { w: f32, l: f32, life: u8 },
+  /// Move forward by d without making a branch
+  Forward { d: f32 },
+  /// Set the width of subsequent segments
+  Width { w: f32 },
+  /// Set the colour index of subsequent segments
+  Color { c: f32 },
+  /// Override the system's tropism susceptibility for subsequent segments
+  Susceptibility { e: f32 },
+  /// A non-drawing symbol of a parsed grammar, with the first n of the parameters p in use
+  Symbol { id: char, n: u8, p: [f32; MAX_PARAMS] },
   /// Can be used for any custom element
   Custom(u8, DrawCommand),
 }
 
-impl Module {
+/// Modules which the turtle interpreters in `draw_helpers` can draw. Implement this for the module
+/// type of a custom alphabet to draw it with the same line and cylinder geometry as `Module`.
+pub trait ToDrawCommand {
   /// Transform an LSystem module into its corresponding turtle drawing command
-  pub fn to_draw_command(& self) -> DrawCommand {
+  fn to_draw_command(& self) -> DrawCommand;
+  /// Which part of the plant the module's geometry belongs to. By default foliage commands are
+  /// foliage, and everything else is a branch.
+  fn organ(& self) -> Organ {
+    match self.to_draw_command() {
+      DrawCommand::Foliage { .. } => Organ::Foliage,
+      _ => Organ::Branch,
+    }
+  }
+}
+
+/// The parts of a plant, which exporters keep in separate meshes
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Organ {
+  Trunk,
+  Branch,
+  Foliage,
+}
+
+/// A word of draw commands can be drawn directly
+impl ToDrawCommand for DrawCommand {
+  fn to_draw_command(& self) -> DrawCommand { * self }
+}
+
+/// So that iterators over words can be drawn without copying the modules
+impl<'a, M: ToDrawCommand> ToDrawCommand for &'a M {
+  fn to_draw_command(& self) -> DrawCommand { (* self).to_draw_command() }
+
+  fn organ(& self) -> Organ { (* self).organ() }
+}
+
+impl ToDrawCommand for Module {
+  fn to_draw_command(& self) -> DrawCommand {
     match * self {
       Module::Roll { r } => roll_cmd(r),
       Module::Pitch { r } => pitch_cmd(r),
@@ -78,9 +170,67 @@ impl Module {
       Module::BranchApex { r, l, .. } => foliage_cmd(r, l),
       Module::Trunk { w, l, .. } => segment_cmd(w, l),
       Module::Branch { w, l, .. } => segment_cmd(w, l),
+      Module::Forward { d } => forward_cmd(d),
+      Module::Width { w } => width_cmd(w),
+      Module::Color { c } => color_cmd(c),
+      Module::Susceptibility { e } => susceptibility_cmd(e),
+      Module::Symbol { .. } => none_cmd(),
       Module::Custom(_, cmd) => cmd,
     }
   }
+
+  fn organ(& self) -> Organ {
+    match * self {
+      Module::Trunk { .. } => Organ::Trunk,
+      Module::BranchApex { .. } | Module::Custom(_, DrawCommand::Foliage { .. }) => Organ::Foliage,
+      _ => Organ::Branch,
+    }
+  }
+}
+
+impl Module {
+  /// The value of one of the module's named parameters, as used by expressions.
+  /// Symbols name their parameters `p0` to `p3`, custom modules use the names from their draw command.
+  pub fn param(& self, name: & str) -> Option<f32> {
+    match (* self, name) {
+      (Module::Roll { r }, "r") | (Module::Pitch { r }, "r") | (Module::Yaw { r }, "r") => Some(r),
+      (Module::Euler { x, .. }, "x") => Some(x),
+      (Module::Euler { y, .. }, "y") => Some(y),
+      (Module::Euler { z, .. }, "z") => Some(z),
+      (Module::TrunkApex { life }, "life") => Some(life as f32),
+      (Module::BranchApex { r, .. }, "r") => Some(r),
+      (Module::BranchApex { l, .. }, "l") => Some(l),
+      (Module::BranchApex { life, .. }, "life") => Some(life as f32),
+      (Module::Trunk { w, .. }, "w") | (Module::Branch { w, .. }, "w") => Some(w),
+      (Module::Trunk { l, .. }, "l") | (Module::Branch { l, .. }, "l") => Some(l),
+      (Module::Trunk { life, .. }, "life") | (Module::Branch { life, .. }, "life") => Some(life as f32),
+      (Module::Forward { d }, "d") => Some(d),
+      (Module::Width { w }, "w") => Some(w),
+      (Module::Color { c }, "c") => Some(c),
+      (Module::Susceptibility { e }, "e") => Some(e),
+      (Module::Symbol { n, p, .. }, _) => {
+        match name {
+          "p0" => Some(0),
+          "p1" => Some(1),
+          "p2" => Some(2),
+          "p3" => Some(3),
+          _ => None,
+        }.and_then(|idx| if idx < n as usize { Some(p[idx]) } else { None })
+      },
+      (Module::Custom(_, cmd), _) => cmd.param(name),
+      _ => None,
+    }
+  }
+
+  /// The role this module plays in bracketed context matching: `Push` and `Pop` delimit branches,
+  /// everything else is an ordinary symbol
+  pub fn context_role(& self) -> ContextRole {
+    match * self {
+      Module::Push => ContextRole::BranchStart,
+      Module::Pop => ContextRole::BranchEnd,
+      _ => ContextRole::Symbol,
+    }
+  }
 }
 
 pub fn roll(r: f32) -> Module { Module::Roll { r: r } }
@@ -93,63 +243,638 @@ pub fn trunk_apex(life: u8) -> Module { Module::TrunkApex { life: life } }
 pub fn branch_apex(r: f32, l: f32, life: u8) -> Module { Module::BranchApex { r: r, l: l, life: life } }
 pub fn trunk(w: f32, l: f32, life: u8) -> Module { Module::Trunk { w: w, l: l, life: life } }
 pub fn branch(w: f32, l: f32, life: u8) -> Module { Module::Branch { w: w, l: l, life: life } }
+pub fn forward(d: f32) -> Module { Module::Forward { d: d } }
+pub fn width(w: f32) -> Module { Module::Width { w: w } }
+pub fn color(c: f32) -> Module { Module::Color { c: c } }
+pub fn susceptibility(e: f32) -> Module { Module::Susceptibility { e: e } }
+/// Make a symbol module, only the first `MAX_PARAMS` parameters are kept
+pub fn symbol(id: char, params: & [f32]) -> Module {
+  let n = params.len().min(MAX_PARAMS);
+  let mut p = [0.0; MAX_PARAMS];
+  p[..n].copy_from_slice(& params[..n]);
+  Module::Symbol { id: id, n: n as u8, p: p }
+}
 pub fn custom(num: u8, cmd: DrawCommand) -> Module { Module::Custom(num, cmd) }
 pub fn custom_none(num: u8) -> Module { Module::Custom(num, DrawCommand::None) }
 
+
+/// The part a module plays when searching for the context of its neighbours in a
+/// context-sensitive l-system. Context is found along the axis of the tree, as in ABOP section 1.8:
+/// lateral branches are skipped over, and the left context of the first module of a branch is
+/// the module which the branch is attached to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ContextRole {
+  /// An ordinary module, which can be part of the context of other modules
+  Symbol,
+  /// Opens a lateral branch (the `[` of ABOP notation)
+  BranchStart,
+  /// Closes a lateral branch (the `]` of ABOP notation)
+  BranchEnd,
+  /// Skipped entirely when searching for context, like the ABOP `#ignore` directive
+  Ignored,
+}
+
 /// A trait which can be implemented by arbitrary structs so that they can be used as an lsystem
-pub trait LSystem where Self: Send + Copy + 'static {
+pub trait LSystem where Self: Sized + Send + Sync + 'static {
   /// The type for the modules of the l-system. These modules are the constituent
   /// parts of the system, which is composed of strings of this type, plus rules for
   /// generation of new strings from the existing modules
-  type Module: Copy + Clone + Send;
+  type Module: Copy + Clone + Send + Sync;
   /// Provides the initial axiom, the "seed" of the lsystem
   fn axiom(& self) -> Vec<Self::Module>;
+  /// Stochastic version of `axiom`, which draws any random values from the derivation's random number generator.
+  /// The default implementation ignores the generator and calls `axiom`.
+  fn axiom_with_rng(& self, _rng: &mut DerivationRng) -> Vec<Self::Module> {
+    self.axiom()
+  }
   /// Implement custom versions of this function to produce new chains of modules from an existing module
   fn produce(& self, module: Self::Module) -> Vec<Self::Module>;
+  /// Context-sensitive version of `produce`, which can inspect the neighbours of the module
+  /// being rewritten. The default implementation ignores the context and calls `produce`,
+  /// so context-free systems only need to implement `produce`.
+  fn produce_in_context(& self, module: Self::Module, _context: & Context<Self>) -> Vec<Self::Module> {
+    self.produce(module)
+  }
+  /// Whether `produce_in_context` looks at the neighbours of the module being rewritten.
+  /// Derivations which can't provide them, like `stream_system`, refuse systems which do.
+  /// By default systems are context-free.
+  fn is_context_sensitive(& self) -> bool {
+    false
+  }
+  /// Fallible version of `produce_in_context`, for systems which check their modules' parameters.
+  /// An error stops the derivation of `try_run_system`, which reports it along with the module.
+  /// The default implementation always succeeds with `produce_in_context`.
+  fn try_produce(& self, module: Self::Module, context: & Context<Self>) -> Result<Vec<Self::Module>, String> {
+    Ok(self.produce_in_context(module, context))
+  }
+  /// The tropism the turtle should apply when drawing this system's words. By default there is none.
+  fn tropism(& self) -> Tropism {
+    Tropism::none()
+  }
+  /// Tells the context search how to treat a module. By default every module is an ordinary symbol,
+  /// so context-sensitive systems will usually want to delegate to something like `Module::context_role`
+  fn context_role(& self, _module: & Self::Module) -> ContextRole {
+    ContextRole::Symbol
+  }
+}
+
+/// The neighbourhood of a module which is being rewritten, in the word of the current iteration
+pub struct Context<'a, T: LSystem + 'a> {
+  lsystem: &'a T,
+  word: &'a [T::Module],
+  index: usize,
+  // The position of the start of `word` in the whole word of the current iteration
+  offset: usize,
+  seed: u64,
+  iteration: u32,
+}
+
+impl<'a, T: LSystem> Context<'a, T> {
+  pub fn new(lsystem: &'a T, word: &'a [T::Module], index: usize, seed: u64, iteration: u32) -> Context<'a, T> {
+    Context::with_offset(lsystem, word, index, 0, seed, iteration)
+  }
+
+  /// A context which can only see part of the current word, starting at position `offset`.
+  /// Modules outside of `word` aren't part of the context.
+  pub fn with_offset(lsystem: &'a T, word: &'a [T::Module], index: usize, offset: usize, seed: u64, iteration: u32) -> Context<'a, T> {
+    Context {
+      lsystem: lsystem,
+      word: word,
+      index: index,
+      offset: offset,
+      seed: seed,
+      iteration: iteration,
+    }
+  }
+
+  /// A random number generator for rewriting this module. It depends only on the derivation seed,
+  /// the iteration and the position of the module in the word, so stochastic productions give the
+  /// same result no matter which thread they run on.
+  pub fn rng(& self) -> DerivationRng { derivation_rng(self.seed, self.iteration, self.index()) }
+
+  /// The iteration of the derivation which is currently being produced, starting from 0
+  pub fn iteration(& self) -> u32 { self.iteration }
+
+  /// The module being rewritten
+  pub fn module(& self) -> T::Module { self.word[self.index] }
+
+  /// The position of the module being rewritten in the current word
+  pub fn index(& self) -> usize { self.offset + self.index }
+
+  /// The closest module to the left, along the axis of the tree
+  pub fn left(& self) -> Option<T::Module> { self.left_context(1).pop() }
+
+  /// The closest module to the right, along the axis of the tree
+  pub fn right(& self) -> Option<T::Module> { self.right_context(1).into_iter().next() }
+
+  /// Up to `count` modules to the left of the current module, in word order. Lateral branches
+  /// are skipped, and at the start of a branch the search continues onto the parent axis.
+  pub fn left_context(& self, count: usize) -> Vec<T::Module> {
+    let mut found = Vec::with_capacity(count);
+    let mut idx = self.index;
+    while found.len() < count && idx > 0 {
+      idx -= 1;
+      let module = self.word[idx];
+      match self.lsystem.context_role(& module) {
+        ContextRole::Symbol => found.push(module),
+        // Skip back over a complete lateral branch to its opening bracket
+        ContextRole::BranchEnd => {
+          let mut depth = 1;
+          while depth > 0 && idx > 0 {
+            idx -= 1;
+            match self.lsystem.context_role(& self.word[idx]) {
+              ContextRole::BranchEnd => depth += 1,
+              ContextRole::BranchStart => depth -= 1,
+              _ => (),
+            }
+          }
+        },
+        // The start of our own branch, the parent axis continues on the other side
+        ContextRole::BranchStart | ContextRole::Ignored => (),
+      }
+    }
+    found.reverse();
+    found
+  }
+
+  /// Up to `count` modules to the right of the current module, in word order. Lateral branches
+  /// are skipped, and the search stops at the end of the current branch.
+  pub fn right_context(& self, count: usize) -> Vec<T::Module> {
+    let mut found = Vec::with_capacity(count);
+    let mut idx = self.index + 1;
+    while found.len() < count && idx < self.word.len() {
+      let module = self.word[idx];
+      match self.lsystem.context_role(& module) {
+        ContextRole::Symbol => found.push(module),
+        // Skip forward over a complete lateral branch to its closing bracket
+        ContextRole::BranchStart => {
+          let mut depth = 1;
+          while depth > 0 && idx + 1 < self.word.len() {
+            idx += 1;
+            match self.lsystem.context_role(& self.word[idx]) {
+              ContextRole::BranchStart => depth += 1,
+              ContextRole::BranchEnd => depth -= 1,
+              _ => (),
+            }
+          }
+        },
+        // Nothing to the right of the end of a branch is part of its context
+        ContextRole::BranchEnd => break,
+        ContextRole::Ignored => (),
+      }
+      idx += 1;
+    }
+    found
+  }
+}
+
+/// The random number generator used for stochastic derivations. Derivations are only reproducible
+/// while it and rand's float sampling stay the same, which is why rand is held to 0.3.
+pub type DerivationRng = XorShiftRng;
+
+/// SplitMix64 finalizer, used to spread seeds out over all of the generator's state
+fn mix_seed(value: u64) -> u64 {
+  let mut z = value.wrapping_add(0x9E3779B97F4A7C15);
+  z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+  z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+  z ^ (z >> 31)
+}
+
+/// Make the generator for one step of a derivation: rewriting the module at `index` in the given `iteration`
+pub fn derivation_rng(seed: u64, iteration: u32, index: usize) -> DerivationRng {
+  let hi = mix_seed(mix_seed(seed) ^ (iteration as u64));
+  let lo = mix_seed(hi ^ (index as u64));
+  let hi = mix_seed(lo);
+  let mut state = [lo as u32, (lo >> 32) as u32, hi as u32, (hi >> 32) as u32];
+  // XorShift can't be seeded with all zeros
+  if state.iter().all(|& x| x == 0) { state[0] = 1; }
+  XorShiftRng::from_seed(state)
+}
+
+/// The generator used by `axiom_with_rng`, kept separate from the streams used by the iterations
+fn axiom_rng(seed: u64) -> DerivationRng {
+  derivation_rng(seed, ::std::u32::MAX, 0)
+}
+
+/// A set of alternative successors for a stochastic production, each with a relative weight.
+/// The weights don't need to add up to one.
+#[derive(Clone, Debug)]
+pub struct StochasticRule<M> {
+  successors: Vec<(f32, Vec<M>)>,
+  total_weight: f32,
+}
+
+impl<M: Clone> StochasticRule<M> {
+  pub fn new(successors: Vec<(f32, Vec<M>)>) -> StochasticRule<M> {
+    let total_weight = successors.iter().map(|& (weight, _)| weight.max(0.0)).fold(0.0, |a, b| a + b);
+    StochasticRule {
+      successors: successors,
+      total_weight: total_weight,
+    }
+  }
+
+  /// Pick one of the successors, with probability proportional to its weight
+  pub fn choose<R: Rng>(& self, rng: &mut R) -> Vec<M> {
+    let mut pick = rng.gen::<f32>() * self.total_weight;
+    for & (weight, ref successor) in & self.successors {
+      let weight = weight.max(0.0);
+      if pick < weight { return successor.clone(); }
+      pick -= weight;
+    }
+    // Rounding can leave a tiny remainder, in which case the last successor wins
+    self.successors.last().map(|& (_, ref successor)| successor.clone()).unwrap_or(Vec::new())
+  }
+}
+
+/// Rewrite the modules of `word` in the range `start..end`, with the entire word visible as context
+fn iterate_range<T: LSystem>(lsystem: & T, word: & [T::Module], start: usize, end: usize, seed: u64, iteration: u32) -> Vec<T::Module> {
+  (start..end).flat_map(|idx| lsystem.produce_in_context(word[idx], & Context::new(lsystem, word, idx, seed, iteration))).collect()
+}
+
+/// Why a module couldn't be rewritten
+#[derive(Clone, Debug)]
+pub enum DerivationFailure {
+  /// `try_produce` returned an error
+  Rejected(String),
+  /// The production panicked, with this message
+  Panicked(String),
+}
+
+impl fmt::Display for DerivationFailure {
+  fn fmt(& self, f: &mut fmt::Formatter) -> fmt::Result {
+    match * self {
+      DerivationFailure::Rejected(ref message) => write!(f, "{}", message),
+      DerivationFailure::Panicked(ref message) => write!(f, "panicked: {}", message),
+    }
+  }
 }
 
-/// Split up a vector into discrete chunks. This function could probably be optimized
-fn split_vec<T: Clone>(thevec: Vec<T>, numsplits: usize) -> Vec<Vec<T>> {
-  thevec.chunks(numsplits).map(|chunk| { chunk.to_vec() }).collect()
+/// A derivation which stopped because one of its modules couldn't be rewritten
+#[derive(Clone, Debug)]
+pub struct DerivationError<M> {
+  /// The iteration being produced, starting from 0
+  pub iteration: u32,
+  /// The chunk of the word the module was in, 0 when the iteration ran on the calling thread
+  pub chunk: usize,
+  /// The position of the module in the word of the iteration
+  pub index: usize,
+  pub module: M,
+  pub failure: DerivationFailure,
+}
+
+impl<M: fmt::Debug> fmt::Display for DerivationError<M> {
+  fn fmt(& self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "iteration {}, chunk {}, module {} ({:?}): {}", self.iteration, self.chunk, self.index, self.module, self.failure)
+  }
+}
+
+impl<M: fmt::Debug> Error for DerivationError<M> {
+  fn description(& self) -> & str { "a module couldn't be rewritten" }
+}
+
+/// Shared by the chunks of an iteration, to stop them all once the budget runs out
+struct ProductionLimit {
+  max_len: usize,
+  produced: AtomicUsize,
+  deadline: Option<Instant>,
+}
+
+impl ProductionLimit {
+  fn out_of_time(& self) -> bool {
+    self.deadline.map_or(false, |deadline| Instant::now() >= deadline)
+  }
+}
+
+/// Like `iterate_range`, but stops at the first module which fails or panics. Gives None if the
+/// word grows past the limit or time runs out before the chunk is finished.
+fn try_iterate_range<T: LSystem>(lsystem: & T, word: & [T::Module], start: usize, end: usize, seed: u64, iteration: u32, chunk: usize, limit: & ProductionLimit)
+  -> Result<Option<Vec<T::Module>>, DerivationError<T::Module>> {
+  let mut produced = Vec::with_capacity(end - start);
+  for idx in start..end {
+    // Looking at the clock for every module would slow things down
+    if (idx - start) % 1024 == 1023 && limit.out_of_time() { return Ok(None); }
+    let context = Context::new(lsystem, word, idx, seed, iteration);
+    let failure = match panic::catch_unwind(AssertUnwindSafe(|| lsystem.try_produce(word[idx], & context))) {
+      Ok(Ok(successors)) => {
+        let count = successors.len();
+        if limit.produced.fetch_add(count, Ordering::Relaxed) + count > limit.max_len { return Ok(None); }
+        produced.extend(successors);
+        continue;
+      },
+      Ok(Err(message)) => DerivationFailure::Rejected(message),
+      Err(payload) => {
+        let message = match payload.downcast_ref::<& str>() {
+          Some(message) => message.to_string(),
+          None => payload.downcast_ref::<String>().cloned().unwrap_or("unknown cause".to_string()),
+        };
+        DerivationFailure::Panicked(message)
+      },
+    };
+    return Err(DerivationError { iteration: iteration, chunk: chunk, index: idx, module: word[idx], failure: failure });
+  }
+  Ok(Some(produced))
 }
 
 /// Iterate over an lsystem word, producing a new vector of modules for each module in the word,
 /// then collect these modules together
-pub fn iterate_system<T: LSystem>(lsystem: T, word: Vec<T::Module>) -> Vec<T::Module> {
-  word.iter().flat_map(|letter| lsystem.produce(* letter)).collect()
-}
-
-/// Multi-threaded l-system processing - splits each iteration of the l-system into several chunks,
-/// spawns a thread to process each chunk, and then joins all the results.
-/// The upside of this is dramatically improved performance.
-/// The downside is that under the current implementation, context-sensitive l-systems are not possible.
-/// Theoretically, it should be possible to implement these, by including a certain
-/// number of "padding" modules on either end of a split chunk. Processing each module would then take into
-/// account the contents of this padding, without actually processing it. Modules in the middle of the chunk would be
-/// processed with context as usual. This approach is obviously more complex, and not needed for my purposes at the moment.
-pub fn run_system<T: LSystem + Send + Copy + 'static>(lsystem: T, iterations: u32) -> Vec<T::Module> {
+pub fn iterate_system<T: LSystem>(lsystem: & T, word: & [T::Module], seed: u64, iteration: u32) -> Vec<T::Module> {
+  iterate_range(lsystem, word, 0, word.len(), seed, iteration)
+}
+
+/// A depth-first derivation, which rewrites each module all the way down to the last iteration
+/// before moving on to the next one, and yields the final word one module at a time. Only the
+/// successors along the current path are kept, so memory grows with the number of iterations
+/// rather than the length of the word. Since the neighbours of a module are never available,
+/// context-sensitive systems can't be streamed, but stochastic ones give the same word as `run_system_seeded`.
+pub struct DerivationStream<'a, T: LSystem + 'a> {
+  lsystem: &'a T,
+  iterations: u32,
+  seed: u64,
+  // The modules left to rewrite in each generation, from the axiom down
+  pending: Vec<::std::vec::IntoIter<T::Module>>,
+  // The position of the next module of each generation in its whole word
+  positions: Vec<usize>,
+}
+
+impl<'a, T: LSystem> Iterator for DerivationStream<'a, T> {
+  type Item = T::Module;
+
+  fn next(&mut self) -> Option<T::Module> {
+    loop {
+      let generation = match self.pending.len() {
+        0 => return None,
+        len => len - 1,
+      };
+      let module = match self.pending[generation].next() {
+        Some(module) => module,
+        None => {
+          self.pending.pop();
+          continue;
+        },
+      };
+      let position = self.positions[generation];
+      self.positions[generation] += 1;
+      if generation == self.iterations as usize {
+        return Some(module);
+      }
+
+      let window = [module];
+      let context = Context::with_offset(self.lsystem, & window, 0, position, self.seed, generation as u32);
+      let successors = self.lsystem.produce_in_context(module, & context);
+      self.pending.push(successors.into_iter());
+    }
+  }
+}
+
+/// Derive the system lazily, see `DerivationStream`. Panics if the system is context-sensitive.
+pub fn stream_system<'a, T: LSystem>(lsystem: &'a T, iterations: u32, seed: u64) -> DerivationStream<'a, T> {
+  assert!(!lsystem.is_context_sensitive(), "context-sensitive systems can't be streamed, their productions need the whole word");
+  let axiom = lsystem.axiom_with_rng(&mut axiom_rng(seed));
+  DerivationStream {
+    lsystem: lsystem,
+    iterations: iterations,
+    seed: seed,
+    pending: vec![axiom.into_iter()],
+    positions: vec![0; iterations as usize + 1],
+  }
+}
+
+/// Run the system with a random seed, see `run_system_seeded`
+pub fn run_system<T: LSystem>(lsystem: T, iterations: u32) -> Vec<T::Module> {
+  run_system_seeded(lsystem, iterations, rand::random())
+}
+
+/// Run the system with the default executor, see `run_system_with`
+pub fn run_system_seeded<T: LSystem>(lsystem: T, iterations: u32, seed: u64) -> Vec<T::Module> {
+  run_system_with(lsystem, iterations, seed, & Executor::new()).0
+}
+
+/// Multi-threaded l-system processing - splits each iteration of the l-system into chunks, as the
+/// executor decides, rewrites each chunk on its own thread, and then joins all the results.
+/// The upside of this is dramatically improved performance on long words.
+/// Context-sensitive systems are supported by padding each chunk with its neighbours: every thread
+/// shares the whole word of the current iteration and only rewrites its own range of it, so context
+/// lookups can cross chunk boundaries (and skip over arbitrarily long branches) exactly as they
+/// would in `iterate_system`. Stochastic systems draw from generators keyed on the seed, the iteration
+/// and the module's position, so the same (system, seed, iterations) always gives the same word,
+/// however it was split up. Along with the word comes the timing of each iteration.
+/// Panics if a production fails, see `try_run_system` for a version which reports the failure.
+pub fn run_system_with<T: LSystem>(lsystem: T, iterations: u32, seed: u64, executor: & Executor) -> (Vec<T::Module>, Vec<IterationTiming>) {
+  match try_run_system(lsystem, iterations, seed, executor) {
+    Ok(result) => result,
+    Err(err) => panic!("derivation failed in iteration {}, chunk {}, module {}: {}", err.iteration, err.chunk, err.index, err.failure),
+  }
+}
+
+/// The derivation of `run_system_with`, which stops at the first module whose production fails
+/// with an error or a panic, and says which module it was. The threads survive the panic, so a
+/// persistent pool can still be used afterwards.
+pub fn try_run_system<T: LSystem>(lsystem: T, iterations: u32, seed: u64, executor: & Executor)
+  -> Result<(Vec<T::Module>, Vec<IterationTiming>), DerivationError<T::Module>> {
+  let (result, timings) = run_system_budgeted(lsystem, iterations, seed, executor, & Budget::unlimited())?;
+  Ok((result.word, timings))
+}
+
+/// `try_run_system` within a budget. When the next word would break one of the limits, or time
+/// runs out while producing it, the derivation stops cleanly with the last word which was
+/// finished and the reason it stopped. Words are checked as they are produced, so an iteration
+/// which would blow up is abandoned before it can use up the memory. An axiom which is already
+/// over `max_modules` or `max_memory` stops the derivation before it starts, with an empty word.
+pub fn run_system_budgeted<T: LSystem>(lsystem: T, iterations: u32, seed: u64, executor: & Executor, budget: & Budget)
+  -> Result<(BudgetedWord<T::Module>, Vec<IterationTiming>), DerivationError<T::Module>> {
+  let derivation_started = Instant::now();
+  let deadline = budget.max_time.map(|max_time| derivation_started + max_time);
+  let module_size = mem::size_of::<T::Module>().max(1);
+  let allowed_iterations = budget.max_iterations.map_or(iterations, |max_iterations| max_iterations.min(iterations));
+
   // Start with the l-system's axiom
-  let mut word = lsystem.axiom();
+  let mut word = lsystem.axiom_with_rng(&mut axiom_rng(seed));
+  // Systems aren't necessarily Copy (parsed grammars own their rules), so the threads share one
+  let lsystem = Arc::new(lsystem);
+  let mut timings = Vec::with_capacity(allowed_iterations as usize);
+  let stop = |word: Vec<T::Module>, iterations: u32, reason: StopReason, timings: Vec<IterationTiming>| {
+    Ok((BudgetedWord { word: word, iterations: iterations, reason: reason }, timings))
+  };
+
+  // No iteration can start from an axiom which is already over the limits, and no word fits
+  // within them, so the word is empty
+  if budget.max_modules.map_or(false, |max_modules| word.len() > max_modules) {
+    return stop(Vec::new(), 0, StopReason::MaxModules, timings);
+  }
+  if budget.max_memory.map_or(false, |max_memory| word.len() > max_memory / module_size) {
+    return stop(Vec::new(), 0, StopReason::MaxMemory, timings);
+  }
 
-  for _ in 0..iterations {
-    // Could make this configurable; this seemed like a sensible default
-    static TARGET_THREAD_NUM: u8 = 8;
-    // Calculate an appropriate split size on which to split up the word
-    let chunk_size = (word.len() as f32 / TARGET_THREAD_NUM as f32).ceil() as usize;
+  for iteration in 0..allowed_iterations {
+    if word.is_empty() { return stop(word, iteration, StopReason::Exhausted, timings); }
+    if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+      return stop(word, iteration, StopReason::MaxTime, timings);
+    }
+    let started = Instant::now();
+    let input_len = word.len();
+
+    // The memory limit covers this word and the next one
+    let memory_len = budget.max_memory.map(|max_memory| (max_memory / module_size).saturating_sub(input_len));
+    let (max_len, len_reason) = match (budget.max_modules, memory_len) {
+      (Some(modules), Some(memory)) if memory < modules => (memory, StopReason::MaxMemory),
+      (Some(modules), _) => (modules, StopReason::MaxModules),
+      (None, Some(memory)) => (memory, StopReason::MaxMemory),
+      (None, None) => (usize::MAX, StopReason::MaxModules),
+    };
+    let limit = Arc::new(ProductionLimit { max_len: max_len, produced: AtomicUsize::new(0), deadline: deadline });
+    let stopped_reason = |limit: & ProductionLimit| if limit.out_of_time() { StopReason::MaxTime } else { len_reason };
+
+    let mut num_chunks = executor.chunk_count(word.len());
+    if num_chunks <= 1 {
+      match try_iterate_range(& * lsystem, & word, 0, word.len(), seed, iteration, 0, & limit)? {
+        Some(next_word) => word = next_word,
+        None => return stop(word, iteration, stopped_reason(& limit), timings),
+      }
+    } else {
+      // Calculate an appropriate split size on which to split up the word
+      let chunk_size = (word.len() + num_chunks - 1) / num_chunks;
+      num_chunks = (word.len() + chunk_size - 1) / chunk_size;
+
+      // Each thread reads from the same word, so no copying is needed to provide the padding
+      let shared_word = Arc::new(word);
+      let (sender, receiver) = mpsc::channel();
+
+      for chunk_idx in 0..num_chunks {
+        let start = chunk_idx * chunk_size;
+        let end = (start + chunk_size).min(shared_word.len());
+        let chunk_word = shared_word.clone();
+        let chunk_lsystem = lsystem.clone();
+        let chunk_limit = limit.clone();
+        let sender = sender.clone();
+        // Grabs the lsystem local variable, and processes this chunk of the word on another thread
+        let job = move || {
+          let produced = try_iterate_range(& * chunk_lsystem, & chunk_word, start, end, seed, iteration, chunk_idx, & chunk_limit);
+          let _ = sender.send((chunk_idx, produced));
+        };
+        match executor.pool {
+          Some(ref pool) => pool.execute(job),
+          None => { thread::spawn(job); },
+        }
+      }
+      drop(sender);
 
-    // The type of this expression is Vec<thread::JoinHandle<Vec<T::Module>>>
-    let threads: Vec<_> = split_vec(word, chunk_size)
-      // Take ownership of the split vector's contents
-      .into_iter()
-      // Grabs the lsystem local variable, spawns a thread to process each chunk of the split up vector
-      .map(|chunk| { thread::spawn(move || { iterate_system(lsystem, chunk) }) })
-      .collect();
+      // The chunks finish in any order, put them back in word order
+      let mut chunks: Vec<Option<Result<Option<Vec<T::Module>>, DerivationError<T::Module>>>> = (0..num_chunks).map(|_| None).collect();
+      for (chunk_idx, produced) in receiver.iter() {
+        chunks[chunk_idx] = Some(produced);
+      }
+      let mut next_word = Vec::new();
+      let mut over_budget = false;
+      // The first failure in word order is reported, so errors don't depend on thread timing
+      for (chunk_idx, chunk) in chunks.into_iter().enumerate() {
+        match chunk {
+          Some(produced) => match produced? {
+            Some(produced) => next_word.extend(produced),
+            None => over_budget = true,
+          },
+          None => {
+            let index = chunk_idx * chunk_size;
+            let failure = DerivationFailure::Panicked("the thread stopped without a result".to_string());
+            return Err(DerivationError { iteration: iteration, chunk: chunk_idx, index: index, module: shared_word[index], failure: failure });
+          },
+        }
+      }
+      if over_budget {
+        // The threads may not have let go of the word quite yet
+        let word = Arc::try_unwrap(shared_word).unwrap_or_else(|shared| (* shared).clone());
+        return stop(word, iteration, stopped_reason(& limit), timings);
+      }
+      word = next_word;
+    }
 
-    // iterate over the JoinHandles and join each one, which waits for its thread
-    // to finish processing its vector of modules.
-    word = threads.into_iter().flat_map(|t| t.join().unwrap()).collect();
+    timings.push(IterationTiming {
+      iteration: iteration,
+      input_len: input_len,
+      output_len: word.len(),
+      chunks: num_chunks,
+      duration: started.elapsed(),
+    });
   }
 
-  // word.
-  word
+  let reason = if allowed_iterations < iterations { StopReason::MaxIterations } else { StopReason::Completed };
+  stop(word, allowed_iterations, reason, timings)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use trees::{AcropetalSignal, StochasticBush};
+
+  /// The stock executors, and pooled and spawning ones which split even the shortest words into
+  /// single modules, so that every chunk boundary gets crossed
+  fn executors() -> Vec<Executor> {
+    let split = |executor: Executor| Executor { min_chunk_size: 1, sequential_threshold: 0, .. executor };
+    vec![
+      Executor::sequential(),
+      Executor::new(),
+      Executor::with_pool(4),
+      split(Executor::new()),
+      split(Executor::with_pool(3)),
+    ]
+  }
+
+  fn assert_executors_agree<T: LSystem + Copy>(lsystem: T, iterations: u32, seed: u64) where T::Module: PartialEq + fmt::Debug {
+    let expected = run_system_with(lsystem, iterations, seed, & Executor::sequential()).0;
+    for executor in executors() {
+      assert_eq!(run_system_with(lsystem, iterations, seed, & executor).0, expected);
+    }
+  }
+
+  #[test]
+  fn executors_agree_on_context_sensitive_systems() {
+    assert_executors_agree(AcropetalSignal, 6, 7);
+  }
+
+  #[test]
+  fn acropetal_signal_moves_up_the_main_axis() {
+    let signals = |word: & [Module]| word.iter().filter(|& & module| match module { Module::Custom(2, _) => true, _ => false }).count();
+    let word = run_system_with(AcropetalSignal, 1, 0, & Executor::sequential()).0;
+    // Out of the base, into the first lateral branch and along the main axis
+    assert_eq!(signals(& word), 2);
+    assert_eq!(word[0], custom(1, segment_cmd(0.1, 1.0)));
+  }
+
+  #[test]
+  fn executors_agree_on_stochastic_systems() {
+    assert_executors_agree(StochasticBush, 5, 42);
+  }
+
+  #[test]
+  fn streams_match_derivations() {
+    let streamed: Vec<Module> = stream_system(& StochasticBush, 5, 42).collect();
+    assert_eq!(streamed, run_system_seeded(StochasticBush, 5, 42));
+  }
+
+  #[test]
+  #[should_panic(expected = "context-sensitive")]
+  fn context_sensitive_systems_are_not_streamed() {
+    stream_system(& AcropetalSignal, 3, 0);
+  }
+
+  #[test]
+  fn seeds_choose_the_successors() {
+    let derive = |seed| run_system_with(StochasticBush, 5, seed, & Executor::sequential()).0;
+    assert_eq!(derive(42), derive(42));
+    assert!((0..8).any(|seed| derive(seed) != derive(42)));
+  }
+
+  #[test]
+  fn axioms_over_the_budget_stop_before_deriving() {
+    let derive = |budget: Budget| run_system_budgeted(StochasticBush, 5, 42, & Executor::sequential(), & budget).unwrap().0;
+    let axiom = StochasticBush.axiom_with_rng(&mut axiom_rng(42));
+
+    let result = derive(Budget { max_modules: Some(axiom.len() - 1), .. Budget::unlimited() });
+    assert_eq!((result.word.len(), result.iterations, result.reason), (0, 0, StopReason::MaxModules));
+    let result = derive(Budget { max_memory: Some(axiom.len() * mem::size_of::<Module>() - 1), .. Budget::unlimited() });
+    assert_eq!((result.word.len(), result.iterations, result.reason), (0, 0, StopReason::MaxMemory));
+
+    // An axiom which just fits is kept, though the next word doesn't fit
+    let result = derive(Budget { max_modules: Some(axiom.len()), .. Budget::unlimited() });
+    assert_eq!((result.word, result.iterations, result.reason), (axiom, 0, StopReason::MaxModules));
+  }
 }
@@ -1,23 +1,17 @@
 #[macro_use]
 extern crate glium;
 extern crate cgmath;
-extern crate rand;
 
 extern crate arcball_cgmath;
-extern crate matrixstack;
 extern crate vertex_index_mesh;
 extern crate line_mesh;
-extern crate convex_hull;
-extern crate half_edge_mesh;
-
-mod lsystem;
-mod defs;
-mod trees;
-mod rand_util;
-mod draw_helpers;
+extern crate lsystem;
 
+use std::env;
 use std::fs::File;
 use std::io::Read;
+use std::process;
+use std::time::Duration;
 
 use glium::glutin;
 use glium::glutin::{Event, ElementState};
@@ -26,18 +20,29 @@ use glium::backend::Facade;
 
 use cgmath::*;
 
-use lsystem::{run_system};
-use trees::*;
-use defs::*;
-use draw_helpers::{ls_to_lines, ls_to_cylinders};
+use lsystem::lsystem::{run_system_budgeted};
+use lsystem::executor::Executor;
+use lsystem::budget::{Budget, StopReason};
+use lsystem::grammar::{Grammar, parse_grammar};
+use lsystem::lsystem::{LSystem, ToDrawCommand};
+use lsystem::trees::*;
+use lsystem::turtle::Tropism;
+use lsystem::defs::*;
+use lsystem::rand_util;
+use lsystem::timed::{self, TimedLSystem};
+use lsystem::word_tree::check_brackets;
+use lsystem::draw_helpers::{BranchStyle, ls_to_lines, ls_to_organ_meshes};
 use line_mesh::LineBuffer;
-use vertex_index_mesh::BufferSet;
+use vertex_index_mesh::{BufferSet, VertexIndexMesh};
 
 const WINDOW_WIDTH: u32 = 800;
 const WINDOW_HEIGHT: u32 = 800;
 const ASPECT_RATIO: f32 = (WINDOW_WIDTH as f32) / (WINDOW_HEIGHT as f32);
 const NEAR_PLANE_Z: f32 = 0.001;
 const FAR_PLANE_Z: f32 = 10000.0;
+const FIELD_OF_VIEW_DEGREES: f32 = 36.0;
+/// How much room to leave around a plant when the camera frames it
+const FRAME_MARGIN: f32 = 1.1;
 
 fn get_file_string(filename: & str) -> String {
   let mut file_obj = File::open(filename).unwrap();
@@ -46,19 +51,115 @@ fn get_file_string(filename: & str) -> String {
   storage
 }
 
-fn gen_new_tree<T: Facade>(gl: & T) -> (LineBuffer, BufferSet) {
-  let tree_system = RoundTree {
+/// The largest tree the viewer will derive, and how long it will wait for one
+const MAX_MODULES: usize = 1000000;
+const MAX_DERIVATION_SECONDS: u64 = 5;
+
+/// Derive within the viewer's budget, so that too many iterations can't hang it
+fn derive<T: LSystem>(lsystem: T, iterations: u32, seed: u64) -> Vec<T::Module> where T::Module: ToDrawCommand {
+  let budget = Budget::interactive(MAX_MODULES, Duration::from_secs(MAX_DERIVATION_SECONDS));
+  match run_system_budgeted(lsystem, iterations, seed, & Executor::new(), & budget) {
+    Ok((result, _)) => {
+      if result.reason != StopReason::Completed && result.reason != StopReason::Exhausted {
+        println!("Stopped after {} iterations: {:?}", result.iterations, result.reason);
+      }
+      if let Err(err) = check_brackets(& result.word) {
+        println!("Unbalanced brackets: {}", err);
+      }
+      result.word
+    },
+    Err(err) => {
+      println!("Derivation failed in iteration {}, module {}: {}", err.iteration, err.index, err.failure);
+      Vec::new()
+    },
+  }
+}
+
+/// The smallest box around both boxes
+fn union_bounds(a: Option<(Pt, Pt)>, b: Option<(Pt, Pt)>) -> Option<(Pt, Pt)> {
+  match (a, b) {
+    (Some((a_min, a_max)), Some((b_min, b_max))) => Some((
+      Pt::new(a_min.x.min(b_min.x), a_min.y.min(b_min.y), a_min.z.min(b_min.z)),
+      Pt::new(a_max.x.max(b_max.x), a_max.y.max(b_max.y), a_max.z.max(b_max.z)),
+    )),
+    (bounds, None) | (None, bounds) => bounds,
+  }
+}
+
+/// Aim the camera at the middle of `bounds`, from far enough away that all of it is in view.
+/// Zooming and panning are scaled to match, so they feel the same for small and large plants.
+fn frame_camera(camera: &mut arcball_cgmath::ArcballCamera<f32>, bounds: Option<(Pt, Pt)>) {
+  let (min, max) = match bounds {
+    Some(bounds) => bounds,
+    None => return,
+  };
+  let center = Vec3::new((min.x + max.x) / 2.0, (min.y + max.y) / 2.0, (min.z + max.z) / 2.0);
+  let radius = ((max - min).magnitude() / 2.0).max(NEAR_PLANE_Z * 10.0);
+  // The distance at which a sphere around the box just fits in the field of view
+  let distance = FRAME_MARGIN * radius / (FIELD_OF_VIEW_DEGREES / 2.0).to_radians().sin();
+  camera.set_target(center)
+    .set_distance(distance)
+    .set_zoom_speed(distance / 30.0)
+    .set_pan_speed(distance / 30.0);
+}
+
+/// Generates a RoundTree, or a system read from a grammar file if one was given.
+/// A new seed is picked each time, it drives both the derivation and the mesh generation.
+/// Returns the plant's lines and mesh, and the bounds of both.
+fn gen_new_tree<T: Facade>(gl: & T, grammar: Option<& Grammar>, iterations: u32, style: BranchStyle) -> (LineBuffer, BufferSet, Option<(Pt, Pt)>) {
+  let seed: u64 = rand_util::random();
+  let (tree_produced, tropism) = match grammar {
+    Some(grammar) => (derive(grammar.clone(), iterations, seed), grammar.tropism()),
+    None => {
+      let tree_system = RoundTree {
+        base_width: 0.15,
+        trunk_base_length: 0.1,
+        branch_base_length: 1.0,
+        base_foliage_radius: 0.5,
+        base_foliage_length: 1.0,
+        tropism: Tropism::none(),
+      };
+      (derive(tree_system, iterations, seed), tree_system.tropism())
+    },
+  };
+  let lines = ls_to_lines(& tree_produced, & tropism);
+  let mesh = ls_to_organ_meshes(& tree_produced, & tropism, seed, style, None).combined();
+  (lines.to_buffer(gl), mesh.to_buffer(gl), union_bounds(lines.bounds(), mesh.bounds()))
+}
+
+/// How long the growth animation runs for, and how much time passes each frame
+const GROWTH_END_TIME: f32 = 8.0;
+const GROWTH_STEP: f32 = 0.02;
+
+/// One frame of the growth animation, which shows a GrowingTree at `time`
+fn gen_growth_frame(time: f32, style: BranchStyle) -> VertexIndexMesh {
+  let tree_system = GrowingTree {
     base_width: 0.15,
-    trunk_base_length: 0.1,
-    branch_base_length: 1.0,
-    base_foliage_radius: 0.5,
-    base_foliage_length: 1.0,
+    segment_length: 1.0,
+    branch_angle: 45.0_f32.to_radians(),
+    branch_depth: 3,
+    foliage_radius: 0.5,
+    tropism: Tropism::none(),
   };
-  let tree_produced = run_system(tree_system, 5);
-  (ls_to_lines(& tree_produced).to_buffer(gl), ls_to_cylinders(& tree_produced).to_buffer(gl))
+  let word = timed::word_at(& tree_system, time);
+  // The same seed every frame, so the foliage doesn't flicker
+  ls_to_organ_meshes(& word, & tree_system.tropism(), 0, style, None).combined()
 }
 
 fn main() {
+  // Usage: lsystem [grammar file] [iterations]
+  let args: Vec<String> = env::args().collect();
+  let grammar = args.get(1).map(|filename| {
+    match parse_grammar(& get_file_string(filename)) {
+      Ok(grammar) => grammar,
+      Err(err) => {
+        println!("{}: {}", filename, err);
+        process::exit(1);
+      },
+    }
+  });
+  let iterations = args.get(2).and_then(|arg| arg.parse::<u32>().ok()).unwrap_or(5);
+
   // OpenGL setup
   let window = glutin::WindowBuilder::new()
     .with_depth_buffer(24)
@@ -66,7 +167,11 @@ fn main() {
     .with_title("L System".to_string())
     .build_glium().unwrap();
 
-  let (_, mut mesh_buffer) = gen_new_tree(& window);
+  // T switches between prisms and tubes for the branches
+  let mut style = BranchStyle::Prisms;
+  let (_, mut mesh_buffer, bounds) = gen_new_tree(& window, grammar.as_ref(), iterations, style);
+  // G plays the growth of a timed tree, this is its current time while it's playing
+  let mut growth_time: Option<f32> = None;
 
   // Shader Program
   // let basic_program = glium::Program::from_source(& window, & get_file_string("src/shader/base.vs"), & get_file_string("src/shader/base.fs"), None).unwrap();
@@ -74,10 +179,10 @@ fn main() {
 
   // Matrices
   let mut camera: arcball_cgmath::ArcballCamera<f32> = arcball_cgmath::ArcballCamera::new();
-  camera.set_distance(30.0)
-    .set_spin_speed(5.0);
+  camera.set_spin_speed(5.0);
+  frame_camera(&mut camera, bounds);
   let model_position = Mat4::from_translation(Vec3::new(0.0, 0.0, 0.0));
-  let perspective_projection: Mat4 = cgmath::perspective(cgmath::Deg(36.0), ASPECT_RATIO, NEAR_PLANE_Z, FAR_PLANE_Z);
+  let perspective_projection: Mat4 = cgmath::perspective(cgmath::Deg(FIELD_OF_VIEW_DEGREES), ASPECT_RATIO, NEAR_PLANE_Z, FAR_PLANE_Z);
 
   let draw_params = glium::draw_parameters::DrawParameters {
     backface_culling: glium::draw_parameters::BackfaceCullingMode::CullClockwise,
@@ -96,6 +201,11 @@ fn main() {
   let dpifactor = window.get_window().unwrap().hidpi_factor();
 
   loop {
+    if let Some(time) = growth_time {
+      mesh_buffer = gen_growth_frame(time, style).to_buffer(& window);
+      growth_time = if time < GROWTH_END_TIME { Some(time + GROWTH_STEP) } else { None };
+    }
+
     let mut target = window.draw();
 
     target.clear_color_and_depth((0.0, 0.0, 0.0, 1.0), 1.0);
@@ -121,7 +231,21 @@ fn main() {
         Event::Closed => return,
         Event::KeyboardInput(ElementState::Pressed, _, Some(glutin::VirtualKeyCode::Escape)) => return,
         Event::KeyboardInput(ElementState::Pressed, _, Some(glutin::VirtualKeyCode::Space)) => {
-          mesh_buffer = gen_new_tree(& window).1;
+          growth_time = None;
+          let (_, new_buffer, bounds) = gen_new_tree(& window, grammar.as_ref(), iterations, style);
+          mesh_buffer = new_buffer;
+          frame_camera(&mut camera, bounds);
+        },
+        Event::KeyboardInput(ElementState::Pressed, _, Some(glutin::VirtualKeyCode::G)) => {
+          growth_time = Some(0.0);
+          // Framed for the fully grown tree, so the camera holds still while it grows
+          frame_camera(&mut camera, gen_growth_frame(GROWTH_END_TIME, style).bounds());
+        },
+        Event::KeyboardInput(ElementState::Pressed, _, Some(glutin::VirtualKeyCode::T)) => {
+          style = if style == BranchStyle::Prisms { BranchStyle::Tubes } else { BranchStyle::Prisms };
+          let (_, new_buffer, bounds) = gen_new_tree(& window, grammar.as_ref(), iterations, style);
+          mesh_buffer = new_buffer;
+          frame_camera(&mut camera, bounds);
         },
         Event::MouseInput(ElementState::Pressed, glutin::MouseButton::Left) => {
           if pan_button_pressed {
@@ -0,0 +1,244 @@
+//! Measurements of the plant a word draws, for comparing variants of a system by numbers instead
+//! of by eye. The branching structure comes from `word_tree`, and the geometry from the turtle.
+
+use cgmath::*;
+
+use defs::*;
+use lsystem::{ToDrawCommand, DrawCommand, Organ};
+use turtle::{self, Tropism, TurtleSink, TurtleState};
+use word_tree::{self, BracketError, WordTree};
+
+/// The width of each bin of `PlantMetrics::branching_angles`
+pub const ANGLE_BIN_DEGREES: f32 = 10.0;
+const ANGLE_BINS: usize = 18;
+
+/// Measurements of a plant. Lengths are in the units of the word, and the turtle starts at the
+/// origin heading up the y axis, so heights are measured from y = 0.
+#[derive(Clone, Debug)]
+pub struct PlantMetrics {
+  pub modules: usize,
+  pub segments: usize,
+  /// The summed length of every segment
+  pub total_branch_length: f32,
+  /// The number of segments on the axes of each branch order, starting with the main axis
+  pub segments_per_order: Vec<usize>,
+  /// The Horton–Strahler order of the plant: tips are order 1, and the order goes up by one where
+  /// two branches of the same order meet
+  pub strahler_order: u32,
+  /// The number of segments of each Strahler order, starting with order 1
+  pub segments_per_strahler_order: Vec<usize>,
+  /// The highest point of the branches and foliage
+  pub max_height: f32,
+  /// The greater of the plant's widths along x and z
+  pub crown_width: f32,
+  pub bounds_min: Pt,
+  pub bounds_max: Pt,
+  pub foliage: usize,
+  /// How many branches leave their parent at each angle, in bins of `ANGLE_BIN_DEGREES` from 0 to
+  /// 180 degrees. The angle is between the first segment of the branch and the segment it grows from.
+  pub branching_angles: Vec<usize>,
+}
+
+/// Collects the geometry drawn for each segment, in word order
+struct MetricsSink {
+  segments: Vec<(Pt, Pt)>,
+  foliage: usize,
+  bounds: Option<(Pt, Pt)>,
+}
+
+impl MetricsSink {
+  fn include(&mut self, point: Pt, margin: f32) {
+    let offset = Vec3::new(margin, margin, margin);
+    let (low, high) = (point + -offset, point + offset);
+    self.bounds = Some(match self.bounds {
+      Some((min, max)) => (
+        Pt::new(min.x.min(low.x), min.y.min(low.y), min.z.min(low.z)),
+        Pt::new(max.x.max(high.x), max.y.max(high.y), max.z.max(high.z)),
+      ),
+      None => (low, high),
+    });
+  }
+}
+
+impl TurtleSink for MetricsSink {
+  fn segment(&mut self, start: Pt, end: Pt, _width: f32, _organ: Organ, _state: & TurtleState) {
+    self.segments.push((start, end));
+    self.include(start, 0.0);
+    self.include(end, 0.0);
+  }
+
+  fn foliage(&mut self, start: Pt, end: Pt, radius: f32, _state: & TurtleState) {
+    self.foliage += 1;
+    self.include(start, radius.abs());
+    self.include(end, radius.abs());
+  }
+}
+
+fn is_segment<M: ToDrawCommand>(module: & M) -> bool {
+  match module.to_draw_command() {
+    DrawCommand::Segment { .. } => true,
+    _ => false,
+  }
+}
+
+/// The nearest segment nodes which grow from a node, looking past apices and foliage
+fn segment_children<M: ToDrawCommand + Clone>(tree: & WordTree<M>, node: usize) -> Vec<usize> {
+  let mut found = Vec::new();
+  let mut pending = tree.children(node);
+  while let Some(child) = pending.pop() {
+    if is_segment(& tree.nodes[child].module) {
+      found.push(child);
+    } else {
+      pending.extend(tree.children(child));
+    }
+  }
+  found
+}
+
+/// Measure the plant drawn for a word. The brackets must be balanced, so that it's clear which
+/// branch grows from where.
+pub fn plant_metrics<M: ToDrawCommand + Clone>(word: & [M], tropism: & Tropism) -> Result<PlantMetrics, BracketError> {
+  let tree = word_tree::word_tree(word)?;
+  let mut sink = MetricsSink { segments: Vec::new(), foliage: 0, bounds: None };
+  turtle::interpret(word, tropism, &mut sink);
+
+  // The turtle draws the segments in word order, as are the nodes
+  let mut segment_of = vec![None; tree.nodes.len()];
+  let mut segment_nodes = Vec::new();
+  for (id, node) in tree.nodes.iter().enumerate() {
+    if is_segment(& node.module) {
+      segment_of[id] = Some(segment_nodes.len());
+      segment_nodes.push(id);
+    }
+  }
+
+  // Zipping the two would quietly drop segments if the turtle ever drew a different number
+  debug_assert_eq!(segment_nodes.len(), sink.segments.len());
+  let mut total_branch_length = 0.0;
+  let mut segments_per_order = Vec::new();
+  for (& id, & (start, end)) in segment_nodes.iter().zip(sink.segments.iter()) {
+    total_branch_length += start.distance(end);
+    let order = tree.nodes[id].order as usize;
+    if segments_per_order.len() <= order { segments_per_order.resize(order + 1, 0); }
+    segments_per_order[order] += 1;
+  }
+
+  // Children always come after their parents, so working backwards finds their orders first
+  let mut strahler = vec![0_u32; segment_nodes.len()];
+  let mut branching_angles = vec![0; ANGLE_BINS];
+  for (segment, & id) in segment_nodes.iter().enumerate().rev() {
+    let children = segment_children(& tree, id);
+    let highest = children.iter().filter_map(|& child| segment_of[child]).map(|child| strahler[child]).max().unwrap_or(0);
+    let at_highest = children.iter().filter_map(|& child| segment_of[child]).filter(|& child| strahler[child] == highest).count();
+    strahler[segment] = if highest == 0 { 1 } else if at_highest > 1 { highest + 1 } else { highest };
+
+    let (start, end) = sink.segments[segment];
+    let heading = end - start;
+    for & child in children.iter().filter(|& & child| tree.nodes[child].branch != tree.nodes[id].branch) {
+      let (child_start, child_end) = sink.segments[segment_of[child].unwrap()];
+      let child_heading = child_end - child_start;
+      if heading.magnitude2() == 0.0 || child_heading.magnitude2() == 0.0 { continue; }
+      let cosine = heading.normalize().dot(child_heading.normalize()).max(-1.0).min(1.0);
+      let bin = (cosine.acos().to_degrees() / ANGLE_BIN_DEGREES) as usize;
+      branching_angles[bin.min(ANGLE_BINS - 1)] += 1;
+    }
+  }
+  let strahler_order = strahler.iter().cloned().max().unwrap_or(0);
+  let mut segments_per_strahler_order = vec![0; strahler_order as usize];
+  for & order in strahler.iter() {
+    segments_per_strahler_order[order as usize - 1] += 1;
+  }
+
+  let (bounds_min, bounds_max) = sink.bounds.unwrap_or((Pt::origin(), Pt::origin()));
+  Ok(PlantMetrics {
+    modules: word.len(),
+    segments: segment_nodes.len(),
+    total_branch_length: total_branch_length,
+    segments_per_order: segments_per_order,
+    strahler_order: strahler_order,
+    segments_per_strahler_order: segments_per_strahler_order,
+    max_height: bounds_max.y.max(0.0),
+    crown_width: (bounds_max.x - bounds_min.x).max(bounds_max.z - bounds_min.z),
+    bounds_min: bounds_min,
+    bounds_max: bounds_max,
+    foliage: sink.foliage,
+    branching_angles: branching_angles,
+  })
+}
+
+/// JSON has no representation for infinities or NaN
+fn json_number(value: f32) -> String {
+  if value.is_finite() { format!("{}", value) } else { "null".to_string() }
+}
+
+fn join<T: ToString>(values: & [T], separator: & str) -> String {
+  values.iter().map(|value| value.to_string()).collect::<Vec<String>>().join(separator)
+}
+
+/// The column names of `PlantMetrics::to_csv`. Lists are written in one column, separated by semicolons.
+pub const CSV_HEADER: &'static str = "modules,segments,total_branch_length,segments_per_order,strahler_order,segments_per_strahler_order,max_height,crown_width,min_x,min_y,min_z,max_x,max_y,max_z,foliage,branching_angles";
+
+impl PlantMetrics {
+  /// A JSON object with a field for each measurement
+  pub fn to_json(& self) -> String {
+    format!(concat!("{{\"modules\":{},\"segments\":{},\"total_branch_length\":{},\"segments_per_order\":[{}],",
+      "\"strahler_order\":{},\"segments_per_strahler_order\":[{}],\"max_height\":{},\"crown_width\":{},",
+      "\"bounds\":{{\"min\":[{},{},{}],\"max\":[{},{},{}]}},\"foliage\":{},",
+      "\"branching_angles\":{{\"bin_degrees\":{},\"counts\":[{}]}}}}"),
+      self.modules, self.segments, json_number(self.total_branch_length), join(& self.segments_per_order, ","),
+      self.strahler_order, join(& self.segments_per_strahler_order, ","), json_number(self.max_height), json_number(self.crown_width),
+      json_number(self.bounds_min.x), json_number(self.bounds_min.y), json_number(self.bounds_min.z),
+      json_number(self.bounds_max.x), json_number(self.bounds_max.y), json_number(self.bounds_max.z), self.foliage,
+      ANGLE_BIN_DEGREES, join(& self.branching_angles, ","))
+  }
+
+  /// A row of values for the columns of `CSV_HEADER`
+  pub fn to_csv(& self) -> String {
+    format!("{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+      self.modules, self.segments, self.total_branch_length, join(& self.segments_per_order, ";"),
+      self.strahler_order, join(& self.segments_per_strahler_order, ";"), self.max_height, self.crown_width,
+      self.bounds_min.x, self.bounds_min.y, self.bounds_min.z, self.bounds_max.x, self.bounds_max.y, self.bounds_max.z, self.foliage,
+      join(& self.branching_angles, ";"))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use lsystem::*;
+
+  /// `F[+F@L]F` with unit segments, a branch at 45 degrees and a leaf at its tip which takes up no room
+  fn fork() -> Vec<Module> {
+    let f = || branch(0.1, 1.0, 0);
+    vec![f(), push(), roll(45.0_f32.to_radians()), f(), custom(1, foliage_cmd(0.0, 0.0)), pop(), f()]
+  }
+
+  #[test]
+  fn forks_are_measured() {
+    let metrics = plant_metrics(& fork(), & Tropism::none()).unwrap();
+    assert_eq!((metrics.modules, metrics.segments, metrics.foliage), (7, 3, 1));
+    assert!((metrics.total_branch_length - 3.0).abs() < 1.0e-5);
+    assert_eq!(metrics.segments_per_order, vec![2, 1]);
+    // Two tips of order 1 meet at the base
+    assert_eq!(metrics.strahler_order, 2);
+    assert_eq!(metrics.segments_per_strahler_order, vec![2, 1]);
+
+    let mut expected_angles = vec![0; ANGLE_BINS];
+    expected_angles[4] = 1;
+    assert_eq!(metrics.branching_angles, expected_angles);
+
+    assert!((metrics.max_height - 2.0).abs() < 1.0e-5);
+    assert!((metrics.crown_width - 45.0_f32.to_radians().sin()).abs() < 1.0e-5);
+  }
+
+  #[test]
+  fn unbalanced_words_are_refused() {
+    assert!(plant_metrics(& [branch(0.1, 1.0, 0), pop()], & Tropism::none()).is_err());
+  }
+
+  #[test]
+  fn csv_rows_match_the_header() {
+    let metrics = plant_metrics(& fork(), & Tropism::none()).unwrap();
+    assert_eq!(metrics.to_csv().split(',').count(), CSV_HEADER.split(',').count());
+  }
+}
@@ -0,0 +1,413 @@
+//! Words as compact text in the bracketed notation of The Algorithmic Beauty of Plants, e.g.
+//!
+//! ```text
+//! F(1,0.1)[+(0.52)F(0.5,0.1,3)@L(0.5,1,0)]\(2.4)A(1,2)
+//! ```
+//!
+//! Every module is written exactly, with angles in radians, so parsing a formatted word gives back
+//! the same modules. The symbols follow the grammar notation where it has them:
+//!
+//! | Text | Module |
+//! |------|--------|
+//! | `F(l,w)` or `F(l,w,life)` | `Branch`, the life is left out when it's 0 |
+//! | `f(d)` | `Forward` |
+//! | `+(r)` `&(r)` `\(r)` | `Roll`, `Pitch` and `Yaw`. `-(r)`, `^(r)` and `/(r)` turn the other way. |
+//! | `[` `]` | `Push` and `Pop` |
+//! | `!(w)` `'(c)` `_(e)` | `Width`, `Color` and `Susceptibility` |
+//! | `@T(l,w,life)` | `Trunk` |
+//! | `@A(life)` | `TrunkApex` |
+//! | `@L(r,l,life)` | `BranchApex` |
+//! | `@E(x,y,z)` | `Euler` |
+//! | `@3:F(l,w)` | `Custom(3, segment_cmd(w, l))`, followed by its draw command in the same notation, where `@L(r,l)` is foliage and `.` is none |
+//! | `A` or `A(p0,p1)` | `Symbol`, any other letter |
+//! | `` `F(1) `` | `Symbol` with an id which would otherwise mean something else |
+//!
+//! Whitespace between modules is ignored, so `format_word_indented` can lay branches out on lines of their own.
+
+use std::error::Error;
+use std::fmt::{self, Write};
+
+use lsystem::*;
+
+/// An error in the text of a word, `column` counts characters from the start of the text, starting at 0
+#[derive(Clone, Debug)]
+pub struct WordError {
+  pub column: usize,
+  pub message: String,
+}
+
+impl fmt::Display for WordError {
+  fn fmt(& self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "column {}: {}", self.column + 1, self.message)
+  }
+}
+
+impl Error for WordError {
+  fn description(& self) -> & str { & self.message }
+}
+
+/// Letters and marks which mean something other than a symbol
+fn is_reserved(key: char) -> bool {
+  "Ff+-&^/\\[]!'_@`.".contains(key)
+}
+
+fn write_params(out: &mut String, params: & [f32]) {
+  if params.is_empty() { return; }
+  out.push('(');
+  for (idx, value) in params.iter().enumerate() {
+    if idx > 0 { out.push(','); }
+    // Display gives the shortest text which parses back to the same value
+    write!(out, "{}", value).unwrap();
+  }
+  out.push(')');
+}
+
+/// Negative turns are written with the symbol for turning the other way
+fn write_turn(out: &mut String, r: f32, positive: char, negative: char) {
+  if r.is_sign_negative() {
+    out.push(negative);
+    write_params(out, & [-r]);
+  } else {
+    out.push(positive);
+    write_params(out, & [r]);
+  }
+}
+
+fn write_command(out: &mut String, command: & DrawCommand) {
+  match * command {
+    DrawCommand::Foliage { r, l } => { out.push_str("@L"); write_params(out, & [r, l]); },
+    DrawCommand::Segment { w, l } => { out.push('F'); write_params(out, & [l, w]); },
+    DrawCommand::Forward { d } => { out.push('f'); write_params(out, & [d]); },
+    DrawCommand::Roll { r } => write_turn(out, r, '+', '-'),
+    DrawCommand::Pitch { r } => write_turn(out, r, '&', '^'),
+    DrawCommand::Yaw { r } => write_turn(out, r, '\\', '/'),
+    DrawCommand::Euler { x, y, z } => { out.push_str("@E"); write_params(out, & [x, y, z]); },
+    DrawCommand::Width { w } => { out.push('!'); write_params(out, & [w]); },
+    DrawCommand::Color { c } => { out.push('\''); write_params(out, & [c]); },
+    DrawCommand::Susceptibility { e } => { out.push('_'); write_params(out, & [e]); },
+    DrawCommand::Push => out.push('['),
+    DrawCommand::Pop => out.push(']'),
+    DrawCommand::None => out.push('.'),
+  }
+}
+
+fn write_module(out: &mut String, module: & Module) {
+  match * module {
+    Module::Branch { w, l, life } => {
+      out.push('F');
+      if life == 0 { write_params(out, & [l, w]); } else { write_params(out, & [l, w, life as f32]); }
+    },
+    Module::Trunk { w, l, life } => { out.push_str("@T"); write_params(out, & [l, w, life as f32]); },
+    Module::TrunkApex { life } => { out.push_str("@A"); write_params(out, & [life as f32]); },
+    Module::BranchApex { r, l, life } => { out.push_str("@L"); write_params(out, & [r, l, life as f32]); },
+    Module::Symbol { id, n, p } => {
+      if is_reserved(id) || !id.is_alphabetic() { out.push('`'); }
+      out.push(id);
+      write_params(out, & p[..n as usize]);
+    },
+    Module::Custom(num, ref command) => {
+      write!(out, "@{}:", num).unwrap();
+      write_command(out, command);
+    },
+    // The rest are the same as their draw commands
+    _ => write_command(out, & module.to_draw_command()),
+  }
+}
+
+/// A word as compact text on a single line
+pub fn format_word(word: & [Module]) -> String {
+  let mut out = String::new();
+  for module in word {
+    write_module(&mut out, module);
+  }
+  out
+}
+
+/// A word as text with each branch on its own lines, indented by its depth, which is easier to
+/// read and to diff than `format_word`. It parses the same way.
+pub fn format_word_indented(word: & [Module], indent: & str) -> String {
+  let mut out = String::new();
+  let mut depth = 0;
+  let mut line_start = true;
+  for module in word {
+    match * module {
+      Module::Push => {
+        if !line_start { out.push('\n'); }
+        for _ in 0..depth { out.push_str(indent); }
+        out.push_str("[\n");
+        depth += 1;
+        line_start = true;
+      },
+      Module::Pop => {
+        if !line_start { out.push('\n'); }
+        depth = if depth > 0 { depth - 1 } else { 0 };
+        for _ in 0..depth { out.push_str(indent); }
+        out.push_str("]\n");
+        line_start = true;
+      },
+      _ => {
+        if line_start {
+          for _ in 0..depth { out.push_str(indent); }
+          line_start = false;
+        }
+        write_module(&mut out, module);
+      },
+    }
+  }
+  if !line_start { out.push('\n'); }
+  out
+}
+
+struct WordCursor {
+  chars: Vec<char>,
+  pos: usize,
+}
+
+impl WordCursor {
+  fn error_at(& self, pos: usize, message: String) -> WordError {
+    WordError { column: pos, message: message }
+  }
+
+  fn skip_whitespace(&mut self) {
+    while self.pos < self.chars.len() && self.chars[self.pos].is_whitespace() {
+      self.pos += 1;
+    }
+  }
+
+  fn next_char(&mut self) -> Option<char> {
+    let found = self.chars.get(self.pos).cloned();
+    if found.is_some() { self.pos += 1; }
+    found
+  }
+
+  fn expect(&mut self, expected: char) -> Result<(), WordError> {
+    match self.next_char() {
+      Some(found) if found == expected => Ok(()),
+      Some(found) => Err(self.error_at(self.pos - 1, format!("expected '{}', found '{}'", expected, found))),
+      None => Err(self.error_at(self.pos, format!("expected '{}'", expected))),
+    }
+  }
+
+  /// The parameters in parentheses after a symbol, if there are any
+  fn params(&mut self) -> Result<Vec<f32>, WordError> {
+    if self.chars.get(self.pos) != Some(& '(') { return Ok(Vec::new()); }
+    self.pos += 1;
+    let mut params = Vec::new();
+    loop {
+      let start = self.pos;
+      while self.pos < self.chars.len() && !",)".contains(self.chars[self.pos]) {
+        self.pos += 1;
+      }
+      let text: String = self.chars[start..self.pos].iter().cloned().collect();
+      match text.trim().parse::<f32>() {
+        Ok(value) => params.push(value),
+        Err(_) => return Err(self.error_at(start, format!("'{}' is not a number", text.trim()))),
+      }
+      match self.next_char() {
+        Some(',') => (),
+        Some(')') => return Ok(params),
+        _ => return Err(self.error_at(self.pos, "missing ')'".to_string())),
+      }
+    }
+  }
+
+  /// Exactly `count` parameters, for the symbol which starts at `start`
+  fn exact_params(&mut self, start: usize, count: usize) -> Result<Vec<f32>, WordError> {
+    let params = self.params()?;
+    if params.len() != count {
+      return Err(self.error_at(start, format!("expected {} parameters, found {}", count, params.len())));
+    }
+    Ok(params)
+  }
+
+  fn life(& self, start: usize, value: f32) -> Result<u8, WordError> {
+    if value >= 0.0 && value <= 255.0 && value.fract() == 0.0 {
+      Ok(value as u8)
+    } else {
+      Err(self.error_at(start, format!("a life of {} is not a whole number from 0 to 255", value)))
+    }
+  }
+
+  fn symbol(&mut self, start: usize, id: char) -> Result<Module, WordError> {
+    let params = self.params()?;
+    if params.len() > MAX_PARAMS {
+      return Err(self.error_at(start, format!("symbols take at most {} parameters", MAX_PARAMS)));
+    }
+    Ok(symbol(id, & params))
+  }
+
+  /// A draw command, as found in a custom module
+  fn command(&mut self) -> Result<DrawCommand, WordError> {
+    let start = self.pos;
+    let key = match self.next_char() {
+      Some(key) => key,
+      None => return Err(self.error_at(start, "expected a draw command".to_string())),
+    };
+    Ok(match key {
+      'F' => { let p = self.exact_params(start, 2)?; segment_cmd(p[1], p[0]) },
+      'f' => forward_cmd(self.exact_params(start, 1)?[0]),
+      '+' => roll_cmd(self.exact_params(start, 1)?[0]),
+      '-' => roll_cmd(-self.exact_params(start, 1)?[0]),
+      '&' => pitch_cmd(self.exact_params(start, 1)?[0]),
+      '^' => pitch_cmd(-self.exact_params(start, 1)?[0]),
+      '\\' => yaw_cmd(self.exact_params(start, 1)?[0]),
+      '/' => yaw_cmd(-self.exact_params(start, 1)?[0]),
+      '!' => width_cmd(self.exact_params(start, 1)?[0]),
+      '\'' => color_cmd(self.exact_params(start, 1)?[0]),
+      '_' => susceptibility_cmd(self.exact_params(start, 1)?[0]),
+      '[' => push_cmd(),
+      ']' => pop_cmd(),
+      '.' => none_cmd(),
+      '@' => match self.next_char() {
+        Some('L') => { let p = self.exact_params(start, 2)?; foliage_cmd(p[0], p[1]) },
+        Some('E') => { let p = self.exact_params(start, 3)?; euler_cmd(p[0], p[1], p[2]) },
+        _ => return Err(self.error_at(start, "expected '@L' or '@E'".to_string())),
+      },
+      other => return Err(self.error_at(start, format!("unexpected '{}'", other))),
+    })
+  }
+
+  fn module(&mut self) -> Result<Module, WordError> {
+    let start = self.pos;
+    let key = self.next_char().unwrap();
+    match key {
+      'F' => {
+        let p = self.params()?;
+        match p.len() {
+          2 => Ok(branch(p[1], p[0], 0)),
+          3 => Ok(branch(p[1], p[0], self.life(start, p[2])?)),
+          count => Err(self.error_at(start, format!("expected 2 or 3 parameters, found {}", count))),
+        }
+      },
+      '@' => {
+        let kind_pos = self.pos;
+        match self.next_char() {
+          Some('T') => { let p = self.exact_params(start, 3)?; Ok(trunk(p[1], p[0], self.life(start, p[2])?)) },
+          Some('A') => { let p = self.exact_params(start, 1)?; Ok(trunk_apex(self.life(start, p[0])?)) },
+          Some('L') => { let p = self.exact_params(start, 3)?; Ok(branch_apex(p[0], p[1], self.life(start, p[2])?)) },
+          Some('E') => { let p = self.exact_params(start, 3)?; Ok(euler(p[0], p[1], p[2])) },
+          Some(digit) if digit.is_digit(10) => {
+            let mut num = digit.to_digit(10).unwrap();
+            while let Some(digit) = self.chars.get(self.pos).and_then(|c| c.to_digit(10)) {
+              num = num * 10 + digit;
+              self.pos += 1;
+              if num > 255 { return Err(self.error_at(kind_pos, "custom module numbers go up to 255".to_string())); }
+            }
+            self.expect(':')?;
+            Ok(custom(num as u8, self.command()?))
+          },
+          _ => Err(self.error_at(kind_pos, "expected 'T', 'A', 'L', 'E' or a custom module number after '@'".to_string())),
+        }
+      },
+      '`' => {
+        match self.next_char() {
+          Some(id) => self.symbol(start, id),
+          None => Err(self.error_at(start, "expected a symbol after '`'".to_string())),
+        }
+      },
+      id if id.is_alphabetic() && !is_reserved(id) => self.symbol(start, id),
+      // Everything else is a draw command of its own
+      _ => {
+        self.pos = start;
+        match self.command()? {
+          DrawCommand::None => Err(self.error_at(start, "'.' is only used in custom modules".to_string())),
+          command => Ok(command_module(command)),
+        }
+      },
+    }
+  }
+}
+
+/// The plain module for a draw command, for the commands which have one
+fn command_module(command: DrawCommand) -> Module {
+  match command {
+    DrawCommand::Roll { r } => roll(r),
+    DrawCommand::Pitch { r } => pitch(r),
+    DrawCommand::Yaw { r } => yaw(r),
+    DrawCommand::Euler { x, y, z } => euler(x, y, z),
+    DrawCommand::Forward { d } => forward(d),
+    DrawCommand::Width { w } => width(w),
+    DrawCommand::Color { c } => color(c),
+    DrawCommand::Susceptibility { e } => susceptibility(e),
+    DrawCommand::Push => push(),
+    DrawCommand::Pop => pop(),
+    // Segments and foliage are only found in custom modules, the rest have their own symbols
+    command => custom(0, command),
+  }
+}
+
+/// Read a word written by `format_word` or `format_word_indented`
+pub fn parse_word(text: & str) -> Result<Vec<Module>, WordError> {
+  let mut cursor = WordCursor { chars: text.chars().collect(), pos: 0 };
+  let mut word = Vec::new();
+  loop {
+    cursor.skip_whitespace();
+    if cursor.pos >= cursor.chars.len() { return Ok(word); }
+    word.push(cursor.module()?);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn round_trip(word: & [Module]) -> Vec<Module> {
+    let parsed = parse_word(& format_word(word)).unwrap();
+    assert_eq!(parse_word(& format_word_indented(word, "  ")).unwrap(), parsed);
+    parsed
+  }
+
+  fn turn_angle(module: & Module) -> f32 {
+    match module.to_draw_command() {
+      DrawCommand::Roll { r } | DrawCommand::Pitch { r } | DrawCommand::Yaw { r } => r,
+      command => panic!("{:?} is not a turn", command),
+    }
+  }
+
+  #[test]
+  fn negative_turns_keep_their_sign() {
+    let word = vec![roll(-0.5), pitch(-1.25), yaw(-2.0), roll(-0.0), pitch(-0.0), yaw(-0.0), roll(0.0), yaw(0.75)];
+    assert_eq!(format_word(& word[..3]), "-(0.5)^(1.25)/(2)");
+    let parsed = round_trip(& word);
+    assert_eq!(parsed, word);
+    for (parsed, module) in parsed.iter().zip(word.iter()) {
+      assert_eq!(turn_angle(parsed).is_sign_negative(), turn_angle(module).is_sign_negative());
+    }
+  }
+
+  #[test]
+  fn reserved_symbol_ids_are_escaped() {
+    let word: Vec<Module> = "Ff+-&^/\\[]!'_@`.$ (3".chars().map(|id| symbol(id, & [1.0, 2.0])).chain(vec![symbol('F', & []), symbol('A', & [])]).collect();
+    let text = format_word(& word);
+    assert!(text.starts_with("`F(1,2)`f(1,2)`+(1,2)"));
+    assert_eq!(round_trip(& word), word);
+  }
+
+  #[test]
+  fn custom_modules_keep_every_command() {
+    let commands = vec![
+      foliage_cmd(0.5, 1.5), segment_cmd(0.1, 2.0), forward_cmd(3.0), roll_cmd(0.25), roll_cmd(-0.25), pitch_cmd(-0.5),
+      yaw_cmd(0.75), euler_cmd(0.1, -0.2, 0.3), width_cmd(0.05), color_cmd(2.0), susceptibility_cmd(0.2), push_cmd(), pop_cmd(), none_cmd(),
+    ];
+    let word: Vec<Module> = commands.into_iter().enumerate().map(|(num, command)| custom(num as u8 * 15, command)).collect();
+    assert_eq!(round_trip(& word), word);
+  }
+
+  #[test]
+  fn susceptibility_is_written_as_in_grammars() {
+    assert_eq!(format_word(& [susceptibility(0.2)]), "_(0.2)");
+    assert_eq!(parse_word("_(0.2)").unwrap(), vec![susceptibility(0.2)]);
+  }
+
+  #[test]
+  fn lives_cover_the_whole_range() {
+    let word = vec![
+      branch(0.1, 1.0, 0), branch(0.1, 1.0, 255), trunk(0.2, 1.0, 0), trunk(0.2, 1.0, 255),
+      trunk_apex(0), trunk_apex(255), branch_apex(0.5, 1.0, 0), branch_apex(0.5, 1.0, 255),
+    ];
+    assert_eq!(format_word(& word[..2]), "F(1,0.1)F(1,0.1,255)");
+    assert_eq!(round_trip(& word), word);
+    assert!(parse_word("F(1,0.1,256)").is_err());
+    assert!(parse_word("@A(-1)").is_err());
+  }
+}
@@ -0,0 +1,164 @@
+//! Branch radii from the topology of a word, after the pipe model of Shinozaki et al. and
+//! Leonardo da Vinci's rule: where a branch forks, `r_parent^n = sum of r_child^n`. An exponent
+//! of 2 keeps the cross-sectional area constant, larger ones give more slender trees.
+
+use lsystem::{ToDrawCommand, DrawCommand};
+
+/// Settings for `branch_radii`
+#[derive(Copy, Clone, Debug)]
+pub struct PipeModel {
+  /// The exponent n of the rule, usually between 2 and 3
+  pub exponent: f32,
+  /// The radius of the segments at the tips of the branches. If there is none, each tip keeps
+  /// half of the width it would have been drawn with.
+  pub tip_radius: Option<f32>,
+  /// Narrow each segment linearly towards the radius of the segment which continues its axis,
+  /// instead of keeping one radius along the whole segment
+  pub taper: bool,
+}
+
+impl PipeModel {
+  /// Panics unless the exponent is positive, as the rule has no meaning otherwise
+  pub fn new(exponent: f32) -> PipeModel {
+    assert!(exponent > 0.0, "the pipe model's exponent must be positive, not {}", exponent);
+    PipeModel {
+      exponent: exponent,
+      tip_radius: None,
+      taper: false,
+    }
+  }
+
+  /// The classic rule, which preserves cross-sectional area
+  pub fn da_vinci() -> PipeModel { PipeModel::new(2.0) }
+}
+
+/// A segment and the segments which grow from its end
+struct SegmentNode {
+  tip_radius: f32,
+  // The next segment along the same axis
+  continuation: Option<usize>,
+  // The first segments of the branches which start from this segment's end
+  laterals: Vec<usize>,
+}
+
+/// The start and end radius of every segment in a word, in the order the turtle draws them.
+/// A segment continues the axis of the segment before it, unless a Push comes between them, in
+/// which case it starts a lateral branch. Radii are then worked out from the tips inwards.
+pub fn branch_radii<M: ToDrawCommand>(word: & [M], model: & PipeModel) -> Vec<(f32, f32)> {
+  let mut nodes: Vec<SegmentNode> = Vec::new();
+  // The last segment on the turtle's axis, and whether the next one starts a lateral branch
+  let mut current: Option<usize> = None;
+  let mut lateral = false;
+  let mut width = 1.0;
+  let mut stack: Vec<(Option<usize>, bool, f32)> = Vec::new();
+
+  for item in word {
+    match item.to_draw_command() {
+      DrawCommand::Segment { w, .. } => {
+        let id = nodes.len();
+        nodes.push(SegmentNode {
+          tip_radius: model.tip_radius.unwrap_or(w * width / 2.0),
+          continuation: None,
+          laterals: Vec::new(),
+        });
+        if let Some(parent) = current {
+          if lateral {
+            nodes[parent].laterals.push(id);
+          } else {
+            nodes[parent].continuation = Some(id);
+          }
+        }
+        current = Some(id);
+        lateral = false;
+      },
+      DrawCommand::Width { w } => {
+        width = w;
+      },
+      DrawCommand::Push => {
+        stack.push((current, lateral, width));
+        lateral = true;
+      },
+      DrawCommand::Pop => {
+        // Unmatched Pops leave the axis as it is
+        if let Some((saved_current, saved_lateral, saved_width)) = stack.pop() {
+          current = saved_current;
+          lateral = saved_lateral;
+          width = saved_width;
+        }
+      },
+      _ => (),
+    }
+  }
+
+  // Children always come after their parents in the word
+  let exponent = model.exponent;
+  let mut radii = vec![0.0_f32; nodes.len()];
+  for id in (0..nodes.len()).rev() {
+    let node = & nodes[id];
+    let children = node.continuation.iter().chain(node.laterals.iter());
+    let sum: f32 = children.map(|& child| radii[child].powf(exponent)).sum();
+    radii[id] = if sum > 0.0 { sum.powf(1.0 / exponent) } else { node.tip_radius };
+  }
+
+  nodes.iter().enumerate().map(|(id, node)| {
+    let end = match node.continuation {
+      Some(next) if model.taper => radii[next],
+      _ => radii[id],
+    };
+    (radii[id], end)
+  }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use lsystem::*;
+
+  fn f(w: f32) -> Module { branch(w, 1.0, 0) }
+
+  fn close(a: f32, b: f32) -> bool { (a - b).abs() < 1.0e-6 }
+
+  #[test]
+  fn forks_follow_the_rule() {
+    // F[F]F, where the tips are half as wide as they're drawn
+    let word = vec![f(0.2), push(), f(0.1), pop(), f(0.3)];
+    for & exponent in & [2.0, 3.0] {
+      let radii = branch_radii(& word, & PipeModel::new(exponent));
+      assert_eq!(radii.len(), 3);
+      assert!(close(radii[1].0, 0.05) && close(radii[2].0, 0.15));
+      assert!(close(radii[0].0.powf(exponent), radii[1].0.powf(exponent) + radii[2].0.powf(exponent)));
+      assert!(close(radii[0].0, (0.05_f32.powf(exponent) + 0.15_f32.powf(exponent)).powf(1.0 / exponent)));
+    }
+  }
+
+  #[test]
+  fn tip_radius_replaces_the_widths() {
+    let word = vec![f(0.2), push(), f(0.1), pop(), f(0.3)];
+    let model = PipeModel { tip_radius: Some(0.02), .. PipeModel::da_vinci() };
+    let radii = branch_radii(& word, & model);
+    assert!(close(radii[1].0, 0.02) && close(radii[2].0, 0.02));
+    assert!(close(radii[0].0, (2.0 * 0.02_f32 * 0.02).sqrt()));
+  }
+
+  #[test]
+  fn tapered_segments_meet_the_next_on_their_axis() {
+    // FF[F]F
+    let word = vec![f(0.4), f(0.3), push(), f(0.1), pop(), f(0.2)];
+    let model = PipeModel { taper: true, .. PipeModel::da_vinci() };
+    let radii = branch_radii(& word, & model);
+    assert!(close(radii[0].1, radii[1].0));
+    assert!(close(radii[1].1, radii[3].0));
+    // The tips have nothing to narrow towards
+    assert_eq!(radii[2].0, radii[2].1);
+    assert_eq!(radii[3].0, radii[3].1);
+    assert!(radii[1].0 > radii[1].1);
+  }
+
+  #[test]
+  fn widths_are_restored_at_pops() {
+    let word = vec![f(0.1), push(), width(4.0), f(0.1), pop(), f(0.1)];
+    let radii = branch_radii(& word, & PipeModel::da_vinci());
+    assert!(close(radii[1].0, 0.2));
+    assert!(close(radii[2].0, 0.05));
+  }
+}
@@ -8,12 +8,12 @@ use defs::*;
 // Re export the random function
 pub use rand::random;
 
-pub fn random_lohi<T: Float + rand::Rand>(lo: T, hi: T) -> T {
-  lo + random::<T>() * (hi - lo)
+pub fn random_lohi<T: Float + rand::Rand, R: rand::Rng>(num_gen: &mut R, lo: T, hi: T) -> T {
+  lo + num_gen.gen::<T>() * (hi - lo)
 }
 
-pub fn random_max<T: Float + rand::Rand>(hi: T) -> T {
-  random::<T>() * hi
+pub fn random_max<T: Float + rand::Rand, R: rand::Rng>(num_gen: &mut R, hi: T) -> T {
+  num_gen.gen::<T>() * hi
 }
 
 pub fn rand_points_in_sphere<R: rand::Rng>(num_gen: &mut R, num: usize, radius: f32) -> Vec<Pt> {
@@ -0,0 +1,208 @@
+//! Timed l-systems, after the timed DOL-systems of ABOP chapter 6. Every module has an age and a
+//! terminal age, and is rewritten when it reaches its terminal age, instead of once per iteration.
+//! Time is continuous, so the word can be found at any time t, and modules which are still growing
+//! are drawn partly grown. Stepping t gives the frames of a smooth growth animation.
+
+use lsystem::{DrawCommand, Module};
+use turtle::Tropism;
+
+/// A module along with how long it has existed, and the age at which it is rewritten
+#[derive(Copy, Clone, Debug)]
+pub struct TimedModule<M> {
+  pub module: M,
+  pub age: f32,
+  pub terminal_age: f32,
+}
+
+impl<M> TimedModule<M> {
+  pub fn new(module: M, age: f32, terminal_age: f32) -> TimedModule<M> {
+    TimedModule {
+      module: module,
+      age: age,
+      terminal_age: terminal_age,
+    }
+  }
+
+  /// A module which is never rewritten and is always fully grown, such as a bracket
+  pub fn fixed(module: M) -> TimedModule<M> { TimedModule::new(module, 0.0, ::std::f32::INFINITY) }
+}
+
+/// Modules which can be drawn part of the way through their growth
+pub trait Growth {
+  /// The module when it has grown by `fraction`, from 0 when it appears to 1 at its terminal age
+  fn grown(& self, fraction: f32) -> Self;
+}
+
+/// Lengths and angles grow, widths and other attributes are there from the start
+impl Growth for DrawCommand {
+  fn grown(& self, fraction: f32) -> DrawCommand {
+    match * self {
+      DrawCommand::Foliage { r, l } => DrawCommand::Foliage { r: r * fraction, l: l * fraction },
+      DrawCommand::Segment { w, l } => DrawCommand::Segment { w: w, l: l * fraction },
+      DrawCommand::Forward { d } => DrawCommand::Forward { d: d * fraction },
+      DrawCommand::Roll { r } => DrawCommand::Roll { r: r * fraction },
+      DrawCommand::Pitch { r } => DrawCommand::Pitch { r: r * fraction },
+      DrawCommand::Yaw { r } => DrawCommand::Yaw { r: r * fraction },
+      DrawCommand::Euler { x, y, z } => DrawCommand::Euler { x: x * fraction, y: y * fraction, z: z * fraction },
+      command => command,
+    }
+  }
+}
+
+impl Growth for Module {
+  fn grown(& self, fraction: f32) -> Module {
+    match * self {
+      Module::Roll { r } => Module::Roll { r: r * fraction },
+      Module::Pitch { r } => Module::Pitch { r: r * fraction },
+      Module::Yaw { r } => Module::Yaw { r: r * fraction },
+      Module::Euler { x, y, z } => Module::Euler { x: x * fraction, y: y * fraction, z: z * fraction },
+      Module::BranchApex { r, l, life } => Module::BranchApex { r: r * fraction, l: l * fraction, life: life },
+      Module::Trunk { w, l, life } => Module::Trunk { w: w, l: l * fraction, life: life },
+      Module::Branch { w, l, life } => Module::Branch { w: w, l: l * fraction, life: life },
+      Module::Forward { d } => Module::Forward { d: d * fraction },
+      Module::Custom(id, cmd) => Module::Custom(id, cmd.grown(fraction)),
+      module => module,
+    }
+  }
+}
+
+/// A timed l-system. Productions are applied to a module when it reaches its terminal age, and
+/// the time left over goes to its successors, so the word at a time doesn't depend on the steps
+/// taken to get there.
+pub trait TimedLSystem {
+  type Module: Copy + Growth;
+  /// The initial word, at time 0
+  fn axiom(& self) -> Vec<TimedModule<Self::Module>>;
+  /// The successors of a module which has reached its terminal age, each with its starting age
+  /// and terminal age. None leaves the module in place, fully grown. Only successors which are
+  /// younger than their terminal age will be rewritten.
+  fn produce(& self, module: Self::Module) -> Option<Vec<TimedModule<Self::Module>>>;
+  /// How far a module has grown at `age`, from 0 to 1. By default modules grow linearly until
+  /// their terminal age, and ones which are never rewritten are fully grown.
+  fn growth(& self, age: f32, terminal_age: f32) -> f32 {
+    if terminal_age.is_finite() && terminal_age > 0.0 {
+      (age / terminal_age).max(0.0).min(1.0)
+    } else {
+      1.0
+    }
+  }
+  /// The tropism the turtle should apply when drawing this system's words. By default there is none.
+  fn tropism(& self) -> Tropism {
+    Tropism::none()
+  }
+}
+
+/// Let `duration` pass for every module of a timed word, rewriting the ones which reach their
+/// terminal age along the way
+pub fn advance<T: TimedLSystem>(lsystem: & T, word: & [TimedModule<T::Module>], duration: f32) -> Vec<TimedModule<T::Module>> {
+  let mut result = Vec::with_capacity(word.len());
+  // Modules still to age, with the time left for them, in reverse word order
+  let mut pending: Vec<(TimedModule<T::Module>, f32)> = word.iter().rev().map(|& timed| (timed, duration)).collect();
+  while let Some((mut timed, remaining)) = pending.pop() {
+    let lifetime = timed.terminal_age - timed.age;
+    if lifetime > 0.0 && remaining >= lifetime {
+      if let Some(successors) = lsystem.produce(timed.module) {
+        let left_over = remaining - lifetime;
+        pending.extend(successors.into_iter().rev().map(|successor| (successor, left_over)));
+        continue;
+      }
+    }
+    timed.age += remaining;
+    result.push(timed);
+  }
+  result
+}
+
+/// The timed word at time `time`
+pub fn timed_word<T: TimedLSystem>(lsystem: & T, time: f32) -> Vec<TimedModule<T::Module>> {
+  advance(lsystem, & lsystem.axiom(), time)
+}
+
+/// Draw each module of a timed word as far as it has grown
+pub fn interpolate<T: TimedLSystem>(lsystem: & T, word: & [TimedModule<T::Module>]) -> Vec<T::Module> {
+  word.iter().map(|timed| timed.module.grown(lsystem.growth(timed.age, timed.terminal_age))).collect()
+}
+
+/// The word to draw at time `time`
+pub fn word_at<T: TimedLSystem>(lsystem: & T, time: f32) -> Vec<T::Module> {
+  interpolate(lsystem, & timed_word(lsystem, time))
+}
+
+/// The words for `count` frames of a growth animation, evenly spaced from time 0 to `end_time`
+pub fn frames<T: TimedLSystem>(lsystem: & T, end_time: f32, count: u32) -> Vec<Vec<T::Module>> {
+  let mut words = Vec::with_capacity(count as usize);
+  let mut word = lsystem.axiom();
+  let mut time = 0.0;
+  for frame in 0..count {
+    let frame_time = if count > 1 { end_time * (frame as f32) / ((count - 1) as f32) } else { end_time };
+    word = advance(lsystem, & word, frame_time - time);
+    time = frame_time;
+    words.push(interpolate(lsystem, & word));
+  }
+  words
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use lsystem::*;
+  use trees::GrowingTree;
+
+  fn tree() -> GrowingTree {
+    GrowingTree {
+      base_width: 0.15,
+      segment_length: 1.0,
+      branch_angle: 45.0_f32.to_radians(),
+      branch_depth: 3,
+      foliage_radius: 0.5,
+      tropism: Tropism::none(),
+    }
+  }
+
+  fn assert_same_words(found: & [TimedModule<Module>], expected: & [TimedModule<Module>]) {
+    assert_eq!(found.len(), expected.len());
+    for (found, expected) in found.iter().zip(expected.iter()) {
+      assert_eq!(found.module, expected.module);
+      assert!((found.age - expected.age).abs() < 1.0e-5);
+      assert_eq!(found.terminal_age, expected.terminal_age);
+    }
+  }
+
+  #[test]
+  fn steps_reach_the_same_word() {
+    let system = tree();
+    let stepped = [1.0, 0.5, 1.5].iter().fold(system.axiom(), |word, & step| advance(& system, & word, step));
+    let direct = timed_word(& system, 3.0);
+    assert!(direct.len() > system.axiom().len());
+    assert_same_words(& stepped, & direct);
+  }
+
+  #[test]
+  fn frames_run_from_the_axiom_to_the_end() {
+    let system = tree();
+    let words = frames(& system, 3.0, 7);
+    assert_eq!(words.len(), 7);
+    assert_eq!(words[0], interpolate(& system, & system.axiom()));
+    assert_eq!(words[6], word_at(& system, 3.0));
+  }
+
+  /// A symbol which is replaced by a copy of itself with no time left to live
+  struct ZeroLifetime;
+
+  impl TimedLSystem for ZeroLifetime {
+    type Module = Module;
+
+    fn axiom(& self) -> Vec<TimedModule<Module>> { vec![TimedModule::new(symbol('A', & []), 0.0, 1.0)] }
+
+    fn produce(& self, module: Module) -> Option<Vec<TimedModule<Module>>> {
+      Some(vec![TimedModule::new(module, 0.0, 0.0), TimedModule::new(module, 0.5, 0.5)])
+    }
+  }
+
+  #[test]
+  fn modules_without_a_lifetime_are_not_rewritten() {
+    let word = timed_word(& ZeroLifetime, 5.0);
+    assert_eq!(word.len(), 2);
+    assert_eq!((word[0].age, word[1].age), (4.0, 4.5));
+  }
+}
@@ -1,7 +1,11 @@
 use std::{f32};
 
+use rand::{self, Rng};
+
 use lsystem::*;
 use rand_util::{random_max, random_lohi};
+use turtle::Tropism;
+use timed::{TimedModule, TimedLSystem};
 
 const PHI: f32 = 1.61803398875;
 const PHI_RECIP: f32 = 1.0 / PHI;
@@ -63,6 +67,90 @@ impl LSystem for DragonCurve {
   }
 }
 
+/// Acropetal signal propagation, after ABOP figure 1.31: a signal (the wider segment, custom module 2)
+/// starts at the base and moves one segment up the main axis and into each lateral branch per iteration.
+#[derive(Copy, Clone)]
+pub struct AcropetalSignal;
+
+impl AcropetalSignal {
+  fn carrier() -> Module { custom(1, segment_cmd(0.1, 1.0)) }
+  fn signal() -> Module { custom(2, segment_cmd(0.3, 1.0)) }
+}
+
+impl LSystem for AcropetalSignal {
+  type Module = Module;
+
+  fn axiom(& self) -> Vec<Module> {
+    let angle = 30.0_f32.to_radians();
+    vec![
+      AcropetalSignal::signal(),
+      push(), roll(angle), AcropetalSignal::carrier(), pop(),
+      AcropetalSignal::carrier(),
+      push(), roll(-angle), AcropetalSignal::carrier(), pop(),
+      AcropetalSignal::carrier(),
+      push(), roll(angle), AcropetalSignal::carrier(), pop(),
+      AcropetalSignal::carrier(),
+    ]
+  }
+
+  fn produce(& self, module: Module) -> Vec<Module> {
+    match module {
+      Module::Custom(2, _) => vec![AcropetalSignal::carrier()],
+      _ => vec![module],
+    }
+  }
+
+  fn produce_in_context(& self, module: Module, context: & Context<Self>) -> Vec<Module> {
+    match (module, context.left()) {
+      (Module::Custom(1, _), Some(Module::Custom(2, _))) => vec![AcropetalSignal::signal()],
+      _ => self.produce(module),
+    }
+  }
+
+  fn is_context_sensitive(& self) -> bool {
+    true
+  }
+
+  fn context_role(& self, module: & Module) -> ContextRole {
+    match * module {
+      Module::Roll { .. } | Module::Pitch { .. } | Module::Yaw { .. } | Module::Euler { .. } => ContextRole::Ignored,
+      _ => module.context_role(),
+    }
+  }
+}
+
+/// The stochastic bush of ABOP figure 1.27: each segment is replaced by one of three
+/// branching patterns, chosen with equal probability
+#[derive(Copy, Clone)]
+pub struct StochasticBush;
+
+impl LSystem for StochasticBush {
+  type Module = Module;
+
+  fn axiom(& self) -> Vec<Module> {
+    vec![branch(0.1, 1.0, 0)]
+  }
+
+  fn produce(& self, module: Module) -> Vec<Module> {
+    self.produce_in_context(module, & Context::new(self, & [module], 0, rand::random(), 0))
+  }
+
+  fn produce_in_context(& self, module: Module, context: & Context<Self>) -> Vec<Module> {
+    match module {
+      Module::Branch { w, l, life } => {
+        let angle = 25.7_f32.to_radians();
+        let f = branch(w, l / 2.0, life);
+        StochasticRule::new(vec![
+          (1.0, vec![f, push(), roll(angle), f, pop(), f, push(), roll(-angle), f, pop(), f]),
+          (1.0, vec![f, push(), roll(angle), f, pop(), f]),
+          (1.0, vec![f, push(), roll(-angle), f, pop(), f]),
+        ]).choose(&mut context.rng())
+      },
+      _ => vec![module],
+    }
+  }
+}
+
 #[derive(Copy, Clone)]
 pub struct BasicTree;
 
@@ -121,6 +209,7 @@ impl LSystem for BasicTree {
 pub struct BranchingTree {
   pub base_width: f32,
   pub base_length: f32,
+  pub tropism: Tropism,
 }
 
 impl LSystem for BranchingTree {
@@ -175,6 +264,10 @@ impl LSystem for BranchingTree {
           _ => vec![module],
       }
   }
+
+  fn tropism(& self) -> Tropism {
+    self.tropism
+  }
 }
 
 #[derive(Copy, Clone)]
@@ -184,21 +277,20 @@ pub struct RoundTree {
   pub branch_base_length: f32,
   pub base_foliage_radius: f32,
   pub base_foliage_length: f32,
+  pub tropism: Tropism,
 }
 
-impl LSystem for RoundTree {
-  type Module = Module;
-
-  fn axiom(&self) -> Vec<Module> {
+impl RoundTree {
+  fn axiom_from<R: Rng>(&self, rng: &mut R) -> Vec<Module> {
     let max_rot = PI / 32.0;
     vec![
-      euler(random_max(max_rot), random_max(max_rot), random_max(max_rot)),
+      euler(random_max(rng, max_rot), random_max(rng, max_rot), random_max(rng, max_rot)),
       trunk(self.base_width, self.trunk_base_length, 0),
       trunk_apex(0),
     ]
   }
 
-  fn produce(&self, module: Module) -> Vec<Module> {
+  fn produce_from<R: Rng>(&self, module: Module, rng: &mut R) -> Vec<Module> {
     let max_trunk_rot = PI / 200.0;
     let min_branch_rot = -PI / 8.0;
     let max_branch_rot = PI / 8.0;
@@ -208,7 +300,7 @@ impl LSystem for RoundTree {
       Module::Trunk { w, l, life } => {
         vec![
           trunk(w, l * 1.2, life),
-          euler(random_max(max_trunk_rot), random_max(max_trunk_rot), random_max(max_trunk_rot)),
+          euler(random_max(rng, max_trunk_rot), random_max(rng, max_trunk_rot), random_max(rng, max_trunk_rot)),
           trunk(w, self.trunk_base_length, 0),
         ]
       },
@@ -226,12 +318,12 @@ impl LSystem for RoundTree {
           yaw((PHI * 30.0_f32).to_radians()),
           // yaw((90.0_f32).to_radians()),
           push(),
-          roll(-random_lohi(branch_angle_min, branch_angle_max)),
+          roll(-random_lohi(rng, branch_angle_min, branch_angle_max)),
           branch(self.base_width, self.branch_base_length, 4),
           branch_apex(self.base_foliage_radius, self.base_foliage_length, 0),
           pop(),
           push(),
-          roll(random_lohi(branch_angle_min, branch_angle_max)),
+          roll(random_lohi(rng, branch_angle_min, branch_angle_max)),
           branch(self.base_width, self.branch_base_length, 4),
           branch_apex(self.base_foliage_radius, self.base_foliage_length, 0),
           pop(),
@@ -245,14 +337,14 @@ impl LSystem for RoundTree {
             yaw((PHI * 360.0_f32).to_radians()),
             // yaw((90.0_f32).to_radians()),
             push(),
-            roll(random_lohi(25.0_f32, 30.0_f32).to_radians()),
-            euler(random_lohi(min_branch_rot, max_branch_rot), 0.0, random_lohi(min_branch_rot, max_branch_rot)),
+            roll(random_lohi(rng, 25.0_f32, 30.0_f32).to_radians()),
+            euler(random_lohi(rng, min_branch_rot, max_branch_rot), 0.0, random_lohi(rng, min_branch_rot, max_branch_rot)),
             branch(self.base_width, self.branch_base_length, 3),
             branch_apex(self.base_foliage_radius, self.base_foliage_length, 2),
             pop(),
             push(),
-            roll(-random_lohi(25.0_f32, 30.0_f32).to_radians()),
-            euler(random_lohi(min_branch_rot, max_branch_rot), 0.0, random_lohi(min_branch_rot, max_branch_rot)),
+            roll(-random_lohi(rng, 25.0_f32, 30.0_f32).to_radians()),
+            euler(random_lohi(rng, min_branch_rot, max_branch_rot), 0.0, random_lohi(rng, min_branch_rot, max_branch_rot)),
             branch(self.base_width, self.branch_base_length, 3),
             branch_apex(self.base_foliage_radius, self.base_foliage_length, 2),
             pop(),
@@ -267,3 +359,82 @@ impl LSystem for RoundTree {
     }
   }
 }
+
+impl LSystem for RoundTree {
+  type Module = Module;
+
+  fn axiom(&self) -> Vec<Module> {
+    self.axiom_from(&mut rand::thread_rng())
+  }
+
+  fn axiom_with_rng(&self, rng: &mut DerivationRng) -> Vec<Module> {
+    self.axiom_from(rng)
+  }
+
+  fn produce(&self, module: Module) -> Vec<Module> {
+    self.produce_from(module, &mut rand::thread_rng())
+  }
+
+  fn produce_in_context(&self, module: Module, context: & Context<Self>) -> Vec<Module> {
+    self.produce_from(module, &mut context.rng())
+  }
+
+  fn tropism(&self) -> Tropism {
+    self.tropism
+  }
+}
+
+/// A timed tree which grows continuously: every apex lays down a segment and two lateral buds
+/// each time unit, and the new segments, buds and angles grow in smoothly over that time.
+/// Lateral branches fork until they're `branch_depth` levels deep, then end in foliage.
+#[derive(Copy, Clone)]
+pub struct GrowingTree {
+  pub base_width: f32,
+  pub segment_length: f32,
+  pub branch_angle: f32,
+  pub branch_depth: u8,
+  pub foliage_radius: f32,
+  pub tropism: Tropism,
+}
+
+impl GrowingTree {
+  /// A module which appears at the start of a time unit and takes the whole unit to grow
+  fn growing(module: Module) -> TimedModule<Module> { TimedModule::new(module, 0.0, 1.0) }
+}
+
+impl TimedLSystem for GrowingTree {
+  type Module = Module;
+
+  fn axiom(& self) -> Vec<TimedModule<Module>> {
+    vec![GrowingTree::growing(trunk_apex(0))]
+  }
+
+  fn produce(& self, module: Module) -> Option<Vec<TimedModule<Module>>> {
+    let grow = GrowingTree::growing;
+    let fixed = TimedModule::fixed;
+    let bud = branch_apex(self.foliage_radius, self.foliage_radius, 0);
+    match module {
+      Module::TrunkApex { life } => Some(vec![
+        grow(trunk(self.base_width, self.segment_length, 0)),
+        fixed(push()), grow(roll(self.branch_angle)), grow(bud), fixed(pop()),
+        fixed(push()), grow(roll(-self.branch_angle)), grow(bud), fixed(pop()),
+        grow(yaw((PHI * 360.0_f32).to_radians())),
+        grow(trunk_apex(life.saturating_add(1))),
+      ]),
+      Module::BranchApex { r, l, life } if life < self.branch_depth => {
+        let child = branch_apex(r, l, life + 1);
+        let width = self.base_width * PHI_RECIP.powi(life as i32 + 1);
+        Some(vec![
+          grow(branch(width, self.segment_length * PHI_RECIP, 0)),
+          fixed(push()), grow(roll(self.branch_angle / 2.0)), grow(child), fixed(pop()),
+          fixed(push()), grow(roll(-self.branch_angle / 2.0)), grow(child), fixed(pop()),
+        ])
+      },
+      _ => None,
+    }
+  }
+
+  fn tropism(& self) -> Tropism {
+    self.tropism
+  }
+}
@@ -0,0 +1,252 @@
+use cgmath::*;
+
+use defs::*;
+use matrixstack;
+use lsystem::{ToDrawCommand, DrawCommand, Organ};
+
+/// The turtle moves in the positive-y direction by default
+fn base_heading() -> Vec3 { Vec3::new(0.0, 1.0, 0.0) }
+
+/// Bends the turtle's heading towards `direction` after every segment, as in ABOP section 2.2:
+/// the heading H is rotated around H × T by `susceptibility * |H × T|`. Pointing the direction
+/// down makes branches droop under gravity, pointing it up makes them grow towards the light.
+/// The length of `direction` scales the effect along with the susceptibility.
+#[derive(Copy, Clone, Debug)]
+pub struct Tropism {
+  pub direction: Vec3,
+  pub susceptibility: f32,
+}
+
+impl Tropism {
+  pub fn new(direction: Vec3, susceptibility: f32) -> Tropism {
+    Tropism {
+      direction: direction,
+      susceptibility: susceptibility,
+    }
+  }
+
+  /// No bending at all
+  pub fn none() -> Tropism { Tropism::new(Vec3::new(0.0, -1.0, 0.0), 0.0) }
+
+  /// Bending downwards, for weeping forms
+  pub fn gravity(susceptibility: f32) -> Tropism { Tropism::new(Vec3::new(0.0, -1.0, 0.0), susceptibility) }
+
+  /// Bending upwards, towards the light
+  pub fn light(susceptibility: f32) -> Tropism { Tropism::new(Vec3::new(0.0, 1.0, 0.0), susceptibility) }
+}
+
+/// The part of the turtle's state which is saved by Push and restored by Pop
+#[derive(Copy, Clone, Debug)]
+pub struct TurtleState {
+  /// Transformation from the turtle's local space to world space, the heading is the local y axis
+  pub frame: Mat4,
+  /// Set by DrawCommand::Width, multiplies the width of each segment
+  pub width: f32,
+  /// Set by DrawCommand::Color
+  pub color: f32,
+  /// Set by DrawCommand::Susceptibility, starts out as the tropism's susceptibility
+  pub susceptibility: f32,
+}
+
+impl TurtleState {
+  pub fn position(& self) -> Pt { Pt::from_vec(self.frame.w.truncate()) }
+
+  pub fn heading(& self) -> Vec3 { (self.frame * base_heading().extend(0.0)).truncate().normalize() }
+}
+
+/// Receives the geometry found by the turtle as it walks a word. Every event has a default
+/// implementation which ignores it, so sinks only need to handle the events they care about.
+pub trait TurtleSink {
+  /// A segment from `start` to `end`, with its final width. `organ` is the part of the plant the
+  /// segment was drawn for, and `state` is the turtle's state at `start`.
+  fn segment(&mut self, _start: Pt, _end: Pt, _width: f32, _organ: Organ, _state: & TurtleState) {}
+  /// Foliage along the heading from `start` to `end`, with radius `radius`. `state` is the turtle's state at `start`.
+  fn foliage(&mut self, _start: Pt, _end: Pt, _radius: f32, _state: & TurtleState) {}
+  /// The turtle moved to `state` without drawing anything
+  fn move_to(&mut self, _state: & TurtleState) {}
+  /// A branch starts from `state`, which is saved until the branch ends
+  fn branch_start(&mut self, _state: & TurtleState) {}
+  /// A branch ended, and the turtle returned to `state`
+  fn branch_end(&mut self, _state: & TurtleState) {}
+}
+
+/// A turtle which interprets draw commands, reporting what it draws to a sink
+pub struct Turtle {
+  mat_stack: matrixstack::MatrixStack<f32>,
+  tropism: Tropism,
+  width: f32,
+  color: f32,
+  susceptibility: f32,
+  // Width, color and susceptibility to go with each transform pushed onto the matrix stack
+  attribute_stack: Vec<(f32, f32, f32)>,
+}
+
+impl Turtle {
+  pub fn new() -> Turtle {
+    Turtle::with_tropism(Tropism::none())
+  }
+
+  pub fn with_tropism(tropism: Tropism) -> Turtle {
+    Turtle {
+      mat_stack: matrixstack::MatrixStack::new(),
+      tropism: tropism,
+      width: 1.0,
+      color: 0.0,
+      susceptibility: tropism.susceptibility,
+      attribute_stack: Vec::new(),
+    }
+  }
+
+  pub fn state(& self) -> TurtleState {
+    TurtleState {
+      frame: self.mat_stack.get_matrix(),
+      width: self.width,
+      color: self.color,
+      susceptibility: self.susceptibility,
+    }
+  }
+
+  fn advance(&mut self, distance: f32) {
+    self.mat_stack.transform(Matrix4::from_translation(base_heading() * distance));
+  }
+
+  /// Rotate the heading towards the tropism direction
+  fn bend(&mut self) {
+    if self.susceptibility == 0.0 { return; }
+    let frame = self.mat_stack.get_matrix();
+    let heading = (frame * base_heading().extend(0.0)).truncate().normalize();
+    let axis = heading.cross(self.tropism.direction);
+    let magnitude = axis.magnitude();
+    // Already parallel to the tropism direction
+    if magnitude < 1.0e-6 { return; }
+    // The frame is a rigid transformation, so its inverse rotation is the transpose
+    let rotation = Mat3::from_cols(frame.x.truncate(), frame.y.truncate(), frame.z.truncate());
+    let local_axis = (rotation.transpose() * (axis / magnitude)).normalize();
+    self.mat_stack.rotate(Mat3::from_axis_angle(local_axis, Rad(self.susceptibility * magnitude)));
+  }
+
+  /// Carry out one command, which draws the given organ
+  pub fn apply<S: TurtleSink>(&mut self, command: DrawCommand, organ: Organ, sink: &mut S) {
+    match command {
+      DrawCommand::Foliage { r: radius, l: length } => {
+        let state = self.state();
+        self.advance(length);
+        sink.foliage(state.position(), self.mat_stack.origin(), radius, & state);
+      },
+      DrawCommand::Segment { w: width, l: length } => {
+        let state = self.state();
+        self.advance(length);
+        sink.segment(state.position(), self.mat_stack.origin(), width * self.width, organ, & state);
+        self.bend();
+      },
+      DrawCommand::Forward { d: distance } => {
+        self.advance(distance);
+        sink.move_to(& self.state());
+      },
+      DrawCommand::Roll { r } => {
+        self.mat_stack.rotate(Matrix3::from_angle_z(Rad(r)));
+      },
+      DrawCommand::Pitch { r } => {
+        self.mat_stack.rotate(Matrix3::from_angle_x(Rad(r)));
+      },
+      DrawCommand::Yaw { r } => {
+        self.mat_stack.rotate(Matrix3::from_angle_y(Rad(r)));
+      },
+      DrawCommand::Euler { x, y, z } => {
+        self.mat_stack.rotate(Matrix3::from(Euler::new(Rad(x), Rad(y), Rad(z))));
+      },
+      DrawCommand::Width { w } => {
+        self.width = w;
+      },
+      DrawCommand::Color { c } => {
+        self.color = c;
+      },
+      DrawCommand::Susceptibility { e } => {
+        self.susceptibility = e;
+      },
+      DrawCommand::Push => {
+        self.mat_stack.push();
+        self.attribute_stack.push((self.width, self.color, self.susceptibility));
+        sink.branch_start(& self.state());
+      },
+      DrawCommand::Pop => {
+        // A Pop without a Push is ignored, rather than dropping the turtle back to the origin.
+        // `word_tree::check_brackets` finds them.
+        if let Some((width, color, susceptibility)) = self.attribute_stack.pop() {
+          self.mat_stack.pop();
+          self.width = width;
+          self.color = color;
+          self.susceptibility = susceptibility;
+          sink.branch_end(& self.state());
+        }
+      },
+      DrawCommand::None => (),
+    }
+  }
+}
+
+/// Walk a word with a fresh turtle, reporting everything it draws to `sink`.
+/// All of the geometry generators share this interpretation of the draw commands.
+pub fn interpret<M: ToDrawCommand, S: TurtleSink>(word: & [M], tropism: & Tropism, sink: &mut S) {
+  interpret_stream(word.iter(), tropism, sink);
+}
+
+/// Like `interpret`, for modules which arrive one at a time, such as from `lsystem::stream_system`
+pub fn interpret_stream<M: ToDrawCommand, I: Iterator<Item = M>, S: TurtleSink>(modules: I, tropism: & Tropism, sink: &mut S) {
+  let mut turtle = Turtle::with_tropism(* tropism);
+  for item in modules {
+    turtle.apply(item.to_draw_command(), item.organ(), sink);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use lsystem::*;
+
+  /// The angle of each segment above the horizontal, with the susceptibility it was drawn with
+  struct Slopes(Vec<(f32, f32)>);
+
+  impl TurtleSink for Slopes {
+    fn segment(&mut self, start: Pt, end: Pt, _width: f32, _organ: Organ, state: & TurtleState) {
+      let heading = end - start;
+      self.0.push((heading.y.atan2((heading.x * heading.x + heading.z * heading.z).sqrt()), state.susceptibility));
+    }
+  }
+
+  fn slopes(word: & [Module], tropism: & Tropism) -> Vec<(f32, f32)> {
+    let mut sink = Slopes(Vec::new());
+    interpret(word, tropism, &mut sink);
+    sink.0
+  }
+
+  fn f() -> Module { branch(0.1, 1.0, 0) }
+
+  fn close(a: f32, b: f32) -> bool { (a - b).abs() < 1.0e-5 }
+
+  #[test]
+  fn gravity_bends_horizontal_segments_down() {
+    // Turned to point along x, where H × T is as long as it gets
+    let word = vec![roll(-90.0_f32.to_radians()), f(), f()];
+    let found = slopes(& word, & Tropism::gravity(0.3));
+    assert!(close(found[0].0, 0.0));
+    assert!(close(found[1].0, -0.3));
+    // Without a tropism nothing bends
+    assert!(slopes(& word, & Tropism::none()).iter().all(|& (slope, _)| close(slope, 0.0)));
+  }
+
+  #[test]
+  fn susceptibility_lasts_until_the_pop() {
+    let word = vec![roll(-90.0_f32.to_radians()), f(), push(), susceptibility(0.0), f(), f(), pop(), f(), f()];
+    let found = slopes(& word, & Tropism::gravity(0.3));
+    let slopes: Vec<f32> = found.iter().map(|& (slope, _)| slope).collect();
+    let susceptibilities: Vec<f32> = found.iter().map(|& (_, susceptibility)| susceptibility).collect();
+    assert_eq!(susceptibilities, vec![0.3, 0.0, 0.0, 0.3, 0.3]);
+
+    // The branch keeps the heading it started with
+    assert!(close(slopes[1], -0.3) && close(slopes[2], -0.3));
+    // After the Pop, bending picks up again, by less as the heading nears the tropism
+    assert!(close(slopes[3], -0.3));
+    assert!(close(slopes[4], -0.3 - 0.3 * 0.3_f32.cos()));
+  }
+}
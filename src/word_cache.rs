@@ -0,0 +1,561 @@
+//! A compact binary format for words, to keep a derivation which was slow or lucky to find.
+//!
+//! A file is a header of 16 bytes, then the modules, then a checksum:
+//!
+//! - `LSWC`, the version as a u16 and two bytes which are always 0
+//! - the number of modules as a u64
+//! - each module as a tag byte followed by its fields, f32s for parameters, a u8 for a life, a u32
+//!   for a symbol's id followed by a byte for its number of parameters, or a u8 custom id followed
+//!   by its draw command, tagged the same way
+//! - the 32 bit FNV-1a hash of everything before it
+//!
+//! Numbers are little endian. `WordReader` reads a word as it streams in, and `MappedWord` maps a
+//! file into memory so the word can be drawn straight from it.
+
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use memmap::{Mmap, Protection};
+
+use lsystem::*;
+
+pub const MAGIC: [u8; 4] = [b'L', b'S', b'W', b'C'];
+/// The version written, which is the only one that can be read
+pub const VERSION: u16 = 1;
+const HEADER_LEN: usize = 16;
+const CHECKSUM_LEN: usize = 4;
+
+/// Why a cached word couldn't be read
+#[derive(Debug)]
+pub enum CacheError {
+  Io(io::Error),
+  /// The data doesn't start with `MAGIC`
+  NotACache,
+  UnsupportedVersion(u16),
+  /// The data has changed since it was written
+  ChecksumMismatch,
+  /// The data is the wrong shape, which a matching checksum should rule out
+  Corrupt(String),
+}
+
+impl fmt::Display for CacheError {
+  fn fmt(& self, f: &mut fmt::Formatter) -> fmt::Result {
+    match * self {
+      CacheError::Io(ref err) => write!(f, "{}", err),
+      CacheError::NotACache => write!(f, "not a cached word"),
+      CacheError::UnsupportedVersion(version) => write!(f, "version {} of the word cache format is not supported", version),
+      CacheError::ChecksumMismatch => write!(f, "the checksum doesn't match, the cached word is damaged"),
+      CacheError::Corrupt(ref message) => write!(f, "corrupt cached word: {}", message),
+    }
+  }
+}
+
+impl Error for CacheError {
+  fn description(& self) -> & str { "error reading a cached word" }
+}
+
+impl From<io::Error> for CacheError {
+  fn from(err: io::Error) -> CacheError {
+    match err.kind() {
+      io::ErrorKind::UnexpectedEof => CacheError::Corrupt("the data ends in the middle of the word".to_string()),
+      _ => CacheError::Io(err),
+    }
+  }
+}
+
+/// A running FNV-1a hash
+#[derive(Copy, Clone)]
+struct Checksum(u32);
+
+impl Checksum {
+  fn new() -> Checksum { Checksum(0x811c9dc5) }
+
+  fn update(&mut self, bytes: & [u8]) {
+    for & byte in bytes {
+      self.0 = (self.0 ^ byte as u32).wrapping_mul(0x01000193);
+    }
+  }
+}
+
+fn push_u32(bytes: &mut Vec<u8>, value: u32) {
+  bytes.push(value as u8);
+  bytes.push((value >> 8) as u8);
+  bytes.push((value >> 16) as u8);
+  bytes.push((value >> 24) as u8);
+}
+
+fn push_f32s(bytes: &mut Vec<u8>, values: & [f32]) {
+  for value in values {
+    push_u32(bytes, value.to_bits());
+  }
+}
+
+fn encode_command(bytes: &mut Vec<u8>, command: & DrawCommand) {
+  match * command {
+    DrawCommand::Foliage { r, l } => { bytes.push(0); push_f32s(bytes, & [r, l]); },
+    DrawCommand::Segment { w, l } => { bytes.push(1); push_f32s(bytes, & [w, l]); },
+    DrawCommand::Forward { d } => { bytes.push(2); push_f32s(bytes, & [d]); },
+    DrawCommand::Roll { r } => { bytes.push(3); push_f32s(bytes, & [r]); },
+    DrawCommand::Pitch { r } => { bytes.push(4); push_f32s(bytes, & [r]); },
+    DrawCommand::Yaw { r } => { bytes.push(5); push_f32s(bytes, & [r]); },
+    DrawCommand::Euler { x, y, z } => { bytes.push(6); push_f32s(bytes, & [x, y, z]); },
+    DrawCommand::Width { w } => { bytes.push(7); push_f32s(bytes, & [w]); },
+    DrawCommand::Color { c } => { bytes.push(8); push_f32s(bytes, & [c]); },
+    DrawCommand::Susceptibility { e } => { bytes.push(9); push_f32s(bytes, & [e]); },
+    DrawCommand::Push => bytes.push(10),
+    DrawCommand::Pop => bytes.push(11),
+    DrawCommand::None => bytes.push(12),
+  }
+}
+
+fn encode_module(bytes: &mut Vec<u8>, module: & Module) {
+  match * module {
+    Module::Roll { r } => { bytes.push(0); push_f32s(bytes, & [r]); },
+    Module::Pitch { r } => { bytes.push(1); push_f32s(bytes, & [r]); },
+    Module::Yaw { r } => { bytes.push(2); push_f32s(bytes, & [r]); },
+    Module::Euler { x, y, z } => { bytes.push(3); push_f32s(bytes, & [x, y, z]); },
+    Module::Push => bytes.push(4),
+    Module::Pop => bytes.push(5),
+    Module::TrunkApex { life } => { bytes.push(6); bytes.push(life); },
+    Module::BranchApex { r, l, life } => { bytes.push(7); push_f32s(bytes, & [r, l]); bytes.push(life); },
+    Module::Trunk { w, l, life } => { bytes.push(8); push_f32s(bytes, & [w, l]); bytes.push(life); },
+    Module::Branch { w, l, life } => { bytes.push(9); push_f32s(bytes, & [w, l]); bytes.push(life); },
+    Module::Forward { d } => { bytes.push(10); push_f32s(bytes, & [d]); },
+    Module::Width { w } => { bytes.push(11); push_f32s(bytes, & [w]); },
+    Module::Color { c } => { bytes.push(12); push_f32s(bytes, & [c]); },
+    Module::Susceptibility { e } => { bytes.push(13); push_f32s(bytes, & [e]); },
+    Module::Symbol { id, n, p } => {
+      bytes.push(14);
+      push_u32(bytes, id as u32);
+      bytes.push(n);
+      push_f32s(bytes, & p[..n as usize]);
+    },
+    Module::Custom(num, ref command) => {
+      bytes.push(15);
+      bytes.push(num);
+      encode_command(bytes, command);
+    },
+  }
+}
+
+/// Write a word in the cache format
+pub fn write_word<W: Write>(word: & [Module], out: &mut W) -> io::Result<()> {
+  let mut checksum = Checksum::new();
+  let mut bytes = Vec::with_capacity(HEADER_LEN);
+  bytes.extend_from_slice(& MAGIC);
+  bytes.push(VERSION as u8);
+  bytes.push((VERSION >> 8) as u8);
+  bytes.extend_from_slice(& [0, 0]);
+  let count = word.len() as u64;
+  push_u32(&mut bytes, count as u32);
+  push_u32(&mut bytes, (count >> 32) as u32);
+
+  // Modules are encoded a batch at a time, so long words don't need a second copy in memory
+  for batch in word.chunks(4096) {
+    for module in batch {
+      encode_module(&mut bytes, module);
+    }
+    checksum.update(& bytes);
+    out.write_all(& bytes)?;
+    bytes.clear();
+  }
+  if word.is_empty() {
+    checksum.update(& bytes);
+    out.write_all(& bytes)?;
+    bytes.clear();
+  }
+
+  push_u32(&mut bytes, checksum.0);
+  out.write_all(& bytes)
+}
+
+/// Write a word to a cache file
+pub fn save_word<P: AsRef<Path>>(word: & [Module], path: P) -> io::Result<()> {
+  let mut out = BufWriter::new(File::create(path)?);
+  write_word(word, &mut out)?;
+  out.flush()
+}
+
+/// Somewhere to decode bytes from
+trait Source {
+  fn fill(&mut self, buf: &mut [u8]) -> Result<(), CacheError>;
+
+  fn read_u8(&mut self) -> Result<u8, CacheError> {
+    let mut buf = [0; 1];
+    self.fill(&mut buf)?;
+    Ok(buf[0])
+  }
+
+  fn read_u16(&mut self) -> Result<u16, CacheError> {
+    let mut buf = [0; 2];
+    self.fill(&mut buf)?;
+    Ok(buf[0] as u16 | (buf[1] as u16) << 8)
+  }
+
+  fn read_u32(&mut self) -> Result<u32, CacheError> {
+    let mut buf = [0; 4];
+    self.fill(&mut buf)?;
+    Ok(buf[0] as u32 | (buf[1] as u32) << 8 | (buf[2] as u32) << 16 | (buf[3] as u32) << 24)
+  }
+
+  fn read_f32(&mut self) -> Result<f32, CacheError> {
+    Ok(f32::from_bits(self.read_u32()?))
+  }
+}
+
+/// Bytes which are already in memory
+struct SliceSource<'a> {
+  bytes: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> Source for SliceSource<'a> {
+  fn fill(&mut self, buf: &mut [u8]) -> Result<(), CacheError> {
+    let end = self.pos + buf.len();
+    if end > self.bytes.len() {
+      return Err(CacheError::Corrupt("the data ends in the middle of the word".to_string()));
+    }
+    buf.copy_from_slice(& self.bytes[self.pos..end]);
+    self.pos = end;
+    Ok(())
+  }
+}
+
+/// Bytes from a reader, hashed as they're read
+struct ReadSource<R> {
+  reader: R,
+  checksum: Checksum,
+}
+
+impl<R: Read> Source for ReadSource<R> {
+  fn fill(&mut self, buf: &mut [u8]) -> Result<(), CacheError> {
+    self.reader.read_exact(buf)?;
+    self.checksum.update(buf);
+    Ok(())
+  }
+}
+
+fn decode_command<S: Source>(source: &mut S) -> Result<DrawCommand, CacheError> {
+  Ok(match source.read_u8()? {
+    0 => foliage_cmd(source.read_f32()?, source.read_f32()?),
+    1 => segment_cmd(source.read_f32()?, source.read_f32()?),
+    2 => forward_cmd(source.read_f32()?),
+    3 => roll_cmd(source.read_f32()?),
+    4 => pitch_cmd(source.read_f32()?),
+    5 => yaw_cmd(source.read_f32()?),
+    6 => euler_cmd(source.read_f32()?, source.read_f32()?, source.read_f32()?),
+    7 => width_cmd(source.read_f32()?),
+    8 => color_cmd(source.read_f32()?),
+    9 => susceptibility_cmd(source.read_f32()?),
+    10 => push_cmd(),
+    11 => pop_cmd(),
+    12 => none_cmd(),
+    tag => return Err(CacheError::Corrupt(format!("unknown draw command tag {}", tag))),
+  })
+}
+
+fn decode_module<S: Source>(source: &mut S) -> Result<Module, CacheError> {
+  Ok(match source.read_u8()? {
+    0 => roll(source.read_f32()?),
+    1 => pitch(source.read_f32()?),
+    2 => yaw(source.read_f32()?),
+    3 => euler(source.read_f32()?, source.read_f32()?, source.read_f32()?),
+    4 => push(),
+    5 => pop(),
+    6 => trunk_apex(source.read_u8()?),
+    7 => branch_apex(source.read_f32()?, source.read_f32()?, source.read_u8()?),
+    8 => trunk(source.read_f32()?, source.read_f32()?, source.read_u8()?),
+    9 => branch(source.read_f32()?, source.read_f32()?, source.read_u8()?),
+    10 => forward(source.read_f32()?),
+    11 => width(source.read_f32()?),
+    12 => color(source.read_f32()?),
+    13 => susceptibility(source.read_f32()?),
+    14 => {
+      let code = source.read_u32()?;
+      let id = ::std::char::from_u32(code).ok_or(CacheError::Corrupt(format!("{:#x} is not a symbol id", code)))?;
+      let n = source.read_u8()? as usize;
+      if n > MAX_PARAMS {
+        return Err(CacheError::Corrupt(format!("a symbol with {} parameters", n)));
+      }
+      let mut params = [0.0; MAX_PARAMS];
+      for param in params[..n].iter_mut() {
+        * param = source.read_f32()?;
+      }
+      symbol(id, & params[..n])
+    },
+    15 => {
+      let num = source.read_u8()?;
+      custom(num, decode_command(source)?)
+    },
+    tag => return Err(CacheError::Corrupt(format!("unknown module tag {}", tag))),
+  })
+}
+
+/// The number of modules given by a header
+fn decode_header<S: Source>(source: &mut S) -> Result<u64, CacheError> {
+  let mut magic = [0; 4];
+  source.fill(&mut magic).map_err(|_| CacheError::NotACache)?;
+  if magic != MAGIC {
+    return Err(CacheError::NotACache);
+  }
+  let version = source.read_u16()?;
+  if version != VERSION {
+    return Err(CacheError::UnsupportedVersion(version));
+  }
+  if source.read_u16()? != 0 {
+    return Err(CacheError::Corrupt("the reserved bytes of the header aren't 0".to_string()));
+  }
+  let low = source.read_u32()? as u64;
+  let high = source.read_u32()? as u64;
+  Ok(low | high << 32)
+}
+
+/// Reads a cached word a module at a time. The checksum can only be compared once the whole word
+/// has been read, so a damaged word gives an error in place of its last module.
+pub struct WordReader<R> {
+  source: ReadSource<R>,
+  remaining: u64,
+}
+
+impl<R: Read> WordReader<R> {
+  pub fn new(reader: R) -> Result<WordReader<R>, CacheError> {
+    let mut source = ReadSource { reader: reader, checksum: Checksum::new() };
+    let remaining = decode_header(&mut source)?;
+    let mut word_reader = WordReader { source: source, remaining: remaining };
+    if remaining == 0 {
+      word_reader.check()?;
+    }
+    Ok(word_reader)
+  }
+
+  /// The number of modules still to be read
+  pub fn remaining(& self) -> u64 { self.remaining }
+
+  fn check(&mut self) -> Result<(), CacheError> {
+    let expected = self.source.checksum.0;
+    if self.source.read_u32()? != expected {
+      return Err(CacheError::ChecksumMismatch);
+    }
+    Ok(())
+  }
+}
+
+impl<R: Read> Iterator for WordReader<R> {
+  type Item = Result<Module, CacheError>;
+
+  fn next(&mut self) -> Option<Result<Module, CacheError>> {
+    if self.remaining == 0 { return None; }
+    let module = match decode_module(&mut self.source) {
+      Ok(module) => module,
+      Err(err) => {
+        // Nothing after a broken module can be trusted
+        self.remaining = 0;
+        return Some(Err(err));
+      },
+    };
+    self.remaining -= 1;
+    if self.remaining == 0 {
+      if let Err(err) = self.check() {
+        return Some(Err(err));
+      }
+    }
+    Some(Ok(module))
+  }
+}
+
+/// Read a whole cached word
+pub fn read_word<R: Read>(reader: R) -> Result<Vec<Module>, CacheError> {
+  let word_reader = WordReader::new(reader)?;
+  // The count comes from the file, so it only sizes the allocation up to a point
+  let mut word = Vec::with_capacity(word_reader.remaining().min(1 << 20) as usize);
+  for module in word_reader {
+    word.push(module?);
+  }
+  Ok(word)
+}
+
+/// Read a cache file into memory
+pub fn load_word<P: AsRef<Path>>(path: P) -> Result<Vec<Module>, CacheError> {
+  read_word(BufReader::new(File::open(path)?))
+}
+
+/// A cache file mapped into memory. The checksum and layout are checked when it's opened, after
+/// which its modules are decoded as they're needed, without reading the file in or keeping a copy.
+///
+/// The map reads the file itself, so the file must not change while it's open: a file which is
+/// rewritten makes `modules` panic, and one which is truncated crashes the process with SIGBUS.
+/// Use `load_word` for files which something else might be writing.
+pub struct MappedWord {
+  map: Mmap,
+  len: usize,
+}
+
+impl MappedWord {
+  pub fn open<P: AsRef<Path>>(path: P) -> Result<MappedWord, CacheError> {
+    let map = Mmap::open_path(path, Protection::Read)?;
+    let len = {
+      // Safe as long as the file doesn't change while it's mapped, see above
+      let bytes = unsafe { map.as_slice() };
+      if bytes.len() < HEADER_LEN + CHECKSUM_LEN {
+        return Err(if bytes.starts_with(& MAGIC) { CacheError::Corrupt("the data ends in the middle of the word".to_string()) } else { CacheError::NotACache });
+      }
+      let body = & bytes[..bytes.len() - CHECKSUM_LEN];
+      let mut source = SliceSource { bytes: body, pos: 0 };
+      let len = decode_header(&mut source)?;
+
+      let mut checksum = Checksum::new();
+      checksum.update(body);
+      let stored = SliceSource { bytes: bytes, pos: body.len() }.read_u32()?;
+      if stored != checksum.0 {
+        return Err(CacheError::ChecksumMismatch);
+      }
+
+      for _ in 0..len {
+        decode_module(&mut source)?;
+      }
+      if source.pos != body.len() {
+        return Err(CacheError::Corrupt(format!("{} bytes left over after the word", body.len() - source.pos)));
+      }
+      len as usize
+    };
+    Ok(MappedWord { map: map, len: len })
+  }
+
+  /// The number of modules in the word
+  pub fn len(& self) -> usize { self.len }
+
+  pub fn is_empty(& self) -> bool { self.len == 0 }
+
+  /// The modules of the word in order, which can be handed straight to `turtle::interpret_stream`
+  pub fn modules<'a>(&'a self) -> Modules<'a> {
+    // Safe as long as the file hasn't changed since `open` checked it
+    let bytes = unsafe { self.map.as_slice() };
+    Modules {
+      source: SliceSource { bytes: & bytes[..bytes.len() - CHECKSUM_LEN], pos: HEADER_LEN },
+      remaining: self.len,
+    }
+  }
+
+  pub fn to_vec(& self) -> Vec<Module> {
+    self.modules().collect()
+  }
+}
+
+/// The modules of a `MappedWord`. They were all decoded once when it was opened, so decoding them
+/// again can only fail if the file has changed since, which `MappedWord` rules out.
+pub struct Modules<'a> {
+  source: SliceSource<'a>,
+  remaining: usize,
+}
+
+impl<'a> Iterator for Modules<'a> {
+  type Item = Module;
+
+  fn next(&mut self) -> Option<Module> {
+    if self.remaining == 0 { return None; }
+    self.remaining -= 1;
+    Some(decode_module(&mut self.source).expect("the mapped word changed after it was opened"))
+  }
+
+  fn size_hint(& self) -> (usize, Option<usize>) { (self.remaining, Some(self.remaining)) }
+}
+
+impl<'a> ExactSizeIterator for Modules<'a> {}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn encoded(word: & [Module]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    write_word(word, &mut bytes).unwrap();
+    bytes
+  }
+
+  /// Change bytes and recompute the checksum, so only the change itself is wrong
+  fn patched(mut bytes: Vec<u8>, pos: usize, patch: & [u8]) -> Vec<u8> {
+    bytes[pos..pos + patch.len()].copy_from_slice(patch);
+    let body = bytes.len() - CHECKSUM_LEN;
+    let mut checksum = Checksum::new();
+    checksum.update(& bytes[..body]);
+    bytes.truncate(body);
+    push_u32(&mut bytes, checksum.0);
+    bytes
+  }
+
+  fn word() -> Vec<Module> {
+    vec![branch(0.1, 1.0, 3), push(), roll(-0.5), symbol('A', & [1.0, 2.0]), custom(4, foliage_cmd(0.5, 1.0)), pop(), trunk_apex(255)]
+  }
+
+  #[test]
+  fn round_trip() {
+    assert_eq!(read_word(& encoded(& word())[..]).unwrap(), word());
+    assert_eq!(read_word(& encoded(& [])[..]).unwrap(), vec![]);
+  }
+
+  #[test]
+  fn damaged_words_fail_the_checksum() {
+    let mut bytes = encoded(& word());
+    // The roll's angle
+    bytes[HEADER_LEN + 12] ^= 1;
+    match read_word(& bytes[..]) {
+      Err(CacheError::ChecksumMismatch) => (),
+      other => panic!("expected a checksum mismatch, found {:?}", other),
+    }
+  }
+
+  #[test]
+  fn other_versions_are_rejected() {
+    match read_word(& patched(encoded(& word()), 4, & [2, 0])[..]) {
+      Err(CacheError::UnsupportedVersion(2)) => (),
+      other => panic!("expected an unsupported version, found {:?}", other),
+    }
+    // A version over 255 is read whole
+    match read_word(& patched(encoded(& word()), 4, & [1, 1])[..]) {
+      Err(CacheError::UnsupportedVersion(257)) => (),
+      other => panic!("expected an unsupported version, found {:?}", other),
+    }
+  }
+
+  #[test]
+  fn reserved_bytes_must_be_zero() {
+    match read_word(& patched(encoded(& word()), 6, & [0, 1])[..]) {
+      Err(CacheError::Corrupt(_)) => (),
+      other => panic!("expected a corrupt header, found {:?}", other),
+    }
+  }
+
+  #[test]
+  fn mapped_words_are_checked_when_opened() {
+    let path = ::std::env::temp_dir().join(format!("word_cache_test_{}.lsw", ::std::process::id()));
+    let open = |bytes: & [u8]| {
+      File::create(& path).and_then(|mut file| file.write_all(bytes)).unwrap();
+      MappedWord::open(& path)
+    };
+    assert_eq!(open(& encoded(& word())).unwrap().to_vec(), word());
+    let mut damaged = encoded(& word());
+    damaged[HEADER_LEN + 12] ^= 1;
+    let damaged = open(& damaged);
+    let version = open(& patched(encoded(& word()), 4, & [2, 0]));
+    let _ = ::std::fs::remove_file(& path);
+    match damaged {
+      Err(CacheError::ChecksumMismatch) => (),
+      other => panic!("expected a checksum mismatch, found {:?}", other.map(|word| word.len())),
+    }
+    match version {
+      Err(CacheError::UnsupportedVersion(2)) => (),
+      other => panic!("expected an unsupported version, found {:?}", other.map(|word| word.len())),
+    }
+  }
+
+  #[test]
+  fn not_a_cache() {
+    match read_word(& b"F(1,0.1)"[..]) {
+      Err(CacheError::NotACache) => (),
+      other => panic!("expected not a cache, found {:?}", other),
+    }
+  }
+}
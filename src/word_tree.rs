@@ -0,0 +1,317 @@
+//! The branching structure of a word. A word is flat, with Push and Pop marking where branches
+//! start and end; a `WordTree` makes the branches explicit, as a tree of nodes which can be walked
+//! along their axes and out into their lateral branches, and can be turned back into the word.
+//!
+//! The nodes are the internodes and apices: modules which draw a segment or foliage, and modules
+//! which draw nothing, such as apices and grammar symbols. Turns, moves and attribute changes are
+//! kept in order along their branch between the nodes, but aren't nodes themselves.
+
+use std::error::Error;
+use std::fmt;
+
+use lsystem::{ToDrawCommand, DrawCommand};
+
+/// A Push or Pop without a partner, at `index` in the word
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BracketError {
+  UnmatchedPop { index: usize },
+  UnclosedPush { index: usize },
+}
+
+impl fmt::Display for BracketError {
+  fn fmt(& self, f: &mut fmt::Formatter) -> fmt::Result {
+    match * self {
+      BracketError::UnmatchedPop { index } => write!(f, "module {} pops a branch which was never pushed", index),
+      BracketError::UnclosedPush { index } => write!(f, "the branch pushed at module {} is never popped", index),
+    }
+  }
+}
+
+impl Error for BracketError {
+  fn description(& self) -> & str { "unbalanced brackets" }
+}
+
+/// Check that every Push in a word has a matching Pop. The turtle ignores Pops without a Push,
+/// and leaves branches which are never popped at the end of the word, so both are usually bugs
+/// in the productions.
+pub fn check_brackets<M: ToDrawCommand>(word: & [M]) -> Result<(), BracketError> {
+  let mut open = Vec::new();
+  for (index, module) in word.iter().enumerate() {
+    match module.to_draw_command() {
+      DrawCommand::Push => open.push(index),
+      DrawCommand::Pop => {
+        if open.pop().is_none() {
+          return Err(BracketError::UnmatchedPop { index: index });
+        }
+      },
+      _ => (),
+    }
+  }
+  match open.first() {
+    Some(& index) => Err(BracketError::UnclosedPush { index: index }),
+    None => Ok(()),
+  }
+}
+
+/// Whether a module is a node of the tree
+fn is_node(command: DrawCommand) -> bool {
+  match command {
+    DrawCommand::Segment { .. } | DrawCommand::Foliage { .. } | DrawCommand::None => true,
+    _ => false,
+  }
+}
+
+/// An internode or apex
+#[derive(Clone, Debug)]
+pub struct TreeNode<M> {
+  pub module: M,
+  /// Where the module is in the word
+  pub index: usize,
+  /// The branch the node is on
+  pub branch: usize,
+  /// The node before it on its axis, or the node its branch grows from. Only the first node of
+  /// the word, and of branches pushed before it, have none.
+  pub parent: Option<usize>,
+  /// The next node along the same axis
+  pub main: Option<usize>,
+  /// The first nodes of the branches which grow from it, in word order
+  pub laterals: Vec<usize>,
+  /// 0 on the main axis, one more on each branch than on the axis it grows from
+  pub order: u32,
+  /// The number of nodes between it and the root
+  pub depth: u32,
+}
+
+/// Something along a branch, in word order
+#[derive(Clone, Debug)]
+pub enum BranchItem<M> {
+  /// An index into `WordTree::nodes`
+  Node(usize),
+  /// A turn, move or attribute change
+  Module(M),
+  /// An index into `WordTree::branches`
+  Branch(usize),
+}
+
+/// The contents of a Push and Pop pair, or the whole word for the main axis
+#[derive(Clone, Debug)]
+pub struct Branch<M> {
+  /// The Push and Pop which enclose the branch, which the main axis doesn't have
+  pub brackets: Option<(M, M)>,
+  /// The branch this one is nested in
+  pub parent: Option<usize>,
+  /// The last node before the branch starts, which the branch grows from
+  pub base: Option<usize>,
+  pub order: u32,
+  pub items: Vec<BranchItem<M>>,
+}
+
+/// A word as a tree of nodes and the branches they're on. `branches[0]` is the main axis.
+#[derive(Clone, Debug)]
+pub struct WordTree<M> {
+  pub nodes: Vec<TreeNode<M>>,
+  pub branches: Vec<Branch<M>>,
+}
+
+/// Build the tree of a word, which must have balanced brackets
+pub fn word_tree<M: ToDrawCommand + Clone>(word: & [M]) -> Result<WordTree<M>, BracketError> {
+  let mut tree = WordTree {
+    nodes: Vec::new(),
+    branches: vec![Branch { brackets: None, parent: None, base: None, order: 0, items: Vec::new() }],
+  };
+  // The branch being added to, the last node on it or the node it grows from, and whether that
+  // node is on the branch
+  let mut branch = 0;
+  let mut current: Option<usize> = None;
+  let mut on_axis = false;
+  // The same for every enclosing branch, with the index and module of the Push
+  let mut stack: Vec<(usize, Option<usize>, bool, usize, M)> = Vec::new();
+
+  for (index, module) in word.iter().enumerate() {
+    let command = module.to_draw_command();
+    match command {
+      DrawCommand::Push => {
+        let id = tree.branches.len();
+        tree.branches.push(Branch {
+          brackets: None,
+          parent: Some(branch),
+          base: current,
+          order: tree.branches[branch].order + 1,
+          items: Vec::new(),
+        });
+        tree.branches[branch].items.push(BranchItem::Branch(id));
+        stack.push((branch, current, on_axis, index, module.clone()));
+        branch = id;
+        on_axis = false;
+      },
+      DrawCommand::Pop => {
+        let (saved_branch, saved_current, saved_on_axis, _, push) = match stack.pop() {
+          Some(saved) => saved,
+          None => return Err(BracketError::UnmatchedPop { index: index }),
+        };
+        tree.branches[branch].brackets = Some((push, module.clone()));
+        branch = saved_branch;
+        current = saved_current;
+        on_axis = saved_on_axis;
+      },
+      command if is_node(command) => {
+        let id = tree.nodes.len();
+        let depth = current.map_or(0, |parent| tree.nodes[parent].depth + 1);
+        if let Some(parent) = current {
+          if on_axis {
+            tree.nodes[parent].main = Some(id);
+          } else {
+            tree.nodes[parent].laterals.push(id);
+          }
+        }
+        tree.nodes.push(TreeNode {
+          module: module.clone(),
+          index: index,
+          branch: branch,
+          parent: current,
+          main: None,
+          laterals: Vec::new(),
+          order: tree.branches[branch].order,
+          depth: depth,
+        });
+        tree.branches[branch].items.push(BranchItem::Node(id));
+        current = Some(id);
+        on_axis = true;
+      },
+      _ => tree.branches[branch].items.push(BranchItem::Module(module.clone())),
+    }
+  }
+
+  match stack.first() {
+    Some(& (_, _, _, index, _)) => Err(BracketError::UnclosedPush { index: index }),
+    None => Ok(tree),
+  }
+}
+
+impl<M: Clone> WordTree<M> {
+  /// The nodes which have no parent, the first of which is the base of the plant
+  pub fn roots(& self) -> Vec<usize> {
+    (0..self.nodes.len()).filter(|& id| self.nodes[id].parent.is_none()).collect()
+  }
+
+  /// The node and the nodes which continue its axis, up to the tip
+  pub fn axis(& self, node: usize) -> Vec<usize> {
+    let mut axis = vec![node];
+    let mut next = self.nodes[node].main;
+    while let Some(id) = next {
+      axis.push(id);
+      next = self.nodes[id].main;
+    }
+    axis
+  }
+
+  /// The nodes which grow from a node, the one on its axis first
+  pub fn children(& self, node: usize) -> Vec<usize> {
+    let node = & self.nodes[node];
+    node.main.iter().chain(node.laterals.iter()).cloned().collect()
+  }
+
+  /// The nodes at the tips of the axes, which nothing grows from
+  pub fn tips(& self) -> Vec<usize> {
+    (0..self.nodes.len()).filter(|& id| self.nodes[id].main.is_none() && self.nodes[id].laterals.is_empty()).collect()
+  }
+
+  /// The word the tree was built from
+  pub fn to_word(& self) -> Vec<M> {
+    let mut word = Vec::new();
+    // Branches being written, and how far through their items each one is
+    let mut stack = vec![(0, 0)];
+    while let Some((branch, item)) = stack.pop() {
+      let items = & self.branches[branch].items;
+      if item == items.len() {
+        if let Some((_, ref pop)) = self.branches[branch].brackets {
+          word.push(pop.clone());
+        }
+        continue;
+      }
+      stack.push((branch, item + 1));
+      match items[item] {
+        BranchItem::Node(node) => word.push(self.nodes[node].module.clone()),
+        BranchItem::Module(ref module) => word.push(module.clone()),
+        BranchItem::Branch(child) => {
+          if let Some((ref push, _)) = self.branches[child].brackets {
+            word.push(push.clone());
+          }
+          stack.push((child, 0));
+        },
+      }
+    }
+    word
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use lsystem::*;
+
+  fn f() -> Module { branch(0.1, 1.0, 0) }
+
+  /// `F[+F]F`, a main axis with one lateral branch
+  fn fork() -> Vec<Module> {
+    vec![f(), push(), roll(0.5), f(), pop(), f()]
+  }
+
+  #[test]
+  fn trees_give_back_their_words() {
+    // F[+F[-F]F]F
+    let word = vec![f(), push(), roll(0.5), f(), push(), roll(-0.5), f(), pop(), f(), pop(), f()];
+    let tree = word_tree(& word).unwrap();
+    assert_eq!(tree.nodes.len(), 5);
+    assert_eq!(tree.branches.len(), 3);
+    assert_eq!(tree.to_word(), word);
+    assert_eq!(word_tree(& fork()).unwrap().to_word(), fork());
+  }
+
+  #[test]
+  fn unbalanced_brackets_are_found() {
+    let unmatched = vec![f(), pop(), f()];
+    assert_eq!(check_brackets(& unmatched), Err(BracketError::UnmatchedPop { index: 1 }));
+    assert_eq!(word_tree(& unmatched).err(), Some(BracketError::UnmatchedPop { index: 1 }));
+
+    // The outermost branch which is never popped is reported
+    let unclosed = vec![f(), push(), f(), push(), f(), pop()];
+    assert_eq!(check_brackets(& unclosed), Err(BracketError::UnclosedPush { index: 1 }));
+    assert_eq!(word_tree(& unclosed).err(), Some(BracketError::UnclosedPush { index: 1 }));
+
+    assert_eq!(check_brackets(& fork()), Ok(()));
+  }
+
+  #[test]
+  fn forks_link_their_nodes() {
+    let tree = word_tree(& fork()).unwrap();
+    let (base, lateral, top) = (& tree.nodes[0], & tree.nodes[1], & tree.nodes[2]);
+    assert_eq!((base.index, lateral.index, top.index), (0, 3, 5));
+
+    assert_eq!((base.order, base.depth, base.parent), (0, 0, None));
+    assert_eq!(base.main, Some(2));
+    assert_eq!(base.laterals, vec![1]);
+
+    assert_eq!((lateral.order, lateral.depth, lateral.parent, lateral.main), (1, 1, Some(0), None));
+    assert_eq!((top.order, top.depth, top.parent, top.main), (0, 1, Some(0), None));
+    assert_eq!((lateral.branch, top.branch), (1, 0));
+
+    assert_eq!(tree.branches[1].base, Some(0));
+    assert_eq!(tree.roots(), vec![0]);
+    assert_eq!(tree.axis(0), vec![0, 2]);
+    assert_eq!(tree.children(0), vec![2, 1]);
+    assert_eq!(tree.tips(), vec![1, 2]);
+  }
+
+  #[test]
+  fn branches_before_the_first_node_have_no_parent() {
+    // [+F]F
+    let word = vec![push(), roll(0.5), f(), pop(), f()];
+    let tree = word_tree(& word).unwrap();
+    assert_eq!(tree.branches[1].base, None);
+    assert_eq!(tree.nodes[0].parent, None);
+    assert_eq!(tree.nodes[1].parent, None);
+    assert_eq!(tree.roots(), vec![0, 1]);
+    assert_eq!(tree.to_word(), word);
+  }
+}
@@ -0,0 +1,12 @@
+//! Little endian encoding for the binary mesh formats
+
+pub fn push_u32(bytes: &mut Vec<u8>, value: u32) {
+  bytes.push(value as u8);
+  bytes.push((value >> 8) as u8);
+  bytes.push((value >> 16) as u8);
+  bytes.push((value >> 24) as u8);
+}
+
+pub fn push_f32(bytes: &mut Vec<u8>, value: f32) {
+  push_u32(bytes, value.to_bits());
+}
@@ -0,0 +1,377 @@
+use std::io::{self, Write};
+
+use cgmath::prelude::*;
+use cgmath::Vector3;
+use glium::index::PrimitiveType;
+
+use bytes::{push_u32, push_f32};
+use vertex_index_mesh::VertexIndexMesh;
+
+const GLB_MAGIC: u32 = 0x46546C67;
+const GLB_VERSION: u32 = 2;
+const CHUNK_JSON: u32 = 0x4E4F534A;
+const CHUNK_BIN: u32 = 0x004E4942;
+
+const COMPONENT_FLOAT: u32 = 5126;
+const COMPONENT_UNSIGNED_INT: u32 = 5125;
+const TARGET_ARRAY_BUFFER: u32 = 34962;
+const TARGET_ELEMENT_ARRAY_BUFFER: u32 = 34963;
+
+fn primitive_mode(primtype: PrimitiveType) -> Option<u32> {
+  match primtype {
+    PrimitiveType::Points => Some(0),
+    PrimitiveType::LinesList => Some(1),
+    PrimitiveType::LineLoop => Some(2),
+    PrimitiveType::LineStrip => Some(3),
+    PrimitiveType::TrianglesList => Some(4),
+    PrimitiveType::TriangleStrip => Some(5),
+    PrimitiveType::TriangleFan => Some(6),
+    // Adjacency and patches have no glTF equivalent
+    _ => None,
+  }
+}
+
+/// JSON has no representation for infinities or NaN
+fn json_number(value: f32) -> String {
+  if value.is_finite() { format!("{}", value) } else { "0".to_string() }
+}
+
+fn json_string(text: & str) -> String {
+  let mut escaped = String::with_capacity(text.len() + 2);
+  escaped.push('"');
+  for ch in text.chars() {
+    match ch {
+      '"' => escaped.push_str("\\\""),
+      '\\' => escaped.push_str("\\\\"),
+      ch if (ch as u32) < 0x20 => escaped.push_str(& format!("\\u{:04x}", ch as u32)),
+      ch => escaped.push(ch),
+    }
+  }
+  escaped.push('"');
+  escaped
+}
+
+fn json_floats(values: & [f32]) -> String {
+  let parts: Vec<String> = values.iter().map(|& value| json_number(value)).collect();
+  format!("[{}]", parts.join(","))
+}
+
+/// glTF requires unit normals, so normals which can't be normalized point up instead
+fn unit_normal(normal: Vector3<f32>) -> Vector3<f32> {
+  let length = normal.magnitude();
+  if length.is_finite() && length > 0.0 {
+    normal / length
+  } else {
+    Vector3::unit_y()
+  }
+}
+
+/// Collects the binary buffer and the JSON describing its views and accessors
+struct GltfBuilder {
+  buffer: Vec<u8>,
+  buffer_views: Vec<String>,
+  accessors: Vec<String>,
+}
+
+impl GltfBuilder {
+  fn add_view(&mut self, bytes: & [u8], target: u32) -> usize {
+    self.buffer_views.push(format!("{{\"buffer\":0,\"byteOffset\":{},\"byteLength\":{},\"target\":{}}}", self.buffer.len(), bytes.len(), target));
+    self.buffer.extend_from_slice(bytes);
+    self.buffer_views.len() - 1
+  }
+
+  /// Add an accessor for `count` float vectors of `size` components, with their bounds
+  fn add_floats(&mut self, values: & [f32], size: usize, kind: & str) -> usize {
+    let count = values.len() / size;
+    let mut min = vec![::std::f32::INFINITY; size];
+    let mut max = vec![::std::f32::NEG_INFINITY; size];
+    let mut bytes = Vec::with_capacity(values.len() * 4);
+    for (idx, & value) in values.iter().enumerate() {
+      min[idx % size] = min[idx % size].min(value);
+      max[idx % size] = max[idx % size].max(value);
+      push_f32(&mut bytes, value);
+    }
+    let view = self.add_view(& bytes, TARGET_ARRAY_BUFFER);
+    self.accessors.push(format!("{{\"bufferView\":{},\"componentType\":{},\"count\":{},\"type\":\"{}\",\"min\":{},\"max\":{}}}",
+      view, COMPONENT_FLOAT, count, kind, json_floats(& min), json_floats(& max)));
+    self.accessors.len() - 1
+  }
+
+  fn add_indices(&mut self, indices: & [u32]) -> usize {
+    let mut bytes = Vec::with_capacity(indices.len() * 4);
+    for & index in indices {
+      push_u32(&mut bytes, index);
+    }
+    let min = indices.iter().cloned().min().unwrap_or(0);
+    let max = indices.iter().cloned().max().unwrap_or(0);
+    let view = self.add_view(& bytes, TARGET_ELEMENT_ARRAY_BUFFER);
+    self.accessors.push(format!("{{\"bufferView\":{},\"componentType\":{},\"count\":{},\"type\":\"SCALAR\",\"min\":[{}],\"max\":[{}]}}",
+      view, COMPONENT_UNSIGNED_INT, indices.len(), min, max));
+    self.accessors.len() - 1
+  }
+}
+
+/// Write named meshes as a binary glTF 2.0 file, each mesh in its own node with its own material.
+/// Positions, normals, vertex colours and texture coordinates all come from the mesh's vertices.
+/// Empty meshes are left out.
+pub fn write_glb<W: Write>(meshes: & [(& str, & VertexIndexMesh)], out: &mut W) -> io::Result<()> {
+  let mut builder = GltfBuilder {
+    buffer: Vec::new(),
+    buffer_views: Vec::new(),
+    accessors: Vec::new(),
+  };
+  let mut nodes = Vec::new();
+  let mut gltf_meshes = Vec::new();
+  let mut materials = Vec::new();
+
+  for & (name, mesh) in meshes {
+    if mesh.vertices.is_empty() || mesh.indices.is_empty() { continue; }
+    let mode = match primitive_mode(mesh.primtype) {
+      Some(mode) => mode,
+      None => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("mesh '{}' has a primitive type glTF doesn't support", name))),
+    };
+    if mesh.indices.iter().any(|& index| index as usize >= mesh.vertices.len()) {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("mesh '{}' has an index out of range", name)));
+    }
+
+    let mut positions = Vec::with_capacity(mesh.vertices.len() * 3);
+    let mut normals = Vec::with_capacity(mesh.vertices.len() * 3);
+    let mut colors = Vec::with_capacity(mesh.vertices.len() * 4);
+    let mut tex_coords = Vec::with_capacity(mesh.vertices.len() * 2);
+    for vert in & mesh.vertices {
+      let (pos, norm, color, tex) = (vert.pos(), unit_normal(vert.normal()), vert.color(), vert.tex());
+      positions.extend_from_slice(& [pos.x, pos.y, pos.z]);
+      normals.extend_from_slice(& [norm.x, norm.y, norm.z]);
+      colors.extend_from_slice(& [color.x, color.y, color.z, color.w]);
+      tex_coords.extend_from_slice(& [tex.x, tex.y]);
+    }
+
+    let position_accessor = builder.add_floats(& positions, 3, "VEC3");
+    let normal_accessor = builder.add_floats(& normals, 3, "VEC3");
+    let color_accessor = builder.add_floats(& colors, 4, "VEC4");
+    let tex_accessor = builder.add_floats(& tex_coords, 2, "VEC2");
+    let index_accessor = builder.add_indices(& mesh.indices);
+
+    // The colour is in the vertices, so the material only needs to be named
+    materials.push(format!("{{\"name\":{},\"pbrMetallicRoughness\":{{\"baseColorFactor\":[1,1,1,1],\"metallicFactor\":0,\"roughnessFactor\":1}}}}", json_string(name)));
+    gltf_meshes.push(format!("{{\"name\":{},\"primitives\":[{{\"attributes\":{{\"POSITION\":{},\"NORMAL\":{},\"COLOR_0\":{},\"TEXCOORD_0\":{}}},\"indices\":{},\"material\":{},\"mode\":{}}}]}}",
+      json_string(name), position_accessor, normal_accessor, color_accessor, tex_accessor, index_accessor, materials.len() - 1, mode));
+    nodes.push(format!("{{\"name\":{},\"mesh\":{}}}", json_string(name), gltf_meshes.len() - 1));
+  }
+
+  let mut json = "{\"asset\":{\"version\":\"2.0\",\"generator\":\"lsystem\"},\"scene\":0".to_string();
+  if nodes.is_empty() {
+    json.push_str(",\"scenes\":[{}]");
+  } else {
+    let node_ids: Vec<String> = (0..nodes.len()).map(|idx| idx.to_string()).collect();
+    json.push_str(& format!(",\"scenes\":[{{\"nodes\":[{}]}}],\"nodes\":[{}]", node_ids.join(","), nodes.join(",")));
+    json.push_str(& format!(",\"meshes\":[{}],\"materials\":[{}],\"accessors\":[{}],\"bufferViews\":[{}],\"buffers\":[{{\"byteLength\":{}}}]",
+      gltf_meshes.join(","), materials.join(","), builder.accessors.join(","), builder.buffer_views.join(","), builder.buffer.len()));
+  }
+  json.push('}');
+
+  // Chunks are padded to four bytes, JSON with spaces and binary with zeros
+  let mut json_bytes = json.into_bytes();
+  while json_bytes.len() % 4 != 0 { json_bytes.push(b' '); }
+  let mut bin_bytes = builder.buffer;
+  while bin_bytes.len() % 4 != 0 { bin_bytes.push(0); }
+
+  let mut total_length = 12 + 8 + json_bytes.len();
+  if !bin_bytes.is_empty() { total_length += 8 + bin_bytes.len(); }
+
+  let mut header = Vec::with_capacity(20);
+  push_u32(&mut header, GLB_MAGIC);
+  push_u32(&mut header, GLB_VERSION);
+  push_u32(&mut header, total_length as u32);
+  push_u32(&mut header, json_bytes.len() as u32);
+  push_u32(&mut header, CHUNK_JSON);
+  out.write_all(& header)?;
+  out.write_all(& json_bytes)?;
+
+  if !bin_bytes.is_empty() {
+    let mut bin_header = Vec::with_capacity(8);
+    push_u32(&mut bin_header, bin_bytes.len() as u32);
+    push_u32(&mut bin_header, CHUNK_BIN);
+    out.write_all(& bin_header)?;
+    out.write_all(& bin_bytes)?;
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use cgmath::Point3;
+
+  use super::*;
+  use vertex::Vertex;
+
+  /// Just enough JSON to read back what `write_glb` writes
+  #[derive(Clone, Debug, PartialEq)]
+  enum Json {
+    Num(f64),
+    Str(String),
+    Arr(Vec<Json>),
+    Obj(Vec<(String, Json)>),
+  }
+
+  impl Json {
+    fn get(& self, key: & str) -> & Json {
+      match * self {
+        Json::Obj(ref fields) => & fields.iter().find(|& & (ref name, _)| name == key).expect(key).1,
+        _ => panic!("not an object"),
+      }
+    }
+
+    fn at(& self, idx: usize) -> & Json {
+      match * self {
+        Json::Arr(ref items) => & items[idx],
+        _ => panic!("not an array"),
+      }
+    }
+
+    fn len(& self) -> usize {
+      match * self {
+        Json::Arr(ref items) => items.len(),
+        _ => panic!("not an array"),
+      }
+    }
+
+    fn num(& self) -> f64 {
+      match * self {
+        Json::Num(value) => value,
+        _ => panic!("not a number"),
+      }
+    }
+
+    fn usize(& self) -> usize { self.num() as usize }
+
+    fn str(& self) -> & str {
+      match * self {
+        Json::Str(ref text) => text,
+        _ => panic!("not a string"),
+      }
+    }
+
+    fn floats(& self) -> Vec<f32> {
+      (0..self.len()).map(|idx| self.at(idx).num() as f32).collect()
+    }
+  }
+
+  fn parse_json(text: & [u8], pos: &mut usize) -> Json {
+    while text[* pos] == b' ' { * pos += 1; }
+    match text[* pos] {
+      b'{' | b'[' => {
+        let object = text[* pos] == b'{';
+        * pos += 1;
+        let (mut fields, mut items) = (Vec::new(), Vec::new());
+        while text[* pos] != b'}' && text[* pos] != b']' {
+          if object {
+            let key = match parse_json(text, pos) { Json::Str(key) => key, _ => panic!("keys are strings") };
+            assert_eq!(text[* pos], b':');
+            * pos += 1;
+            fields.push((key, parse_json(text, pos)));
+          } else {
+            items.push(parse_json(text, pos));
+          }
+          if text[* pos] == b',' { * pos += 1; }
+        }
+        * pos += 1;
+        if object { Json::Obj(fields) } else { Json::Arr(items) }
+      },
+      b'"' => {
+        let start = * pos + 1;
+        * pos = start + text[start..].iter().position(|& byte| byte == b'"').unwrap();
+        * pos += 1;
+        Json::Str(String::from_utf8(text[start..(* pos - 1)].to_vec()).unwrap())
+      },
+      _ => {
+        let start = * pos;
+        while b"-+.eE0123456789".contains(& text[* pos]) { * pos += 1; }
+        Json::Num(::std::str::from_utf8(& text[start..* pos]).unwrap().parse().unwrap())
+      },
+    }
+  }
+
+  fn read_u32(bytes: & [u8], at: usize) -> u32 {
+    bytes[at] as u32 | (bytes[at + 1] as u32) << 8 | (bytes[at + 2] as u32) << 16 | (bytes[at + 3] as u32) << 24
+  }
+
+  fn read_floats(bin: & [u8], offset: usize, count: usize) -> Vec<f32> {
+    (0..count).map(|idx| f32::from_bits(read_u32(bin, offset + idx * 4))).collect()
+  }
+
+  fn triangle(offset: f32) -> VertexIndexMesh {
+    let mut mesh = VertexIndexMesh::new(PrimitiveType::TrianglesList);
+    let color = [0.3, 0.2, 0.1, 1.0];
+    mesh.add_vertex_shared(Vertex::new(& [offset, 0.0, 0.0], & [0.0, 0.0, 2.0], & color, & [0.0, 0.0]));
+    mesh.add_vertex_shared(Vertex::new(& [offset + 1.0, 0.0, -1.0], & [0.0, 0.0, 0.0], & color, & [1.0, 0.0]));
+    mesh.add_vertex_shared(Vertex::new(& [offset, 2.0, 0.5], & [::std::f32::NAN, 0.0, 1.0], & color, & [0.0, 1.0]));
+    mesh.add_triangle(0, 1, 2);
+    mesh
+  }
+
+  #[test]
+  fn glb_reads_back() {
+    let trunk = triangle(0.0);
+    let mut foliage = triangle(3.0);
+    foliage.extend_with(& triangle(5.0));
+    let branches = VertexIndexMesh::new(PrimitiveType::TrianglesList);
+    let mut glb = Vec::new();
+    write_glb(& [("trunk", & trunk), ("branches", & branches), ("foliage", & foliage)], &mut glb).unwrap();
+
+    // Header, then the JSON chunk and the binary chunk
+    assert_eq!(read_u32(& glb, 0), GLB_MAGIC);
+    assert_eq!(read_u32(& glb, 4), 2);
+    assert_eq!(read_u32(& glb, 8) as usize, glb.len());
+    let json_length = read_u32(& glb, 12) as usize;
+    assert_eq!(read_u32(& glb, 16), CHUNK_JSON);
+    assert_eq!(json_length % 4, 0);
+    let bin_start = 20 + json_length;
+    let bin_length = read_u32(& glb, bin_start) as usize;
+    assert_eq!(read_u32(& glb, bin_start + 4), CHUNK_BIN);
+    assert_eq!(bin_start + 8 + bin_length, glb.len());
+    let bin = & glb[(bin_start + 8)..];
+
+    let json = parse_json(& glb[20..bin_start], &mut 0);
+    assert_eq!(json.get("buffers").at(0).get("byteLength").usize(), bin_length);
+
+    // The empty branches are left out
+    let nodes = json.get("nodes");
+    let names: Vec<& str> = (0..nodes.len()).map(|idx| nodes.at(idx).get("name").str()).collect();
+    assert_eq!(names, vec!["trunk", "foliage"]);
+
+    let accessors = json.get("accessors");
+    let views = json.get("bufferViews");
+    for (node, & (mesh, vertices)) in [(& trunk, 3), (& foliage, 6)].iter().enumerate() {
+      let gltf_mesh = json.get("meshes").at(nodes.at(node).get("mesh").usize());
+      let primitive = gltf_mesh.get("primitives").at(0);
+      assert_eq!(primitive.get("mode").usize(), 4);
+      let attributes = primitive.get("attributes");
+      for & (attribute, kind) in & [("POSITION", "VEC3"), ("NORMAL", "VEC3"), ("COLOR_0", "VEC4"), ("TEXCOORD_0", "VEC2")] {
+        let accessor = accessors.at(attributes.get(attribute).usize());
+        assert_eq!(accessor.get("count").usize(), vertices);
+        assert_eq!(accessor.get("type").str(), kind);
+      }
+      let indices = accessors.at(primitive.get("indices").usize());
+      assert_eq!(indices.get("count").usize(), mesh.indices.len());
+
+      let positions = accessors.at(attributes.get("POSITION").usize());
+      let bounds = mesh.bounds().unwrap();
+      assert_eq!(positions.get("min").floats(), vec![bounds.0.x, bounds.0.y, bounds.0.z]);
+      assert_eq!(positions.get("max").floats(), vec![bounds.1.x, bounds.1.y, bounds.1.z]);
+
+      let view = views.at(positions.get("bufferView").usize());
+      assert_eq!(view.get("byteLength").usize(), vertices * 12);
+      let values = read_floats(bin, view.get("byteOffset").usize(), vertices * 3);
+      for (idx, vert) in mesh.vertices.iter().enumerate() {
+        assert_eq!(Point3::new(values[idx * 3], values[idx * 3 + 1], values[idx * 3 + 2]), vert.pos());
+      }
+
+      // Normals are unit length, or up where there's no direction to normalize
+      let view = views.at(accessors.at(attributes.get("NORMAL").usize()).get("bufferView").usize());
+      let normals = read_floats(bin, view.get("byteOffset").usize(), vertices * 3);
+      assert_eq!(& normals[..9], & [0.0, 0.0, 1.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0]);
+    }
+  }
+}
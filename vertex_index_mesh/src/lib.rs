@@ -4,16 +4,31 @@ extern crate cgmath;
 
 mod vertex_index_mesh;
 mod bufferset;
+mod bytes;
 mod vertex;
+mod obj;
+mod gltf;
+mod ply;
+mod weld;
 
 pub use vertex_index_mesh::VertexIndexMesh;
 pub use bufferset::BufferSet;
 pub use vertex::Vertex;
+pub use obj::{write_obj, write_mtl, save_obj, read_obj, read_mtl, load_obj};
+pub use gltf::write_glb;
+pub use ply::write_ply;
+pub use weld::{WeldTolerance, weld_vertices};
 
 use cgmath::prelude::*;
-use cgmath::Vector4;
+use cgmath::{Vector3, Vector4};
 
+/// Set each vertex's normal to the average of the normals of the triangles using it, so that
+/// triangles sharing vertices are shaded smoothly
 pub fn recompute_normals(mut mesh: VertexIndexMesh) -> VertexIndexMesh {
+  for vert in mesh.vertices.iter_mut() {
+    vert.set_normal(Vector3::zero());
+  }
+
   for tri in mesh.indices.chunks(3) {
     if tri.len() != 3 { continue; }
     let (i0, i1, i2) = (tri[0] as usize, tri[1] as usize, tri[2] as usize);
@@ -37,8 +52,8 @@ pub fn recompute_normals(mut mesh: VertexIndexMesh) -> VertexIndexMesh {
   mesh
 }
 
-pub fn assign_colors<F>(mut mesh: VertexIndexMesh, get_colors: F) -> VertexIndexMesh where
-F: Fn((usize, usize, usize), (& Vertex, & Vertex, & Vertex)) -> [Vector4<f32>; 3]
+pub fn assign_colors<F>(mut mesh: VertexIndexMesh, mut get_colors: F) -> VertexIndexMesh where
+F: FnMut((usize, usize, usize), (& Vertex, & Vertex, & Vertex)) -> [Vector4<f32>; 3]
  {
   for tri in mesh.indices.chunks(3) {
     if tri.len() != 3 { continue; }
@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use glium::index::PrimitiveType;
+
+use vertex::Vertex;
+use vertex_index_mesh::VertexIndexMesh;
+
+fn invalid_data(line: usize, message: String) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", line, message))
+}
+
+/// Materials are named after their colour in 8 bit RGBA, so that nearly identical colours share one
+fn material_name(color: [f32; 4]) -> String {
+  let byte = |value: f32| (value.max(0.0).min(1.0) * 255.0).round() as u8;
+  format!("color_{:02x}{:02x}{:02x}{:02x}", byte(color[0]), byte(color[1]), byte(color[2]), byte(color[3]))
+}
+
+/// A material, with its colour and the triangles which use it
+struct MaterialGroup<'a> {
+  name: String,
+  color: [f32; 4],
+  triangles: Vec<&'a [u32]>,
+}
+
+/// The triangles of a mesh grouped by material, in the order the materials first appear. Each
+/// triangle gets the material of its first vertex's colour.
+fn material_groups<'a>(mesh: &'a VertexIndexMesh) -> Vec<MaterialGroup<'a>> {
+  let mut groups: Vec<MaterialGroup> = Vec::new();
+  let mut group_ids: HashMap<String, usize> = HashMap::new();
+  for tri in mesh.indices.chunks(3) {
+    if tri.len() != 3 { continue; }
+    let color: [f32; 4] = mesh.vertices[tri[0] as usize].color().into();
+    let name = material_name(color);
+    let next_id = groups.len();
+    let id = * group_ids.entry(name.clone()).or_insert(next_id);
+    if id == next_id {
+      groups.push(MaterialGroup { name: name, color: color, triangles: Vec::new() });
+    }
+    groups[id].triangles.push(tri);
+  }
+  groups
+}
+
+fn write_face<W: Write>(out: &mut W, tri: & [u32]) -> io::Result<()> {
+  // OBJ indices start at 1
+  let (i0, i1, i2) = (tri[0] + 1, tri[1] + 1, tri[2] + 1);
+  writeln!(out, "f {}/{}/{} {}/{}/{} {}/{}/{}", i0, i0, i0, i1, i1, i1, i2, i2, i2)
+}
+
+/// Write a triangle mesh as Wavefront OBJ. Every vertex is written once, with its normal and
+/// texture coordinates at the same index, and its colour appended to the position as most tools
+/// read it. If `mtl_filename` is given, triangles also get a material per colour from that file,
+/// which `write_mtl` writes, and are written grouped by material.
+pub fn write_obj<W: Write>(mesh: & VertexIndexMesh, out: &mut W, mtl_filename: Option<& str>) -> io::Result<()> {
+  if mesh.primtype != PrimitiveType::TrianglesList {
+    return Err(io::Error::new(io::ErrorKind::InvalidInput, "only triangle lists can be written as OBJ"));
+  }
+
+  if let Some(filename) = mtl_filename {
+    writeln!(out, "mtllib {}", filename)?;
+  }
+
+  for vert in & mesh.vertices {
+    let (pos, color) = (vert.pos(), vert.color());
+    writeln!(out, "v {} {} {} {} {} {}", pos.x, pos.y, pos.z, color.x, color.y, color.z)?;
+  }
+  for vert in & mesh.vertices {
+    let tex = vert.tex();
+    writeln!(out, "vt {} {}", tex.x, tex.y)?;
+  }
+  for vert in & mesh.vertices {
+    let norm = vert.normal();
+    writeln!(out, "vn {} {} {}", norm.x, norm.y, norm.z)?;
+  }
+
+  if mtl_filename.is_some() {
+    for group in material_groups(mesh) {
+      writeln!(out, "usemtl {}", group.name)?;
+      for tri in group.triangles {
+        write_face(out, tri)?;
+      }
+    }
+  } else {
+    for tri in mesh.indices.chunks(3) {
+      if tri.len() != 3 { continue; }
+      write_face(out, tri)?;
+    }
+  }
+
+  Ok(())
+}
+
+/// Write the materials used by `write_obj`, one for each distinct triangle colour
+pub fn write_mtl<W: Write>(mesh: & VertexIndexMesh, out: &mut W) -> io::Result<()> {
+  for group in material_groups(mesh) {
+    let color = group.color;
+    writeln!(out, "newmtl {}", group.name)?;
+    writeln!(out, "Kd {} {} {}", color[0], color[1], color[2])?;
+    writeln!(out, "d {}", color[3])?;
+    writeln!(out, "")?;
+  }
+  Ok(())
+}
+
+/// Write `path` as OBJ, along with its materials in a file of the same name with the extension .mtl
+pub fn save_obj<P: AsRef<Path>>(mesh: & VertexIndexMesh, path: P) -> io::Result<()> {
+  let obj_path = path.as_ref();
+  let mtl_path = obj_path.with_extension("mtl");
+  let mtl_filename = mtl_path.file_name().and_then(|name| name.to_str()).unwrap_or("materials.mtl").to_string();
+
+  let mut obj_out = BufWriter::new(File::create(obj_path)?);
+  write_obj(mesh, &mut obj_out, Some(& mtl_filename))?;
+  let mut mtl_out = BufWriter::new(File::create(& mtl_path)?);
+  write_mtl(mesh, &mut mtl_out)
+}
+
+fn parse_floats(line: usize, fields: & [& str]) -> io::Result<Vec<f32>> {
+  fields.iter().map(|field| {
+    field.parse::<f32>().map_err(|_| invalid_data(line, format!("'{}' is not a number", field)))
+  }).collect()
+}
+
+/// Read the colours of the materials in an MTL file, from `Kd` and `d`
+pub fn read_mtl<R: BufRead>(input: R) -> io::Result<HashMap<String, [f32; 4]>> {
+  let mut materials = HashMap::new();
+  let mut current: Option<String> = None;
+
+  for (idx, text) in input.lines().enumerate() {
+    let text = text?;
+    let line = idx + 1;
+    let fields: Vec<& str> = text.split_whitespace().collect();
+    if fields.is_empty() || fields[0].starts_with('#') { continue; }
+
+    match fields[0] {
+      "newmtl" => {
+        let name = match fields.get(1) {
+          Some(name) => name.to_string(),
+          None => return Err(invalid_data(line, "a material needs a name".to_string())),
+        };
+        materials.insert(name.clone(), [1.0, 1.0, 1.0, 1.0]);
+        current = Some(name);
+      },
+      "Kd" | "d" => {
+        let name = match current {
+          Some(ref name) => name,
+          None => return Err(invalid_data(line, format!("'{}' before any 'newmtl'", fields[0]))),
+        };
+        let values = parse_floats(line, & fields[1..])?;
+        let color = materials.get_mut(name).unwrap();
+        match (fields[0], values.len()) {
+          ("Kd", 3) => { color[0] = values[0]; color[1] = values[1]; color[2] = values[2]; },
+          ("d", 1) => color[3] = values[0],
+          _ => return Err(invalid_data(line, format!("wrong number of values for '{}'", fields[0]))),
+        }
+      },
+      // Other properties don't affect vertex colours
+      _ => (),
+    }
+  }
+
+  Ok(materials)
+}
+
+/// One corner of a face: indices of its position, texture coordinates and normal
+type Corner = (usize, Option<usize>, Option<usize>);
+
+/// Read a Wavefront OBJ file into a triangle mesh. Polygons are split into fans of triangles.
+/// When every corner uses the same index for its position, texture coordinates and normal, as
+/// `write_obj` does, the vertices keep their order in the file. Otherwise each distinct
+/// combination becomes one vertex. Vertices take their colour from the position line if it has
+/// one, otherwise from the face's material in `materials`, which `read_mtl` provides.
+pub fn read_obj<R: BufRead>(input: R, materials: & HashMap<String, [f32; 4]>) -> io::Result<VertexIndexMesh> {
+  let mut positions: Vec<([f32; 3], Option<[f32; 3]>)> = Vec::new();
+  let mut tex_coords: Vec<[f32; 2]> = Vec::new();
+  let mut normals: Vec<[f32; 3]> = Vec::new();
+  let mut faces: Vec<(Vec<Corner>, Option<String>)> = Vec::new();
+  let mut material: Option<String> = None;
+
+  for (idx, text) in input.lines().enumerate() {
+    let text = text?;
+    let line = idx + 1;
+    let fields: Vec<& str> = text.split_whitespace().collect();
+    if fields.is_empty() || fields[0].starts_with('#') { continue; }
+
+    match fields[0] {
+      "v" => {
+        let values = parse_floats(line, & fields[1..])?;
+        match values.len() {
+          3 | 4 => positions.push(([values[0], values[1], values[2]], None)),
+          6 => positions.push(([values[0], values[1], values[2]], Some([values[3], values[4], values[5]]))),
+          count => return Err(invalid_data(line, format!("a position takes 3 or 6 values, found {}", count))),
+        }
+      },
+      "vt" => {
+        let values = parse_floats(line, & fields[1..])?;
+        if values.len() < 2 {
+          return Err(invalid_data(line, "texture coordinates take at least 2 values".to_string()));
+        }
+        tex_coords.push([values[0], values[1]]);
+      },
+      "vn" => {
+        let values = parse_floats(line, & fields[1..])?;
+        if values.len() != 3 {
+          return Err(invalid_data(line, "a normal takes 3 values".to_string()));
+        }
+        normals.push([values[0], values[1], values[2]]);
+      },
+      "usemtl" => {
+        material = fields.get(1).map(|name| name.to_string());
+      },
+      "f" => {
+        if fields.len() < 4 {
+          return Err(invalid_data(line, "a face needs at least 3 vertices".to_string()));
+        }
+        // Negative indices count back from the latest element
+        let resolve = |value: Option<& str>, count: usize| -> io::Result<Option<usize>> {
+          match value {
+            None | Some("") => Ok(None),
+            Some(value) => {
+              let index = value.parse::<i64>().map_err(|_| invalid_data(line, format!("'{}' is not an index", value)))?;
+              let resolved = if index < 0 { count as i64 + index } else { index - 1 };
+              if resolved < 0 || resolved >= count as i64 {
+                return Err(invalid_data(line, format!("index {} is out of range", index)));
+              }
+              Ok(Some(resolved as usize))
+            },
+          }
+        };
+        let mut corners = Vec::with_capacity(fields.len() - 1);
+        for field in & fields[1..] {
+          let mut refs = field.split('/');
+          let pos_idx = match resolve(refs.next(), positions.len())? {
+            Some(pos_idx) => pos_idx,
+            None => return Err(invalid_data(line, "a face vertex needs a position".to_string())),
+          };
+          let tex_idx = resolve(refs.next(), tex_coords.len())?;
+          let norm_idx = resolve(refs.next(), normals.len())?;
+          corners.push((pos_idx, tex_idx, norm_idx));
+        }
+        faces.push((corners, material.clone()));
+      },
+      // Groups, objects, smoothing groups and lines aren't needed for a single mesh
+      _ => (),
+    }
+  }
+
+  let material_color = |material: & Option<String>| material.as_ref().and_then(|name| materials.get(name)).cloned();
+  let make_vertex = |& (pos_idx, tex_idx, norm_idx): & Corner, material: & Option<String>| {
+    let (pos, vertex_color) = positions[pos_idx];
+    let color = match (vertex_color, material_color(material)) {
+      (Some(rgb), color) => [rgb[0], rgb[1], rgb[2], color.map_or(1.0, |color| color[3])],
+      (None, Some(color)) => color,
+      (None, None) => [1.0, 1.0, 1.0, 1.0],
+    };
+    let tex = tex_idx.map_or([0.0, 0.0], |tex_idx| tex_coords[tex_idx]);
+    let norm = norm_idx.map_or([0.0, 0.0, 0.0], |norm_idx| normals[norm_idx]);
+    Vertex::new(& pos, & norm, & color, & tex)
+  };
+
+  let mut mesh = VertexIndexMesh::new(PrimitiveType::TrianglesList);
+  let uniform = faces.iter().all(|& (ref corners, ref material)| {
+    corners.iter().all(|& (pos_idx, tex_idx, norm_idx)| {
+      tex_idx.map_or(true, |tex_idx| tex_idx == pos_idx) &&
+        norm_idx.map_or(true, |norm_idx| norm_idx == pos_idx) &&
+        // Without a vertex colour the material matters, so a position can't be shared across materials
+        (positions[pos_idx].1.is_some() || material.is_none())
+    })
+  });
+
+  if uniform {
+    let mut used: Vec<Option<Corner>> = vec![None; positions.len()];
+    for & (ref corners, _) in & faces {
+      for & corner in corners {
+        used[corner.0] = Some(corner);
+      }
+    }
+    for (pos_idx, corner) in used.iter().enumerate() {
+      // Positions no face uses still take the texture coordinates and normal at their index
+      let corner = corner.unwrap_or_else(|| {
+        let at_index = |count: usize| if pos_idx < count { Some(pos_idx) } else { None };
+        (pos_idx, at_index(tex_coords.len()), at_index(normals.len()))
+      });
+      mesh.add_vertex_shared(make_vertex(& corner, & None));
+    }
+    // Alpha comes from the material
+    for & (ref corners, ref material) in & faces {
+      if let Some(color) = material_color(material) {
+        for & (pos_idx, _, _) in corners {
+          let mut vert_color = mesh.vertices[pos_idx].color();
+          vert_color.w = color[3];
+          mesh.vertices[pos_idx].set_color(vert_color);
+        }
+      }
+      for corner in 1..(corners.len() - 1) {
+        mesh.add_triangle(corners[0].0 as u32, corners[corner].0 as u32, corners[corner + 1].0 as u32);
+      }
+    }
+  } else {
+    let mut vertex_ids: HashMap<(Corner, Option<String>), u32> = HashMap::new();
+    for & (ref corners, ref material) in & faces {
+      let ids: Vec<u32> = corners.iter().map(|corner| {
+        let key = (* corner, if positions[corner.0].1.is_some() { None } else { material.clone() });
+        let next_id = mesh.vertices.len() as u32;
+        let id = * vertex_ids.entry(key).or_insert(next_id);
+        if id == next_id {
+          mesh.add_vertex_shared(make_vertex(corner, material));
+        }
+        id
+      }).collect();
+      for corner in 1..(ids.len() - 1) {
+        mesh.add_triangle(ids[0], ids[corner], ids[corner + 1]);
+      }
+    }
+  }
+
+  Ok(mesh)
+}
+
+/// Read an OBJ file, along with the materials of its `mtllib` if there is one
+pub fn load_obj<P: AsRef<Path>>(path: P) -> io::Result<VertexIndexMesh> {
+  let obj_path = path.as_ref();
+  let mut materials = HashMap::new();
+
+  let obj_file = BufReader::new(File::open(obj_path)?);
+  let mtl_filename = obj_file.lines()
+    .filter_map(|line| line.ok())
+    .filter(|line| line.trim().starts_with("mtllib"))
+    .filter_map(|line| line.split_whitespace().nth(1).map(|name| name.to_string()))
+    .next();
+  if let Some(filename) = mtl_filename {
+    let mtl_path = obj_path.with_file_name(filename);
+    materials = read_mtl(BufReader::new(File::open(mtl_path)?))?;
+  }
+
+  read_obj(BufReader::new(File::open(obj_path)?), & materials)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A strip of quads in alternating colours, so that the materials interleave
+  fn striped_mesh() -> VertexIndexMesh {
+    let colors = [[0.4, 0.25, 0.1, 1.0], [0.1, 0.6, 0.2, 0.5]];
+    let mut mesh = VertexIndexMesh::new(PrimitiveType::TrianglesList);
+    for quad in 0..4 {
+      let color = & colors[quad % 2];
+      let x = quad as f32 * 0.7;
+      for & (dx, y) in & [(0.0, 0.0), (0.7, 0.0), (0.7, 1.3), (0.0, 1.3)] {
+        let norm = [0.0, (x + dx) / 3.0, 1.0];
+        mesh.add_vertex_shared(Vertex::new(& [x + dx, y, -0.1], & norm, color, & [(x + dx) / 2.8, y / 1.3]));
+      }
+      let base = quad as u32 * 4;
+      mesh.add_triangle(base, base + 1, base + 2);
+      mesh.add_triangle(base, base + 2, base + 3);
+    }
+    mesh
+  }
+
+  fn sorted_triangles(mesh: & VertexIndexMesh) -> Vec<Vec<u32>> {
+    let mut triangles: Vec<Vec<u32>> = mesh.indices.chunks(3).map(|tri| tri.to_vec()).collect();
+    triangles.sort();
+    triangles
+  }
+
+  #[test]
+  fn round_trip() {
+    let mesh = striped_mesh();
+    let (mut obj, mut mtl) = (Vec::new(), Vec::new());
+    write_obj(& mesh, &mut obj, Some("striped.mtl")).unwrap();
+    write_mtl(& mesh, &mut mtl).unwrap();
+
+    let materials = read_mtl(& mtl[..]).unwrap();
+    assert_eq!(materials.len(), 2);
+    let read = read_obj(& obj[..], & materials).unwrap();
+
+    assert_eq!(read.vertices.len(), mesh.vertices.len());
+    for (found, expected) in read.vertices.iter().zip(mesh.vertices.iter()) {
+      assert_eq!(found.pos(), expected.pos());
+      assert_eq!(found.normal(), expected.normal());
+      assert_eq!(found.tex(), expected.tex());
+      assert_eq!(found.color(), expected.color());
+    }
+    assert_eq!(sorted_triangles(& read), sorted_triangles(& mesh));
+  }
+
+  #[test]
+  fn faces_are_grouped_by_material() {
+    let mut obj = Vec::new();
+    write_obj(& striped_mesh(), &mut obj, Some("striped.mtl")).unwrap();
+    let text = String::from_utf8(obj).unwrap();
+    assert_eq!(text.lines().filter(|line| line.starts_with("usemtl")).count(), 2);
+
+    let mut plain = Vec::new();
+    write_obj(& striped_mesh(), &mut plain, None).unwrap();
+    let plain = String::from_utf8(plain).unwrap();
+    assert!(!plain.contains("usemtl") && !plain.contains("mtllib"));
+    assert!(read_obj(plain.as_bytes(), & HashMap::new()).is_ok());
+  }
+}
@@ -0,0 +1,98 @@
+use std::io::{self, Write};
+
+use glium::index::PrimitiveType;
+
+use bytes::{push_u32, push_f32};
+use vertex_index_mesh::VertexIndexMesh;
+
+/// Write a triangle mesh as binary little endian PLY, with positions, normals, 8 bit RGBA
+/// colours and texture coordinates for each vertex
+pub fn write_ply<W: Write>(mesh: & VertexIndexMesh, out: &mut W) -> io::Result<()> {
+  if mesh.primtype != PrimitiveType::TrianglesList {
+    return Err(io::Error::new(io::ErrorKind::InvalidInput, "only triangle lists can be written as PLY"));
+  }
+  let triangles: Vec<& [u32]> = mesh.indices.chunks(3).filter(|tri| tri.len() == 3).collect();
+
+  writeln!(out, "ply")?;
+  writeln!(out, "format binary_little_endian 1.0")?;
+  writeln!(out, "element vertex {}", mesh.vertices.len())?;
+  for name in & ["x", "y", "z", "nx", "ny", "nz"] {
+    writeln!(out, "property float {}", name)?;
+  }
+  for name in & ["red", "green", "blue", "alpha"] {
+    writeln!(out, "property uchar {}", name)?;
+  }
+  writeln!(out, "property float s")?;
+  writeln!(out, "property float t")?;
+  writeln!(out, "element face {}", triangles.len())?;
+  writeln!(out, "property list uchar uint vertex_indices")?;
+  writeln!(out, "end_header")?;
+
+  let byte = |value: f32| (value.max(0.0).min(1.0) * 255.0).round() as u8;
+  let mut bytes = Vec::with_capacity(mesh.vertices.len() * 36 + triangles.len() * 13);
+  for vert in & mesh.vertices {
+    let (pos, norm, color, tex) = (vert.pos(), vert.normal(), vert.color(), vert.tex());
+    for & value in & [pos.x, pos.y, pos.z, norm.x, norm.y, norm.z] {
+      push_f32(&mut bytes, value);
+    }
+    for & value in & [color.x, color.y, color.z, color.w] {
+      bytes.push(byte(value));
+    }
+    push_f32(&mut bytes, tex.x);
+    push_f32(&mut bytes, tex.y);
+  }
+  for tri in triangles {
+    bytes.push(3);
+    for & index in tri {
+      push_u32(&mut bytes, index);
+    }
+  }
+  out.write_all(& bytes)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use vertex::Vertex;
+
+  fn quad() -> VertexIndexMesh {
+    let mut mesh = VertexIndexMesh::new(PrimitiveType::TrianglesList);
+    for & (x, y) in & [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)] {
+      mesh.add_vertex_shared(Vertex::new(& [x, y, 0.0], & [0.0, 0.0, 1.0], & [1.0, 0.5, 0.0, 1.0], & [x, y]));
+    }
+    mesh.add_triangle(0, 1, 2);
+    mesh.add_triangle(0, 2, 3);
+    mesh
+  }
+
+  #[test]
+  fn header_and_body_agree() {
+    let mut out = Vec::new();
+    write_ply(& quad(), &mut out).unwrap();
+
+    let end = b"end_header\n";
+    let header_len = out.windows(end.len()).position(|window| window == end).unwrap() + end.len();
+    let header = String::from_utf8(out[..header_len].to_vec()).unwrap();
+    let count = |element: & str| header.lines()
+      .find(|line| line.starts_with(& format!("element {} ", element)))
+      .and_then(|line| line.split(' ').nth(2))
+      .and_then(|count| count.parse::<usize>().ok())
+      .unwrap();
+    assert!(header.starts_with("ply\nformat binary_little_endian 1.0\n"));
+    let (vertices, faces) = (count("vertex"), count("face"));
+    assert_eq!((vertices, faces), (4, 2));
+
+    let body = & out[header_len..];
+    assert_eq!(body.len(), vertices * 36 + faces * 13);
+    // The colour of the first vertex, then the last face
+    assert_eq!(& body[24..28], & [255, 128, 0, 255]);
+    assert_eq!(& body[body.len() - 13..], & [3, 0, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0]);
+  }
+
+  #[test]
+  fn only_triangles_are_written() {
+    let mut mesh = quad();
+    mesh.primtype = PrimitiveType::LinesList;
+    assert!(write_ply(& mesh, &mut Vec::new()).is_err());
+  }
+}
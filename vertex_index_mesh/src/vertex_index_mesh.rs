@@ -25,10 +25,23 @@ impl VertexIndexMesh {
     self.add_vertex(Vertex::from_pos(point));
   }
 
+  /// Add a vertex which is only used once, along with its own index
   pub fn add_vertex(&mut self, vert: Vertex) {
+    let index = self.add_vertex_shared(vert);
+    self.indices.push(index);
+  }
+
+  /// Add a vertex without using it yet, returning its index for `add_triangle`
+  pub fn add_vertex_shared(&mut self, vert: Vertex) -> u32 {
     self.vertices.push(vert);
-    let last = self.indices.len() as u32;
-    self.indices.push(last);
+    (self.vertices.len() - 1) as u32
+  }
+
+  /// Add a triangle between vertices which have already been added
+  pub fn add_triangle(&mut self, i0: u32, i1: u32, i2: u32) {
+    self.indices.push(i0);
+    self.indices.push(i1);
+    self.indices.push(i2);
   }
 
   /// Assumes that the primitive type is the same
@@ -42,6 +55,19 @@ impl VertexIndexMesh {
     }
   }
 
+  /// The opposite corners of the smallest axis-aligned box around the vertices, or None if there are none
+  pub fn bounds(& self) -> Option<(Point3<f32>, Point3<f32>)> {
+    let mut points = self.vertices.iter().map(|vert| vert.pos());
+    let first = match points.next() {
+      Some(first) => first,
+      None => return None,
+    };
+    Some(points.fold((first, first), |(min, max), pt| (
+      Point3::new(min.x.min(pt.x), min.y.min(pt.y), min.z.min(pt.z)),
+      Point3::new(max.x.max(pt.x), max.y.max(pt.y), max.z.max(pt.z)),
+    )))
+  }
+
   pub fn to_buffer<T: Facade>(& self, gl: & T) -> BufferSet {
     BufferSet::from_vertex_index(gl, self.primtype, self)
   }
@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+
+use glium::index::PrimitiveType;
+
+use cgmath::prelude::*;
+
+use vertex::Vertex;
+use vertex_index_mesh::VertexIndexMesh;
+
+/// How far apart the attributes of two vertices can be for them to be welded into one
+#[derive(Copy, Clone, Debug)]
+pub struct WeldTolerance {
+  pub position: f32,
+  pub normal: f32,
+  pub color: f32,
+  pub tex: f32,
+}
+
+impl WeldTolerance {
+  /// Weld vertices within `position` of each other, whose other attributes are practically equal
+  pub fn new(position: f32) -> WeldTolerance {
+    WeldTolerance {
+      position: position,
+      normal: 1.0e-4,
+      color: 1.0e-4,
+      tex: 1.0e-4,
+    }
+  }
+}
+
+fn can_weld(a: & Vertex, b: & Vertex, tolerance: & WeldTolerance) -> bool {
+  (a.pos() - b.pos()).magnitude() <= tolerance.position &&
+    (a.normal() - b.normal()).magnitude() <= tolerance.normal &&
+    (a.color() - b.color()).magnitude() <= tolerance.color &&
+    (a.tex() - b.tex()).magnitude() <= tolerance.tex
+}
+
+/// Merge vertices which are within the tolerance of one another, keeping the first of each group,
+/// and point the indices at the merged vertices. Candidates are found with a spatial hash whose
+/// cells are the size of the position tolerance. Triangles which collapse are removed.
+pub fn weld_vertices(mesh: VertexIndexMesh, tolerance: & WeldTolerance) -> VertexIndexMesh {
+  let cell_size = if tolerance.position > 0.0 { tolerance.position } else { 1.0 };
+  let cell_of = |vert: & Vertex| {
+    let pos = vert.pos();
+    ((pos.x / cell_size).floor() as i64, (pos.y / cell_size).floor() as i64, (pos.z / cell_size).floor() as i64)
+  };
+
+  let mut welded = VertexIndexMesh::new(mesh.primtype);
+  let mut cells: HashMap<(i64, i64, i64), Vec<u32>> = HashMap::new();
+  let mut remap: Vec<u32> = Vec::with_capacity(mesh.vertices.len());
+
+  for vert in & mesh.vertices {
+    let (cx, cy, cz) = cell_of(vert);
+    let mut found = None;
+    'search: for dx in -1..2 {
+      for dy in -1..2 {
+        for dz in -1..2 {
+          if let Some(candidates) = cells.get(& (cx + dx, cy + dy, cz + dz)) {
+            for & candidate in candidates {
+              if can_weld(& welded.vertices[candidate as usize], vert, tolerance) {
+                found = Some(candidate);
+                break 'search;
+              }
+            }
+          }
+        }
+      }
+    }
+
+    let index = match found {
+      Some(index) => index,
+      None => {
+        let index = welded.add_vertex_shared(* vert);
+        cells.entry((cx, cy, cz)).or_insert_with(Vec::new).push(index);
+        index
+      },
+    };
+    remap.push(index);
+  }
+
+  if mesh.primtype == PrimitiveType::TrianglesList {
+    for tri in mesh.indices.chunks(3) {
+      if tri.len() != 3 { continue; }
+      let (i0, i1, i2) = (remap[tri[0] as usize], remap[tri[1] as usize], remap[tri[2] as usize]);
+      if i0 == i1 || i1 == i2 || i2 == i0 { continue; }
+      welded.add_triangle(i0, i1, i2);
+    }
+  } else {
+    welded.indices = mesh.indices.iter().map(|& index| remap[index as usize]).collect();
+  }
+
+  welded
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use cgmath::{Vector2, Vector3, Vector4};
+
+  fn vertex(pos: [f32; 3]) -> Vertex {
+    Vertex::new(& pos, & [0.0, 0.0, 1.0], & [0.5, 0.3, 0.1, 1.0], & [0.0, 0.0])
+  }
+
+  #[test]
+  fn neighbouring_cells_weld() {
+    let mut mesh = VertexIndexMesh::new(PrimitiveType::TrianglesList);
+    // Either side of the cell boundary at x = 0.1
+    let a = mesh.add_vertex_shared(vertex([0.099, 0.0, 0.0]));
+    let b = mesh.add_vertex_shared(vertex([0.101, 0.0, 0.0]));
+    let c = mesh.add_vertex_shared(vertex([0.0, 1.0, 0.0]));
+    let d = mesh.add_vertex_shared(vertex([1.0, 1.0, 0.0]));
+    mesh.add_triangle(a, d, c);
+    mesh.add_triangle(b, d, c);
+
+    let welded = weld_vertices(mesh, & WeldTolerance::new(0.1));
+    assert_eq!(welded.vertices.len(), 3);
+    assert_eq!(welded.indices, vec![0, 2, 1, 0, 2, 1]);
+    assert_eq!(welded.vertices[0].pos(), vertex([0.099, 0.0, 0.0]).pos());
+  }
+
+  #[test]
+  fn other_attributes_keep_vertices_apart() {
+    let base = vertex([0.0, 0.0, 0.0]);
+    let mut normal = base;
+    normal.set_normal(Vector3::new(1.0, 0.0, 0.0));
+    let mut color = base;
+    color.set_color(Vector4::new(0.1, 0.6, 0.2, 1.0));
+    let mut tex = base;
+    tex.set_tex(Vector2::new(0.5, 0.0));
+
+    let mut mesh = VertexIndexMesh::new(PrimitiveType::Points);
+    for & vert in & [base, normal, color, tex, base] {
+      let index = mesh.add_vertex_shared(vert);
+      mesh.indices.push(index);
+    }
+    let welded = weld_vertices(mesh, & WeldTolerance::new(0.1));
+    assert_eq!(welded.vertices.len(), 4);
+    assert_eq!(welded.indices, vec![0, 1, 2, 3, 0]);
+  }
+
+  #[test]
+  fn collapsed_triangles_are_dropped() {
+    let mut mesh = VertexIndexMesh::new(PrimitiveType::TrianglesList);
+    let a = mesh.add_vertex_shared(vertex([0.0, 0.0, 0.0]));
+    let b = mesh.add_vertex_shared(vertex([0.01, 0.0, 0.0]));
+    let c = mesh.add_vertex_shared(vertex([0.0, 1.0, 0.0]));
+    let d = mesh.add_vertex_shared(vertex([1.0, 0.0, 0.0]));
+    // a and b weld, which leaves the first triangle with two corners in one place
+    mesh.add_triangle(a, b, c);
+    mesh.add_triangle(b, d, c);
+
+    let welded = weld_vertices(mesh, & WeldTolerance::new(0.05));
+    assert_eq!(welded.vertices.len(), 3);
+    assert_eq!(welded.indices, vec![0, 2, 1]);
+  }
+
+  #[test]
+  fn other_primitives_are_remapped() {
+    let mut mesh = VertexIndexMesh::new(PrimitiveType::LinesList);
+    let a = mesh.add_vertex_shared(vertex([0.0, 0.0, 0.0]));
+    let b = mesh.add_vertex_shared(vertex([1.0, 0.0, 0.0]));
+    let c = mesh.add_vertex_shared(vertex([1.0, 0.0, 0.001]));
+    let d = mesh.add_vertex_shared(vertex([2.0, 0.0, 0.0]));
+    mesh.indices = vec![a, b, c, d, a, a];
+
+    let welded = weld_vertices(mesh, & WeldTolerance::new(0.01));
+    assert_eq!(welded.primtype, PrimitiveType::LinesList);
+    assert_eq!(welded.vertices.len(), 3);
+    // Lines aren't triangles, so even the one which collapses to a point is kept
+    assert_eq!(welded.indices, vec![0, 1, 1, 2, 0, 0]);
+  }
+}